@@ -0,0 +1,92 @@
+//! Compare the allocation strategies on workloads that fragment storage differently.
+//!
+//! Run with `cargo bench --bench allocators`. Before timing, the number of failed operations
+//! and the number of extents left at the end of each workload are printed per strategy.
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use mem_fs::{Allocator, BestFit, Buddy, FirstFit, MemoryFs, NextFit, bitmap_words};
+use std::hint::black_box;
+use std::sync::LazyLock;
+
+const STORAGE_SIZE: usize = 64 * 1024;
+const PAGE_SIZE: usize = 64;
+
+type Fs<'a, A> =
+    MemoryFs<'a, STORAGE_SIZE, PAGE_SIZE, 64, 32, { bitmap_words(STORAGE_SIZE, PAGE_SIZE) }, A>;
+type Workload<A> = fn(&mut Fs<'_, A>) -> usize;
+
+/// Names used by `assets`, built once so formatting them is not part of the timing.
+static ASSET_NAMES: LazyLock<Vec<String>> =
+    LazyLock::new(|| (0..48).map(|i| format!("asset{i}")).collect());
+
+/// Deterministic pseudo random numbers, so every strategy sees the same operations.
+struct Lcg(u32);
+
+impl Lcg {
+    fn next(&mut self) -> usize {
+        self.0 = self.0.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        (self.0 >> 8) as usize
+    }
+}
+
+/// A few log files that are appended to in turn and recreated when storage runs out.
+/// Returns the number of failed operations.
+fn logs<A: Allocator>(fs: &mut Fs<'_, A>) -> usize {
+    let mut rng = Lcg(1);
+    let line = [b'x'; 48];
+    let mut failed = 0;
+    for i in 0..2000 {
+        let name = ["log0", "log1", "log2", "log3"][i % 4];
+        let len = 16 + rng.next() % (line.len() - 16);
+        if fs.append(name, &line[..len]).is_err() {
+            let _ = fs.delete(name);
+            if fs.create(name, &line[..len]).is_err() {
+                failed += 1;
+            }
+        }
+    }
+    failed
+}
+
+/// Files of mixed sizes that are replaced and deleted at random, like an asset cache.
+/// Returns the number of failed operations.
+fn assets<A: Allocator>(fs: &mut Fs<'_, A>) -> usize {
+    let mut rng = Lcg(2);
+    let data = [0xa5u8; 2048];
+    let mut failed = 0;
+    for _ in 0..2000 {
+        let name = &ASSET_NAMES[rng.next() % ASSET_NAMES.len()];
+        if rng.next().is_multiple_of(4) {
+            let _ = fs.delete(name);
+        } else if fs.write(name, &data[..rng.next() % data.len()]).is_err() {
+            failed += 1;
+        }
+    }
+    failed
+}
+
+fn bench_strategy<A: Allocator + Default>(c: &mut Criterion, strategy: &str) {
+    let storage = Box::leak(Box::new([0u8; STORAGE_SIZE]));
+    let workloads: [(&str, Workload<A>); 2] = [("logs", logs), ("assets", assets)];
+
+    for (workload, run) in workloads {
+        let mut fs = Fs::<A>::from_backed(storage);
+        let failed = run(&mut fs);
+        let extents: usize = fs.entries().map(|entry| entry.extent_count()).sum();
+        println!("{workload}/{strategy}: {failed} failed operations, {extents} extents");
+
+        c.bench_function(&format!("{workload}/{strategy}"), |b| {
+            b.iter(|| run(black_box(&mut Fs::<A>::from_backed(storage))))
+        });
+    }
+}
+
+fn allocators(c: &mut Criterion) {
+    bench_strategy::<FirstFit>(c, "first-fit");
+    bench_strategy::<NextFit>(c, "next-fit");
+    bench_strategy::<BestFit>(c, "best-fit");
+    bench_strategy::<Buddy>(c, "buddy");
+}
+
+criterion_group!(benches, allocators);
+criterion_main!(benches);
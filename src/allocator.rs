@@ -0,0 +1,190 @@
+use core::ops::Range;
+
+/// Read-only view of the page bitmap, handed to an `Allocator` when searching for pages.
+pub struct PageMap<'b> {
+    words: &'b [u32],
+    num_pages: usize,
+}
+
+impl<'b> PageMap<'b> {
+    pub(crate) fn new(words: &'b [u32], num_pages: usize) -> Self {
+        Self { words, num_pages }
+    }
+
+    /// Total number of pages in storage.
+    pub fn num_pages(&self) -> usize {
+        self.num_pages
+    }
+
+    /// Whether `page` is free.
+    pub fn is_free(&self, page: usize) -> bool {
+        (self.words[page / 32] & (1 << (page % 32))) == 0
+    }
+
+    /// First free page at or after `start`, if any.
+    pub fn next_free(&self, start: usize) -> Option<usize> {
+        if start >= self.num_pages {
+            return None;
+        }
+        let mut index = start / 32;
+        // Treat the pages before `start` as used.
+        let mut free = !self.words[index] & (u32::MAX << (start % 32));
+        while free == 0 {
+            index += 1;
+            if index * 32 >= self.num_pages {
+                return None;
+            }
+            free = !self.words[index];
+        }
+        let page = index * 32 + free.trailing_zeros() as usize;
+        (page < self.num_pages).then_some(page)
+    }
+
+    /// First used page in `start..end`, or `end` if all of them are free.
+    fn next_used(&self, start: usize, end: usize) -> usize {
+        if start >= end {
+            return end;
+        }
+        let mut index = start / 32;
+        let mut used = self.words[index] >> (start % 32);
+        let mut page = start;
+        while used == 0 {
+            index += 1;
+            page = index * 32;
+            if page >= end {
+                return end;
+            }
+            used = self.words[index];
+        }
+        (page + used.trailing_zeros() as usize).min(end)
+    }
+
+    /// Length of the free run starting at `start`, up to `max_pages`.
+    pub fn free_run_len(&self, start: usize, max_pages: usize) -> usize {
+        let end = start.saturating_add(max_pages).min(self.num_pages);
+        self.next_used(start, end).saturating_sub(start)
+    }
+
+    /// Total number of free pages.
+    pub fn free_count(&self) -> usize {
+        let full_words = self.num_pages / 32;
+        let mut count: usize = self.words[..full_words]
+            .iter()
+            .map(|word| word.count_zeros() as usize)
+            .sum();
+        let rest = self.num_pages % 32;
+        if rest > 0 {
+            count += (!self.words[full_words] & (u32::MAX >> (32 - rest))).count_ones() as usize;
+        }
+        count
+    }
+
+    /// Iterate over all maximal runs of free pages starting at or after `start`.
+    pub fn free_runs(&self, start: usize) -> impl Iterator<Item = Range<usize>> + '_ {
+        let mut page = start;
+        core::iter::from_fn(move || {
+            let run_start = self.next_free(page)?;
+            let run_end = run_start + self.free_run_len(run_start, usize::MAX);
+            page = run_end;
+            Some(run_start..run_end)
+        })
+    }
+}
+
+/// Strategy for choosing where new pages are allocated.
+///
+/// The allocator only picks a location; the filesystem marks the pages in the bitmap. It is
+/// used whenever a file needs a new contiguous run of pages. If it finds none, files that may
+/// fragment are split over the largest free runs instead.
+pub trait Allocator {
+    /// Find a run of at least `need_pages` free pages and return its page range.
+    ///
+    /// The returned run may be longer than requested (for example when rounding up to a
+    /// block size); the file then owns the whole run. `need_pages` is never `0`.
+    fn find_run(&mut self, pages: &PageMap<'_>, need_pages: usize) -> Option<Range<usize>>;
+}
+
+/// Use the first free run that is large enough. This is the default.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FirstFit;
+
+impl Allocator for FirstFit {
+    fn find_run(&mut self, pages: &PageMap<'_>, need_pages: usize) -> Option<Range<usize>> {
+        pages
+            .free_runs(0)
+            .find(|run| run.len() >= need_pages)
+            .map(|run| run.start..run.start + need_pages)
+    }
+}
+
+/// Like `FirstFit`, but continue searching where the previous allocation ended.
+///
+/// Spreads allocations over storage, which suits files that are appended to one after
+/// another, such as logs.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NextFit {
+    cursor: usize,
+}
+
+impl Allocator for NextFit {
+    fn find_run(&mut self, pages: &PageMap<'_>, need_pages: usize) -> Option<Range<usize>> {
+        let cursor = self.cursor.min(pages.num_pages());
+        let run = pages
+            .free_runs(cursor)
+            .chain(pages.free_runs(0).take_while(|run| run.start < cursor))
+            .find(|run| run.len() >= need_pages)?;
+
+        self.cursor = run.start + need_pages;
+        Some(run.start..self.cursor)
+    }
+}
+
+/// Use the smallest free run that is large enough.
+///
+/// Keeps large runs intact for large files, at the cost of scanning all free runs.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BestFit;
+
+impl Allocator for BestFit {
+    fn find_run(&mut self, pages: &PageMap<'_>, need_pages: usize) -> Option<Range<usize>> {
+        let mut best: Option<Range<usize>> = None;
+        for run in pages.free_runs(0).filter(|run| run.len() >= need_pages) {
+            if run.len() == need_pages {
+                return Some(run);
+            }
+            if best.as_ref().is_none_or(|best| run.len() < best.len()) {
+                best = Some(run);
+            }
+        }
+        best.map(|run| run.start..run.start + need_pages)
+    }
+}
+
+/// Buddy allocation: runs are rounded up to a power of two and aligned to their size.
+///
+/// Blocks are taken preferably from partly used larger blocks, so freed blocks can merge
+/// back into large aligned runs. Rounding wastes up to half of each run.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Buddy;
+
+impl Allocator for Buddy {
+    fn find_run(&mut self, pages: &PageMap<'_>, need_pages: usize) -> Option<Range<usize>> {
+        let block = need_pages.next_power_of_two();
+        let mut first_free = None;
+
+        for start in (0..pages.num_pages()).step_by(block) {
+            if start + block > pages.num_pages() || pages.free_run_len(start, block) < block {
+                continue;
+            }
+            // A block whose buddy is (partly) in use does not split a larger free block.
+            let buddy = start ^ block;
+            let buddy_free =
+                buddy + block <= pages.num_pages() && pages.free_run_len(buddy, block) == block;
+            if !buddy_free {
+                return Some(start..start + block);
+            }
+            first_free.get_or_insert(start);
+        }
+        first_free.map(|start| start..start + block)
+    }
+}
@@ -0,0 +1,232 @@
+use crate::{FsErr, RestoreCheck};
+
+// LZSS stream: a flag byte announces the next eight items, least significant bit first. A set
+// bit is a literal byte, a clear bit a back-reference of two bytes (little endian) holding
+// `distance - 1` in the upper 10 bits and `len - MIN_MATCH` in the lower 6 bits.
+const WINDOW: usize = 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = MIN_MATCH + 63;
+const BUFFER: usize = 2 * WINDOW; // History and lookahead.
+const HASH_SIZE: usize = 1024;
+const MAX_CANDIDATES: usize = 32; // Limits the match search per position.
+
+/// Streaming LZSS compressor with fixed buffers.
+///
+/// Match candidates are found through hash chains over the first `MIN_MATCH` bytes. Chains
+/// hold the lower 16 bits of stream positions; a stale entry can only point at other bytes
+/// within the window, which are compared before use.
+pub(crate) struct Encoder {
+    buf: [u8; BUFFER],
+    base: usize, // Stream position of `buf[0]`.
+    pos: usize,  // Next byte to encode; everything before it is history.
+    len: usize,
+    head: [u16; HASH_SIZE], // Last position per hash.
+    chain: [u16; WINDOW],   // Previous position with the same hash, by position.
+    group: [u8; 1 + 8 * 2], // Flag byte and up to eight items.
+    group_len: usize,
+    items: u8,
+}
+
+impl Encoder {
+    pub(crate) fn new() -> Self {
+        Self {
+            buf: [0; BUFFER],
+            base: 0,
+            pos: 0,
+            len: 0,
+            head: [0; HASH_SIZE],
+            chain: [0; WINDOW],
+            group: [0; 1 + 8 * 2],
+            group_len: 1,
+            items: 0,
+        }
+    }
+
+    /// Compress `data`, passing finished output to `out`.
+    pub(crate) fn write(&mut self, mut data: &[u8], out: &mut impl FnMut(&[u8])) {
+        while !data.is_empty() {
+            let n = data.len().min(BUFFER - self.len);
+            self.buf[self.len..self.len + n].copy_from_slice(&data[..n]);
+            self.len += n;
+            data = &data[n..];
+
+            // Only encode once a longest possible match can be seen.
+            while self.len - self.pos >= MAX_MATCH {
+                self.step(out);
+            }
+            if self.len == BUFFER {
+                let keep = self.pos - WINDOW;
+                self.buf.copy_within(keep..self.len, 0);
+                self.base += keep;
+                self.pos -= keep;
+                self.len -= keep;
+            }
+        }
+    }
+
+    /// Encode the remaining input and flush the last group.
+    pub(crate) fn finish(mut self, out: &mut impl FnMut(&[u8])) {
+        while self.pos < self.len {
+            self.step(out);
+        }
+        self.flush(out);
+    }
+
+    fn step(&mut self, out: &mut impl FnMut(&[u8])) {
+        let max = (self.len - self.pos).min(MAX_MATCH);
+        let ahead = &self.buf[self.pos..self.pos + max];
+        let (mut best_len, mut best_dist) = (0, 0);
+        if max >= MIN_MATCH {
+            let here = (self.base + self.pos) as u16;
+            let mut candidate = self.head[hash(ahead)];
+            let mut last_dist = 0;
+            for _ in 0..MAX_CANDIDATES {
+                // Chains run backwards; anything else is a stale entry.
+                let dist = here.wrapping_sub(candidate) as usize;
+                if dist <= last_dist || dist > WINDOW.min(self.pos) {
+                    break;
+                }
+                let len = self.buf[self.pos - dist..]
+                    .iter()
+                    .zip(ahead)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if len > best_len {
+                    (best_len, best_dist) = (len, dist);
+                    if len == max {
+                        break;
+                    }
+                }
+                last_dist = dist;
+                candidate = self.chain[candidate as usize % WINDOW];
+            }
+        }
+
+        let advance = if best_len >= MIN_MATCH {
+            let token = (((best_dist - 1) << 6) | (best_len - MIN_MATCH)) as u16;
+            self.emit(&token.to_le_bytes(), false, out);
+            best_len
+        } else {
+            self.emit(&[self.buf[self.pos]], true, out);
+            1
+        };
+        for _ in 0..advance {
+            self.insert();
+            self.pos += 1;
+        }
+    }
+
+    // Add the position `pos` to its hash chain.
+    fn insert(&mut self) {
+        if self.len - self.pos >= MIN_MATCH {
+            let slot = hash(&self.buf[self.pos..]);
+            let position = (self.base + self.pos) as u16;
+            self.chain[position as usize % WINDOW] = self.head[slot];
+            self.head[slot] = position;
+        }
+    }
+
+    fn emit(&mut self, item: &[u8], literal: bool, out: &mut impl FnMut(&[u8])) {
+        if literal {
+            self.group[0] |= 1 << self.items;
+        }
+        self.group[self.group_len..self.group_len + item.len()].copy_from_slice(item);
+        self.group_len += item.len();
+        self.items += 1;
+        if self.items == 8 {
+            self.flush(out);
+        }
+    }
+
+    fn flush(&mut self, out: &mut impl FnMut(&[u8])) {
+        if self.items > 0 {
+            out(&self.group[..self.group_len]);
+            self.group[0] = 0;
+            self.group_len = 1;
+            self.items = 0;
+        }
+    }
+}
+
+/// Streaming LZSS decompressor with a fixed window.
+pub(crate) struct Decoder {
+    window: [u8; WINDOW],
+    pos: usize, // Number of bytes produced so far.
+    flags: u8,
+    items: u8, // Items left in the current group.
+    copy_dist: usize,
+    copy_len: usize,
+}
+
+impl Decoder {
+    pub(crate) fn new() -> Self {
+        Self {
+            window: [0; WINDOW],
+            pos: 0,
+            flags: 0,
+            items: 0,
+            copy_dist: 0,
+            copy_len: 0,
+        }
+    }
+
+    /// Fill `out` with decompressed bytes, reading compressed input from `src`.
+    ///
+    /// # Errors
+    /// - `FsErr::Restore` with `RestoreCheck::Compression` if a back-reference points before
+    ///   the start of the stream
+    /// - Any error returned by `src`
+    pub(crate) fn read(
+        &mut self,
+        out: &mut [u8],
+        src: &mut impl FnMut(&mut [u8]) -> Result<(), FsErr>,
+    ) -> Result<(), FsErr> {
+        for byte in out {
+            if self.copy_len == 0 {
+                if self.items == 0 {
+                    let mut flags = [0u8; 1];
+                    src(&mut flags)?;
+                    self.flags = flags[0];
+                    self.items = 8;
+                }
+                let literal = self.flags & 1 != 0;
+                self.flags >>= 1;
+                self.items -= 1;
+
+                if literal {
+                    let mut literal = [0u8; 1];
+                    src(&mut literal)?;
+                    self.push(literal[0]);
+                    *byte = literal[0];
+                    continue;
+                }
+
+                let mut token = [0u8; size_of::<u16>()];
+                src(&mut token)?;
+                let token = u16::from_le_bytes(token) as usize;
+                self.copy_dist = (token >> 6) + 1;
+                self.copy_len = (token & 63) + MIN_MATCH;
+                if self.copy_dist > self.pos {
+                    return Err(RestoreCheck::Compression.fail());
+                }
+            }
+
+            let value = self.window[(self.pos - self.copy_dist) % WINDOW];
+            self.push(value);
+            self.copy_len -= 1;
+            *byte = value;
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, value: u8) {
+        self.window[self.pos % WINDOW] = value;
+        self.pos += 1;
+    }
+}
+
+// Hash of the first `MIN_MATCH` bytes of `bytes`.
+fn hash(bytes: &[u8]) -> usize {
+    let key = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]);
+    (key.wrapping_mul(0x9E37_79B1) >> (32 - HASH_SIZE.trailing_zeros())) as usize
+}
@@ -0,0 +1,203 @@
+use core::cell::Cell;
+
+use crate::{
+    Allocator, CHECKSUM, DUMP_ENTRY_VERSION, DUMP_VERSION, FsErr, MemoryFs, PageMap, RestoreCheck,
+    read_footer, read_u32,
+};
+
+// Delta layout: magic "MEMFSDLT", version (u8), dump version of the entries (u8), page size,
+// num pages, base snapshot, snapshot, entry count (u32 each), the entry table in the dump
+// format of that version, the number of page runs (u32) and per run its start page, page count
+// (u32 each) and contents, then the dump footer.
+// Version 1 has no dump version; its entries use `DUMP_ENTRY_VERSION`.
+const DELTA_MAGIC: &[u8; 8] = b"MEMFSDLT";
+const DELTA_VERSION: u8 = 2;
+
+/// Identifies a state of a `MemoryFs` that was persisted, as returned by `checkpoint`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotId(u32);
+
+impl<
+    const STORAGE_SIZE: usize,
+    const PAGE_SIZE: usize,
+    const MAX_FILES: usize,
+    const MAX_NAME_LEN: usize,
+    const BITMAP_WORDS: usize,
+    A: Allocator,
+> MemoryFs<'_, STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>
+{
+    /// Mark the current state as persisted and start tracking changes from here.
+    ///
+    /// Call this once the output of `dump` or `dump_delta` is safely stored; the returned id
+    /// is the base for the next `dump_delta`.
+    pub fn checkpoint(&mut self) -> SnapshotId {
+        let id = SnapshotId(self.snapshot);
+        self.snapshot = self.snapshot.wrapping_add(1);
+        self.clear_dirty_pages();
+        id
+    }
+
+    /// Serialize the changes since the checkpoint `since` into a byte stream.
+    ///
+    /// Only pages in use that were written since then are included. The entry table is
+    /// always written in full, so deleted and renamed files need no special records.
+    /// `restore_delta` applies the stream on top of a filesystem in the `since` state.
+    ///
+    /// A delta covers a single checkpoint interval: changes are only tracked since the last
+    /// `checkpoint`, so deltas cannot be merged or skipped. To restore, apply the full dump and
+    /// then every delta after it, in order.
+    ///
+    /// # Errors
+    /// - `FsErr::InvalidOp` if `since` is not the id returned by the last `checkpoint`
+    pub fn dump_delta<W: FnMut(&[u8])>(
+        &self,
+        since: SnapshotId,
+        mut write: W,
+    ) -> Result<(), FsErr> {
+        if since.0.wrapping_add(1) != self.snapshot {
+            return Err(FsErr::InvalidOp);
+        }
+
+        // Runs of set bits in `dirty_pages & page_bitmap`, as free runs of the inverse.
+        let mut changed = self.page_bitmap.clone();
+        for (word, dirty) in changed.iter_mut().zip(&self.dirty_pages) {
+            *word = !(*word & dirty);
+        }
+        let changed = PageMap::new(&changed, self.num_pages());
+
+        let mut digest = CHECKSUM.digest();
+        let mut total_len: u32 = 0;
+        {
+            let mut write = |bytes: &[u8]| {
+                digest.update(bytes);
+                write(bytes);
+                total_len = total_len.wrapping_add(bytes.len() as u32);
+            };
+
+            write(DELTA_MAGIC);
+            write(&[DELTA_VERSION, DUMP_VERSION]);
+            write(&(self.page_size() as u32).to_le_bytes());
+            write(&(self.num_pages() as u32).to_le_bytes());
+            write(&since.0.to_le_bytes());
+            write(&self.snapshot.to_le_bytes());
+            write(&(self.entries.len() as u32).to_le_bytes());
+            Self::dump_entries(&self.entries, true, &mut write)?;
+
+            write(&(changed.free_runs(0).count() as u32).to_le_bytes());
+            let page_size = self.page_size();
+            for run in changed.free_runs(0) {
+                write(&(run.start as u32).to_le_bytes());
+                write(&(run.len() as u32).to_le_bytes());
+                write(&self.storage[run.start * page_size..run.end * page_size]);
+            }
+        }
+
+        write(b"MEMFSEND");
+        write(&total_len.to_le_bytes());
+        write(&digest.finalize().to_le_bytes());
+        Ok(())
+    }
+
+    /// Apply a byte stream created by `dump_delta` on top of this filesystem.
+    ///
+    /// The filesystem must be in the state the delta was based on, for example restored from
+    /// the full dump and the deltas before it. Pages are written while the stream is read, so
+    /// if this fails after the header was accepted, the filesystem is left empty and has to be
+    /// restored from scratch.
+    ///
+    /// # Errors
+    /// - `FsErr::InvalidOp` if the delta is based on a different state
+    /// - `FsErr::Restore` if the stream is malformed, inconsistent, or checksum validation
+    ///   fails; the error tells which check failed and at which byte of the stream
+    /// - `FsErr::Restore` with `RestoreCheck::Truncated` if `read` fails, for example when the
+    ///   stream ends early
+    pub fn restore_delta<R>(&mut self, mut read: R) -> Result<(), FsErr>
+    where
+        R: FnMut(&mut [u8]) -> Result<(), FsErr>,
+    {
+        let mut read = |buf: &mut [u8]| read(buf).map_err(|_| RestoreCheck::Truncated.fail());
+        let mut digest = CHECKSUM.digest();
+        let total_len = Cell::new(0u32);
+        let snapshot = {
+            let mut read = |buf: &mut [u8]| -> Result<(), FsErr> {
+                read(buf)?;
+                digest.update(buf);
+                total_len.set(total_len.get().wrapping_add(buf.len() as u32));
+                Ok(())
+            };
+            self.restore_delta_contents(&mut read)
+                .map_err(|err| err.at(total_len.get() as usize))?
+        };
+
+        read_footer(&mut read, total_len.get(), digest.finalize())
+            .inspect_err(|_| self.clear_metadata())?;
+
+        self.snapshot = snapshot;
+        self.clear_dirty_pages();
+        Ok(())
+    }
+
+    // Read everything of a delta before the footer, returning its snapshot id.
+    fn restore_delta_contents(
+        &mut self,
+        read: &mut impl FnMut(&mut [u8]) -> Result<(), FsErr>,
+    ) -> Result<u32, FsErr> {
+        let mut head = [0u8; 9];
+        read(&mut head)?;
+        if &head[..8] != DELTA_MAGIC {
+            return Err(RestoreCheck::Magic.fail());
+        }
+        let entry_version = match head[8] {
+            1 => DUMP_ENTRY_VERSION,
+            DELTA_VERSION => {
+                let mut version = [0u8; 1];
+                read(&mut version)?;
+                // Entries without ids predate directories, which deltas always had.
+                if !(DUMP_ENTRY_VERSION..=DUMP_VERSION).contains(&version[0]) {
+                    return Err(RestoreCheck::Version.fail());
+                }
+                version[0]
+            }
+            _ => return Err(RestoreCheck::Version.fail()),
+        };
+        if read_u32(read)? as usize != self.page_size()
+            || read_u32(read)? as usize != self.num_pages()
+        {
+            return Err(RestoreCheck::Layout.fail());
+        }
+        if read_u32(read)? != self.snapshot {
+            return Err(FsErr::InvalidOp);
+        }
+        let snapshot = read_u32(read)?;
+        let num_entries = read_u32(read)? as usize;
+
+        // The entry table replaces the current one; leave the filesystem empty if that fails.
+        self.clear_metadata();
+        self.restore_delta_pages(entry_version, num_entries, read)
+            .inspect_err(|_| self.clear_metadata())?;
+        Ok(snapshot)
+    }
+
+    // Read the entry table and the changed pages of a delta.
+    fn restore_delta_pages(
+        &mut self,
+        entry_version: u8,
+        num_entries: usize,
+        read: &mut impl FnMut(&mut [u8]) -> Result<(), FsErr>,
+    ) -> Result<(), FsErr> {
+        let page_size = self.page_size();
+        self.restore_entries(entry_version, num_entries, page_size, false, read)?;
+        self.claim_pages()?;
+
+        for _ in 0..read_u32(read)? {
+            let start = read_u32(read)? as usize;
+            let len = read_u32(read)? as usize;
+            let end = start.checked_add(len).ok_or(RestoreCheck::Extent.fail())?;
+            if end > self.num_pages() {
+                return Err(RestoreCheck::Extent.fail());
+            }
+            read(&mut self.storage[start * page_size..end * page_size])?;
+        }
+        Ok(())
+    }
+}
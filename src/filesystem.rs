@@ -0,0 +1,217 @@
+use core::ops::Deref;
+use core::str::FromStr;
+use heapless::String;
+
+use crate::{
+    Allocator, DEFAULT_MAX_NAME_LEN, FileEntry, FileFlags, FsErr, MAX_FILE_NAME_LENGTH, MemoryFs,
+};
+
+/// Metadata of a file or directory, as returned by `FileSystem::metadata`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    len: usize,
+    flags: FileFlags,
+    is_dir: bool,
+}
+
+impl Metadata {
+    /// Create metadata from its parts. Mainly useful for other `FileSystem` implementations.
+    pub const fn new(len: usize, flags: FileFlags, is_dir: bool) -> Self {
+        Self { len, flags, is_dir }
+    }
+
+    /// Size of the file in bytes. Always `0` for directories.
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the file is empty.
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Flags of the file.
+    pub const fn flags(&self) -> FileFlags {
+        self.flags
+    }
+
+    /// Whether this is a directory.
+    pub const fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+
+    /// Whether this is a regular file.
+    pub const fn is_file(&self) -> bool {
+        !self.is_dir
+    }
+}
+
+impl<const MAX_NAME_LEN: usize> From<&FileEntry<MAX_NAME_LEN>> for Metadata {
+    fn from(entry: &FileEntry<MAX_NAME_LEN>) -> Self {
+        Self::new(entry.size, entry.flags, entry.is_dir)
+    }
+}
+
+/// An entry yielded by `FileSystem::read_dir`.
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    /// Name of the entry within its directory.
+    pub name: String<MAX_FILE_NAME_LENGTH>,
+    pub metadata: Metadata,
+}
+
+/// Common file operations, implemented by `MemoryFs` and (with `std`) by `HostFs`.
+///
+/// Paths use `/` as separator and are relative to the root of the filesystem.
+/// Code generic over `FileSystem` can run against in-memory storage and a real directory
+/// alike. Implementations follow the semantics documented on `MemoryFs`; notably files
+/// have no holes, so `write_at` past the end and growing `truncate` are rejected.
+pub trait FileSystem {
+    type Error: core::error::Error;
+
+    /// Iterator returned by `read_dir`.
+    type ReadDir<'s>: Iterator<Item = Result<DirEntry, Self::Error>>
+    where
+        Self: 's;
+
+    /// Create a new file with `data`. Fails if the file already exists.
+    fn create(&mut self, path: &str, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Copy the start of a file into `buf` and return the number of bytes copied.
+    fn read_into(&self, path: &str, buf: &mut [u8]) -> Result<usize, Self::Error>;
+
+    /// Replace the contents of a file, creating it if it does not exist.
+    fn write(&mut self, path: &str, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Overwrite part of an existing file, starting at `offset <= size`.
+    fn write_at(&mut self, path: &str, offset: usize, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Append `data` to an existing file.
+    fn append(&mut self, path: &str, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Shrink a file to `new_size` bytes.
+    fn truncate(&mut self, path: &str, new_size: usize) -> Result<(), Self::Error>;
+
+    /// Rename or move a file or directory. Fails if `new_path` already exists.
+    fn rename(&mut self, path: &str, new_path: &str) -> Result<(), Self::Error>;
+
+    /// Delete a file.
+    fn delete(&mut self, path: &str) -> Result<(), Self::Error>;
+
+    /// Check whether a file or directory exists.
+    fn exists(&self, path: &str) -> bool;
+
+    /// Look up the metadata of a file or directory.
+    fn metadata(&self, path: &str) -> Result<Metadata, Self::Error>;
+
+    /// Iterate over the entries directly inside a directory.
+    fn read_dir(&self, path: &str) -> Result<Self::ReadDir<'_>, Self::Error>;
+}
+
+/// Iterator over a directory of a `MemoryFs`, returned by `FileSystem::read_dir`.
+pub struct ReadDir<'s, const MAX_NAME_LEN: usize = DEFAULT_MAX_NAME_LEN> {
+    entries: core::slice::Iter<'s, FileEntry<MAX_NAME_LEN>>,
+    parent: u32,
+}
+
+impl<const MAX_NAME_LEN: usize> Iterator for ReadDir<'_, MAX_NAME_LEN> {
+    type Item = Result<DirEntry, FsErr>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let parent = self.parent;
+        let entry = self.entries.find(|f| f.parent == parent)?;
+        let name =
+            String::from_str(&entry.name).map_err(|_| FsErr::FileNameInvalid("File name too long"));
+        Some(name.map(|name| DirEntry {
+            name,
+            metadata: entry.into(),
+        }))
+    }
+}
+
+impl<
+    const STORAGE_SIZE: usize,
+    const PAGE_SIZE: usize,
+    const MAX_FILES: usize,
+    const MAX_NAME_LEN: usize,
+    const BITMAP_WORDS: usize,
+    A: Allocator,
+    S: Deref<Target = [u8]>,
+> MemoryFs<'_, STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A, S>
+{
+    // `FileSystem::metadata`, also used by `MemoryFsRo`.
+    pub(crate) fn path_metadata(&self, path: &str) -> Result<Metadata, FsErr> {
+        match self.resolve(path)? {
+            Some(index) => Ok((&self.entries[index]).into()),
+            None => Ok(Metadata::new(0, FileFlags::empty(), true)),
+        }
+    }
+
+    // `FileSystem::read_dir`, also used by `MemoryFsRo`.
+    pub(crate) fn dir_entries(&self, path: &str) -> Result<ReadDir<'_, MAX_NAME_LEN>, FsErr> {
+        Ok(ReadDir {
+            entries: self.entries.iter(),
+            parent: self.find_dir_id(path)?,
+        })
+    }
+}
+
+impl<
+    const STORAGE_SIZE: usize,
+    const PAGE_SIZE: usize,
+    const MAX_FILES: usize,
+    const MAX_NAME_LEN: usize,
+    const BITMAP_WORDS: usize,
+    A: Allocator,
+> FileSystem for MemoryFs<'_, STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>
+{
+    type Error = FsErr;
+    type ReadDir<'s>
+        = ReadDir<'s, MAX_NAME_LEN>
+    where
+        Self: 's;
+
+    fn create(&mut self, path: &str, data: &[u8]) -> Result<(), FsErr> {
+        MemoryFs::create(self, path, data)
+    }
+
+    fn read_into(&self, path: &str, buf: &mut [u8]) -> Result<usize, FsErr> {
+        MemoryFs::read_into(self, path, buf)
+    }
+
+    fn write(&mut self, path: &str, data: &[u8]) -> Result<(), FsErr> {
+        MemoryFs::write(self, path, data)
+    }
+
+    fn write_at(&mut self, path: &str, offset: usize, data: &[u8]) -> Result<(), FsErr> {
+        MemoryFs::write_at(self, path, offset, data)
+    }
+
+    fn append(&mut self, path: &str, data: &[u8]) -> Result<(), FsErr> {
+        MemoryFs::append(self, path, data)
+    }
+
+    fn truncate(&mut self, path: &str, new_size: usize) -> Result<(), FsErr> {
+        MemoryFs::truncate(self, path, new_size)
+    }
+
+    fn rename(&mut self, path: &str, new_path: &str) -> Result<(), FsErr> {
+        MemoryFs::rename(self, path, new_path)
+    }
+
+    fn delete(&mut self, path: &str) -> Result<(), FsErr> {
+        MemoryFs::delete(self, path)
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        MemoryFs::exists(self, path)
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata, FsErr> {
+        self.path_metadata(path)
+    }
+
+    fn read_dir(&self, path: &str) -> Result<ReadDir<'_, MAX_NAME_LEN>, FsErr> {
+        self.dir_entries(path)
+    }
+}
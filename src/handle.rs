@@ -0,0 +1,469 @@
+use crate::{
+    Allocator, DEFAULT_BITMAP_WORDS, DEFAULT_MAX_FILES, DEFAULT_MAX_NAME_LEN, FileFlags, FirstFit,
+    FsErr, Growth, MemoryFs,
+};
+
+/// Options for opening a file, modelled after `std::fs::OpenOptions`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct OpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    create: bool,
+    truncate: bool,
+}
+
+impl OpenOptions {
+    /// Create a blank set of options with every option disabled.
+    pub const fn new() -> Self {
+        Self {
+            read: false,
+            write: false,
+            append: false,
+            create: false,
+            truncate: false,
+        }
+    }
+
+    /// Allow reading through the handle.
+    pub const fn read(mut self, read: bool) -> Self {
+        self.read = read;
+        self
+    }
+
+    /// Allow writing through the handle, starting at the cursor position.
+    pub const fn write(mut self, write: bool) -> Self {
+        self.write = write;
+        self
+    }
+
+    /// Make every write go to the end of the file, regardless of the cursor position.
+    pub const fn append(mut self, append: bool) -> Self {
+        self.append = append;
+        self
+    }
+
+    /// Create the file if it does not exist. Requires `write` or `append`.
+    pub const fn create(mut self, create: bool) -> Self {
+        self.create = create;
+        self
+    }
+
+    /// Truncate the file to zero length when opening. Requires `write` or `append`.
+    pub const fn truncate(mut self, truncate: bool) -> Self {
+        self.truncate = truncate;
+        self
+    }
+
+    const fn writable(&self) -> bool {
+        self.write || self.append
+    }
+}
+
+/// Position to seek to within a file, mirroring `std::io::SeekFrom`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    End(i64),
+    Current(i64),
+}
+
+/// An open file with a cursor.
+///
+/// Created by `MemoryFs::open`. The handle refers to the file by its entry, so operations do
+/// not look up the name again. It borrows the filesystem mutably for as long as it is open.
+pub struct FileHandle<
+    'f,
+    'a,
+    const STORAGE_SIZE: usize,
+    const PAGE_SIZE: usize,
+    const MAX_FILES: usize = DEFAULT_MAX_FILES,
+    const MAX_NAME_LEN: usize = DEFAULT_MAX_NAME_LEN,
+    const BITMAP_WORDS: usize = DEFAULT_BITMAP_WORDS,
+    A: Allocator = FirstFit,
+> {
+    fs: &'f mut MemoryFs<'a, STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>,
+    index: usize,
+    pos: usize,
+    options: OpenOptions,
+    verified: bool, // Checksum checked since the last write through this handle.
+}
+
+impl<
+    'a,
+    const STORAGE_SIZE: usize,
+    const PAGE_SIZE: usize,
+    const MAX_FILES: usize,
+    const MAX_NAME_LEN: usize,
+    const BITMAP_WORDS: usize,
+    A: Allocator,
+> MemoryFs<'a, STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>
+{
+    /// Open a file and return a handle with a cursor at the start of the file.
+    ///
+    /// At least one of `read`, `write` or `append` must be set.
+    ///
+    /// # Errors
+    /// - `FsErr::NotFound` if the file does not exist and `create` is not set
+    /// - `FsErr::IsDirectory` if the path refers to a directory
+    /// - `FsErr::ReadOnly` if the file has `IMMUTABLE` and is opened for writing
+    /// - `FsErr::InvalidOp` if the combination of options is invalid
+    /// - Any error of `create` when the file is created
+    pub fn open(
+        &mut self,
+        name: &str,
+        options: OpenOptions,
+    ) -> Result<
+        FileHandle<'_, 'a, STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>,
+        FsErr,
+    > {
+        if !options.read && !options.writable() {
+            return Err(FsErr::InvalidOp);
+        }
+        if (options.create || options.truncate) && !options.writable() {
+            return Err(FsErr::InvalidOp);
+        }
+
+        let index = match self.find_file_index(name) {
+            Ok(index) => index,
+            Err(FsErr::NotFound) if options.create => {
+                self.create(name, &[])?;
+                self.find_file_index(name)?
+            }
+            Err(e) => return Err(e),
+        };
+
+        if options.writable() && self.entries[index].flags.contains(FileFlags::IMMUTABLE) {
+            return Err(FsErr::ReadOnly);
+        }
+        if options.truncate {
+            self.truncate_index(index, 0)?;
+        }
+
+        Ok(FileHandle {
+            fs: self,
+            index,
+            pos: 0,
+            options,
+            verified: false,
+        })
+    }
+}
+
+impl<
+    const STORAGE_SIZE: usize,
+    const PAGE_SIZE: usize,
+    const MAX_FILES: usize,
+    const MAX_NAME_LEN: usize,
+    const BITMAP_WORDS: usize,
+    A: Allocator,
+> FileHandle<'_, '_, STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>
+{
+    /// Read from the cursor position into `buf` and advance the cursor.
+    ///
+    /// Returns the number of bytes read, which is `0` at the end of the file.
+    ///
+    /// # Errors
+    /// - `FsErr::InvalidOp` if the handle was not opened for reading
+    /// - `FsErr::Corrupt` if the file has `CHECKSUMMED` and its contents fail verification
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsErr> {
+        self.check_readable()?;
+
+        let len = buf.len().min(self.size().saturating_sub(self.pos));
+        self.fs.copy_out(self.index, self.pos, &mut buf[..len]);
+        self.pos += len;
+        Ok(len)
+    }
+
+    /// Write `data` at the cursor position and advance the cursor.
+    ///
+    /// Writing at the end of the file (or through a handle opened with `append`) appends.
+    /// The write is all-or-nothing: on success the returned count is always `data.len()`.
+    ///
+    /// # Errors
+    /// - `FsErr::InvalidOp` if the handle was not opened for writing, or when overwriting
+    ///   data of an `APPEND_ONLY` file
+    /// - Any error of `write_at` / `append`
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, FsErr> {
+        if !self.options.writable() {
+            return Err(FsErr::InvalidOp);
+        }
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        if self.options.append || self.pos == self.size() {
+            self.fs.append_index(self.index, data, Growth::Fragment)?;
+            self.pos = self.size();
+        } else {
+            self.fs.write_at_index(self.index, self.pos, data)?;
+            self.pos += data.len();
+        }
+        self.verified = false;
+        Ok(data.len())
+    }
+
+    /// Move the cursor and return the new position from the start of the file.
+    ///
+    /// Files have no holes, so the cursor cannot be moved past the end of the file.
+    ///
+    /// # Errors
+    /// - `FsErr::InvalidOp` if the new position is before the start or past the end of the file
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<u64, FsErr> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => usize::try_from(offset).ok(),
+            SeekFrom::End(delta) => isize::try_from(delta)
+                .ok()
+                .and_then(|delta| self.size().checked_add_signed(delta)),
+            SeekFrom::Current(delta) => isize::try_from(delta)
+                .ok()
+                .and_then(|delta| self.pos.checked_add_signed(delta)),
+        };
+
+        match new_pos {
+            Some(new_pos) if new_pos <= self.size() => {
+                self.pos = new_pos;
+                Ok(new_pos as u64)
+            }
+            _ => Err(FsErr::InvalidOp),
+        }
+    }
+
+    /// Current cursor position from the start of the file.
+    pub fn tell(&self) -> u64 {
+        self.pos as u64
+    }
+
+    /// Current size of the file in bytes.
+    pub fn size(&self) -> usize {
+        self.fs.entries[self.index].size
+    }
+
+    /// Close the handle, releasing the borrow of the filesystem.
+    ///
+    /// All writes are applied immediately, so this is equivalent to dropping the handle.
+    pub fn close(self) {}
+
+    /// Borrow the file contents from the cursor up to the end of the current extent.
+    ///
+    /// Returns an empty slice at the end of the file. Used for the `BufRead` implementations.
+    #[cfg(any(feature = "std", feature = "embedded-io"))]
+    fn fill_buf(&mut self) -> Result<&[u8], FsErr> {
+        self.check_readable()?;
+
+        let entry = &self.fs.entries[self.index];
+        let len = entry.size.saturating_sub(self.pos);
+        match MemoryFs::<STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>::spans(
+            &entry.extents,
+            self.fs.page_size(),
+            self.pos,
+            len,
+        )
+        .next()
+        {
+            Some(range) => Ok(&self.fs.storage[range]),
+            None => Ok(&[]),
+        }
+    }
+
+    /// Advance the cursor past `amt` bytes returned by `fill_buf`.
+    #[cfg(any(feature = "std", feature = "embedded-io"))]
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.size());
+    }
+
+    /// Check that the handle may read, verifying the checksum once until the next write.
+    ///
+    /// The handle holds the only borrow of the filesystem, so the file cannot change
+    /// behind its back.
+    fn check_readable(&mut self) -> Result<(), FsErr> {
+        if !self.options.read {
+            return Err(FsErr::InvalidOp);
+        }
+        if !self.verified {
+            self.fs.verify_checksum(self.index)?;
+            self.verified = true;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<
+    const STORAGE_SIZE: usize,
+    const PAGE_SIZE: usize,
+    const MAX_FILES: usize,
+    const MAX_NAME_LEN: usize,
+    const BITMAP_WORDS: usize,
+    A: Allocator,
+> std::io::Read
+    for FileHandle<'_, '_, STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        Ok(FileHandle::read(self, buf)?)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<
+    const STORAGE_SIZE: usize,
+    const PAGE_SIZE: usize,
+    const MAX_FILES: usize,
+    const MAX_NAME_LEN: usize,
+    const BITMAP_WORDS: usize,
+    A: Allocator,
+> std::io::BufRead
+    for FileHandle<'_, '_, STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>
+{
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        Ok(self.fill_buf()?)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.consume(amt);
+    }
+}
+
+#[cfg(feature = "std")]
+impl<
+    const STORAGE_SIZE: usize,
+    const PAGE_SIZE: usize,
+    const MAX_FILES: usize,
+    const MAX_NAME_LEN: usize,
+    const BITMAP_WORDS: usize,
+    A: Allocator,
+> std::io::Write
+    for FileHandle<'_, '_, STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(FileHandle::write(self, buf)?)
+    }
+
+    /// Writes are applied to storage immediately, so there is nothing to flush.
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<
+    const STORAGE_SIZE: usize,
+    const PAGE_SIZE: usize,
+    const MAX_FILES: usize,
+    const MAX_NAME_LEN: usize,
+    const BITMAP_WORDS: usize,
+    A: Allocator,
+> std::io::Seek
+    for FileHandle<'_, '_, STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>
+{
+    /// Unlike `std::fs::File`, seeking past the end of the file fails with `InvalidInput`.
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            std::io::SeekFrom::Start(offset) => SeekFrom::Start(offset),
+            std::io::SeekFrom::End(delta) => SeekFrom::End(delta),
+            std::io::SeekFrom::Current(delta) => SeekFrom::Current(delta),
+        };
+        Ok(FileHandle::seek(self, pos)?)
+    }
+
+    fn stream_position(&mut self) -> std::io::Result<u64> {
+        Ok(self.tell())
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl<
+    const STORAGE_SIZE: usize,
+    const PAGE_SIZE: usize,
+    const MAX_FILES: usize,
+    const MAX_NAME_LEN: usize,
+    const BITMAP_WORDS: usize,
+    A: Allocator,
+> embedded_io::ErrorType
+    for FileHandle<'_, '_, STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>
+{
+    type Error = FsErr;
+}
+
+#[cfg(feature = "embedded-io")]
+impl<
+    const STORAGE_SIZE: usize,
+    const PAGE_SIZE: usize,
+    const MAX_FILES: usize,
+    const MAX_NAME_LEN: usize,
+    const BITMAP_WORDS: usize,
+    A: Allocator,
+> embedded_io::Read
+    for FileHandle<'_, '_, STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsErr> {
+        FileHandle::read(self, buf)
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl<
+    const STORAGE_SIZE: usize,
+    const PAGE_SIZE: usize,
+    const MAX_FILES: usize,
+    const MAX_NAME_LEN: usize,
+    const BITMAP_WORDS: usize,
+    A: Allocator,
+> embedded_io::BufRead
+    for FileHandle<'_, '_, STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>
+{
+    fn fill_buf(&mut self) -> Result<&[u8], FsErr> {
+        FileHandle::fill_buf(self)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        FileHandle::consume(self, amt);
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl<
+    const STORAGE_SIZE: usize,
+    const PAGE_SIZE: usize,
+    const MAX_FILES: usize,
+    const MAX_NAME_LEN: usize,
+    const BITMAP_WORDS: usize,
+    A: Allocator,
+> embedded_io::Write
+    for FileHandle<'_, '_, STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>
+{
+    fn write(&mut self, buf: &[u8]) -> Result<usize, FsErr> {
+        FileHandle::write(self, buf)
+    }
+
+    /// Writes are applied to storage immediately, so there is nothing to flush.
+    fn flush(&mut self) -> Result<(), FsErr> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl<
+    const STORAGE_SIZE: usize,
+    const PAGE_SIZE: usize,
+    const MAX_FILES: usize,
+    const MAX_NAME_LEN: usize,
+    const BITMAP_WORDS: usize,
+    A: Allocator,
+> embedded_io::Seek
+    for FileHandle<'_, '_, STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>
+{
+    /// Seeking past the end of the file fails with `FsErr::InvalidOp`.
+    fn seek(&mut self, pos: embedded_io::SeekFrom) -> Result<u64, FsErr> {
+        let pos = match pos {
+            embedded_io::SeekFrom::Start(offset) => SeekFrom::Start(offset),
+            embedded_io::SeekFrom::End(delta) => SeekFrom::End(delta),
+            embedded_io::SeekFrom::Current(delta) => SeekFrom::Current(delta),
+        };
+        FileHandle::seek(self, pos)
+    }
+
+    fn stream_position(&mut self) -> Result<u64, FsErr> {
+        Ok(self.tell())
+    }
+}
@@ -0,0 +1,169 @@
+use core::str::FromStr;
+use heapless::String;
+use std::fs;
+use std::io::{self, ErrorKind, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{DirEntry, FileFlags, FileSystem, Metadata};
+
+/// A `FileSystem` backed by a directory on the host. Only available with the `std` feature.
+///
+/// Paths are resolved relative to the root directory passed to `new`. This is meant for
+/// desktop tooling, not as a sandbox: paths containing `..` may escape the root.
+///
+/// Operations mirror the semantics of `MemoryFs` where the host allows it: `create` and
+/// `rename` do not overwrite, `write_at` past the end and growing `truncate` fail with
+/// `InvalidInput`. Read-only host files report `FileFlags::IMMUTABLE` in their metadata.
+#[derive(Clone, Debug)]
+pub struct HostFs {
+    root: PathBuf,
+}
+
+impl HostFs {
+    /// Use `root` as the root directory. The directory is not created or checked here.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// The root directory on the host.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn host_path(&self, path: &str) -> PathBuf {
+        self.root.join(path.trim_start_matches('/'))
+    }
+}
+
+impl FileSystem for HostFs {
+    type Error = io::Error;
+    type ReadDir<'s> = HostReadDir;
+
+    fn create(&mut self, path: &str, data: &[u8]) -> io::Result<()> {
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(self.host_path(path))?
+            .write_all(data)
+    }
+
+    fn read_into(&self, path: &str, buf: &mut [u8]) -> io::Result<usize> {
+        let mut file = fs::File::open(self.host_path(path))?;
+        let mut read = 0;
+        while read < buf.len() {
+            match file.read(&mut buf[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(read)
+    }
+
+    fn write(&mut self, path: &str, data: &[u8]) -> io::Result<()> {
+        fs::write(self.host_path(path), data)
+    }
+
+    fn write_at(&mut self, path: &str, offset: usize, data: &[u8]) -> io::Result<()> {
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .open(self.host_path(path))?;
+        if offset as u64 > file.metadata()?.len() {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        file.seek(io::SeekFrom::Start(offset as u64))?;
+        file.write_all(data)
+    }
+
+    fn append(&mut self, path: &str, data: &[u8]) -> io::Result<()> {
+        fs::OpenOptions::new()
+            .append(true)
+            .open(self.host_path(path))?
+            .write_all(data)
+    }
+
+    fn truncate(&mut self, path: &str, new_size: usize) -> io::Result<()> {
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .open(self.host_path(path))?;
+        if new_size as u64 > file.metadata()?.len() {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        file.set_len(new_size as u64)
+    }
+
+    fn rename(&mut self, path: &str, new_path: &str) -> io::Result<()> {
+        let from = self.host_path(path);
+        let to = self.host_path(new_path);
+        fs::symlink_metadata(&from)?;
+        if fs::symlink_metadata(&to).is_ok() {
+            return Err(ErrorKind::AlreadyExists.into());
+        }
+        fs::rename(from, to)
+    }
+
+    fn delete(&mut self, path: &str) -> io::Result<()> {
+        fs::remove_file(self.host_path(path))
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.host_path(path).exists()
+    }
+
+    fn metadata(&self, path: &str) -> io::Result<Metadata> {
+        Ok(host_metadata(&fs::metadata(self.host_path(path))?))
+    }
+
+    fn read_dir(&self, path: &str) -> io::Result<HostReadDir> {
+        Ok(HostReadDir {
+            inner: fs::read_dir(self.host_path(path))?,
+        })
+    }
+}
+
+/// Iterator over a host directory, returned by `FileSystem::read_dir` for `HostFs`.
+///
+/// Entries whose name is not valid UTF-8 or too long for a `DirEntry` yield an
+/// `InvalidData` error.
+pub struct HostReadDir {
+    inner: fs::ReadDir,
+}
+
+impl Iterator for HostReadDir {
+    type Item = io::Result<DirEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = match self.inner.next()? {
+            Ok(entry) => entry,
+            Err(e) => return Some(Err(e)),
+        };
+        Some(host_dir_entry(&entry))
+    }
+}
+
+fn host_dir_entry(entry: &fs::DirEntry) -> io::Result<DirEntry> {
+    let name = entry
+        .file_name()
+        .to_str()
+        .and_then(|name| String::from_str(name).ok())
+        .ok_or(ErrorKind::InvalidData)?;
+    Ok(DirEntry {
+        name,
+        metadata: host_metadata(&entry.metadata()?),
+    })
+}
+
+fn host_metadata(metadata: &fs::Metadata) -> Metadata {
+    let flags = if metadata.permissions().readonly() {
+        FileFlags::IMMUTABLE
+    } else {
+        FileFlags::empty()
+    };
+    let len = if metadata.is_dir() {
+        0
+    } else {
+        metadata.len() as usize
+    };
+    Metadata::new(len, flags, metadata.is_dir())
+}
@@ -0,0 +1,296 @@
+use core::cell::Cell;
+use core::ops::Deref;
+
+use crate::{
+    Allocator, CHECKSUM, DUMP_ENTRY_VERSION, DynMemoryFs, FileEntry, FsErr, MemoryFs, RestoreCheck,
+    bitmap_words,
+};
+
+// Superblock layout, at the start of an image buffer:
+// magic "MEMFSIMG", version (u8), page size, num pages, data offset, entry count (u32 each),
+// the entry table in the dump format, and a CRC32 over all of the above.
+const IMAGE_MAGIC: &[u8; 8] = b"MEMFSIMG";
+const IMAGE_VERSION: u8 = 1;
+const IMAGE_HEADER_SIZE: usize = 8 + 1 + 4 * 4;
+
+impl<const MAX_FILES: usize, const MAX_NAME_LEN: usize, const BITMAP_WORDS: usize, A: Allocator>
+    DynMemoryFs<'_, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>
+{
+    /// Size of the superblock region that `format` reserves at the start of the buffer.
+    ///
+    /// It holds a full entry table, so it grows with `MAX_FILES` and `MAX_NAME_LEN`.
+    pub const fn superblock_size() -> usize {
+        let checksum = 4;
+        IMAGE_HEADER_SIZE + MAX_FILES * FileEntry::<MAX_NAME_LEN>::serialized_max_size() + checksum
+    }
+}
+
+impl<
+    'a,
+    const MAX_FILES: usize,
+    const MAX_NAME_LEN: usize,
+    const BITMAP_WORDS: usize,
+    A: Allocator + Default,
+> DynMemoryFs<'a, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>
+{
+    /// Create an empty filesystem image in `buffer`, split into `page_size` byte pages.
+    ///
+    /// The start of the buffer is reserved for a superblock (see `superblock_size`), the rest
+    /// holds file data in place. The image can later be opened again with `mount`, after
+    /// `sync` wrote the current metadata.
+    ///
+    /// # Errors
+    /// - `FsErr::NoSpace` if `buffer` is too small for the superblock
+    /// - `FsErr::InvalidOp` if `page_size` is `0` or the data region has more pages than the
+    ///   page bitmap can track
+    pub fn format(buffer: &'a mut [u8], page_size: usize) -> Result<Self, FsErr> {
+        let data_offset = Self::superblock_size();
+        if buffer.len() < data_offset {
+            return Err(FsErr::NoSpace);
+        }
+        let (superblock, data) = buffer.split_at_mut(data_offset);
+
+        let mut fs = Self::from_slice(data, page_size)?;
+        fs.image = Some(superblock);
+        fs.sync()?;
+        Ok(fs)
+    }
+
+    /// Open an image written by `format` and `sync`, using `buffer` as backing storage.
+    ///
+    /// Only the superblock is parsed; file data stays where it is and is not copied, so
+    /// mounting takes time proportional to the number of files, not the storage size.
+    /// File checksums are verified when files are read.
+    ///
+    /// Files written after the last `sync` may have newer contents than the superblock
+    /// describes. Once pages of a synced file were given to another file, the superblock is
+    /// invalidated, so mounting fails instead of returning another file's data.
+    ///
+    /// # Errors
+    /// - `FsErr::Restore` if the superblock is invalid, was invalidated, or does not match its
+    ///   checksum
+    /// - `FsErr::InvalidOp` if the image has more pages than the page bitmap can track
+    pub fn mount(buffer: &'a mut [u8]) -> Result<Self, FsErr> {
+        let header = ImageHeader::parse(buffer)?;
+        if bitmap_words(header.data_len, header.page_size) > BITMAP_WORDS {
+            return Err(FsErr::InvalidOp);
+        }
+
+        let (superblock, data) =
+            buffer[..header.data_offset + header.data_len].split_at_mut(header.data_offset);
+        let mut fs = Self::from_slice(data, header.page_size)?;
+        fs.load_superblock(superblock, &header)?;
+        fs.image = Some(superblock);
+        Ok(fs)
+    }
+}
+
+/// Header fields of an image superblock.
+pub(crate) struct ImageHeader {
+    pub(crate) page_size: usize,
+    pub(crate) data_offset: usize,
+    pub(crate) data_len: usize,
+    num_entries: usize,
+}
+
+impl ImageHeader {
+    /// Parse and check the header at the start of `image`.
+    pub(crate) fn parse(image: &[u8]) -> Result<Self, FsErr> {
+        let header = image
+            .get(..IMAGE_HEADER_SIZE)
+            .ok_or(RestoreCheck::Truncated.fail().at(image.len()))?;
+        let field = |offset: usize| {
+            u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap()) as usize
+        };
+        let (page_size, num_pages, data_offset, num_entries) =
+            (field(9), field(13), field(17), field(21));
+        if &header[..8] != IMAGE_MAGIC {
+            return Err(RestoreCheck::Magic.fail().at(0));
+        }
+        if header[8] != IMAGE_VERSION {
+            return Err(RestoreCheck::Version.fail().at(8));
+        }
+        if page_size == 0 {
+            return Err(RestoreCheck::Header.fail().at(9));
+        }
+
+        let data_len = num_pages
+            .checked_mul(page_size)
+            .ok_or(RestoreCheck::Header.fail().at(13))?;
+        let data_end = data_offset
+            .checked_add(data_len)
+            .ok_or(RestoreCheck::Header.fail().at(17))?;
+        if data_offset < IMAGE_HEADER_SIZE {
+            return Err(RestoreCheck::Header.fail().at(17));
+        }
+        if data_end > image.len() {
+            return Err(RestoreCheck::Truncated.fail().at(image.len()));
+        }
+        Ok(Self {
+            page_size,
+            data_offset,
+            data_len,
+            num_entries,
+        })
+    }
+}
+
+impl<
+    const STORAGE_SIZE: usize,
+    const PAGE_SIZE: usize,
+    const MAX_FILES: usize,
+    const MAX_NAME_LEN: usize,
+    const BITMAP_WORDS: usize,
+    A: Allocator,
+    S: Deref<Target = [u8]>,
+> MemoryFs<'_, STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A, S>
+{
+    /// Load the entry table from `superblock` and check its checksum.
+    pub(crate) fn load_superblock(
+        &mut self,
+        superblock: &[u8],
+        header: &ImageHeader,
+    ) -> Result<(), FsErr> {
+        let mut digest = CHECKSUM.digest();
+        digest.update(&superblock[..IMAGE_HEADER_SIZE]);
+        let pos = Cell::new(IMAGE_HEADER_SIZE);
+        let at_pos = |err: FsErr| err.at(pos.get());
+        self.restore_entries(
+            DUMP_ENTRY_VERSION,
+            header.num_entries,
+            header.page_size,
+            false,
+            &mut |buf: &mut [u8]| {
+                let bytes = superblock
+                    .get(pos.get()..pos.get() + buf.len())
+                    .ok_or(RestoreCheck::Truncated.fail())?;
+                buf.copy_from_slice(bytes);
+                digest.update(bytes);
+                pos.set(pos.get() + buf.len());
+                Ok(())
+            },
+        )
+        .map_err(at_pos)?;
+
+        let crc = superblock
+            .get(pos.get()..pos.get() + 4)
+            .ok_or(RestoreCheck::Truncated.fail().at(superblock.len()))?;
+        if u32::from_le_bytes(crc.try_into().unwrap()) != digest.finalize() {
+            return Err(RestoreCheck::Checksum.fail().at(pos.get()));
+        }
+        self.claim_pages().map_err(at_pos)
+    }
+
+    /// Make `mount` reject the superblock, once it no longer matches the storage.
+    pub(crate) fn invalidate_superblock(&mut self) {
+        if let Some(superblock) = self.image.as_deref_mut() {
+            superblock[..IMAGE_MAGIC.len()].fill(0);
+        }
+    }
+
+    /// Whether a valid superblock assigns any of pages `start..start + len` to a file.
+    ///
+    /// Walks the entry table in the superblock, so no copy of it is kept in RAM.
+    pub(crate) fn superblock_uses_pages(&self, start: usize, len: usize) -> bool {
+        let Some(superblock) = self.image.as_deref() else {
+            return false;
+        };
+        if !superblock.starts_with(IMAGE_MAGIC) {
+            return false;
+        }
+        let u16_at = |pos: usize| {
+            let bytes = superblock.get(pos..pos + 2)?;
+            Some(u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+        };
+        let u32_at = |pos: usize| {
+            let bytes = superblock.get(pos..pos + 4)?;
+            Some(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+        };
+
+        // Entries as written by `dump_entries`: name length and name, then id, parent, kind,
+        // size, flags, crc, the extent count and the extents.
+        let num_entries = u32_at(21).unwrap_or(0);
+        let mut pos = IMAGE_HEADER_SIZE;
+        for _ in 0..num_entries {
+            let Some(name_len) = u16_at(pos) else {
+                return false;
+            };
+            pos += 2 + name_len + 4 + 4 + 1 + 4 + 4 + 4;
+            let Some(extent_count) = u16_at(pos) else {
+                return false;
+            };
+            pos += 2;
+            for _ in 0..extent_count {
+                let (Some(extent_start), Some(extent_len)) = (u32_at(pos), u32_at(pos + 4)) else {
+                    return false;
+                };
+                if extent_start < start + len && start < extent_start + extent_len {
+                    return true;
+                }
+                pos += 8;
+            }
+        }
+        false
+    }
+}
+
+impl<
+    const STORAGE_SIZE: usize,
+    const PAGE_SIZE: usize,
+    const MAX_FILES: usize,
+    const MAX_NAME_LEN: usize,
+    const BITMAP_WORDS: usize,
+    A: Allocator,
+> MemoryFs<'_, STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>
+{
+    /// Write the current metadata to the superblock of an image created by `format` or
+    /// `mount`.
+    ///
+    /// File data is always written in place; call this after changes that should survive a
+    /// remount. If it is interrupted, the superblock fails its checksum on the next `mount`.
+    ///
+    /// Until the next `sync`, the superblock is invalidated as soon as pages of a file it
+    /// describes are reused, for example after deleting the file or compacting storage.
+    ///
+    /// # Errors
+    /// - `FsErr::InvalidOp` if the filesystem was not created by `format` or `mount`
+    /// - `FsErr::NoSpace` if the entry table does not fit in the superblock, which can only
+    ///   happen for images formatted with a smaller `MAX_FILES` or `MAX_NAME_LEN`
+    pub fn sync(&mut self) -> Result<(), FsErr> {
+        let page_size = self.page_size() as u32;
+        let num_pages = self.num_pages() as u32;
+        let Some(superblock) = self.image.as_deref_mut() else {
+            return Err(FsErr::InvalidOp);
+        };
+        let data_offset = superblock.len() as u32;
+
+        let mut digest = CHECKSUM.digest();
+        let mut pos = 0;
+        let mut overflow = false;
+        let mut write = |bytes: &[u8]| match superblock.get_mut(pos..pos + bytes.len()) {
+            Some(dest) => {
+                dest.copy_from_slice(bytes);
+                digest.update(bytes);
+                pos += bytes.len();
+            }
+            None => overflow = true,
+        };
+
+        write(IMAGE_MAGIC);
+        write(&[IMAGE_VERSION]);
+        write(&page_size.to_le_bytes());
+        write(&num_pages.to_le_bytes());
+        write(&data_offset.to_le_bytes());
+        write(&(self.entries.len() as u32).to_le_bytes());
+        Self::dump_entries(&self.entries, false, &mut write)?;
+        let crc = digest.finalize();
+
+        match superblock.get_mut(pos..pos + 4) {
+            Some(dest) if !overflow => {
+                dest.copy_from_slice(&crc.to_le_bytes());
+                Ok(())
+            }
+            _ => Err(FsErr::NoSpace),
+        }
+    }
+}
@@ -1,38 +1,380 @@
 #![cfg_attr(not(feature = "std"), no_std)]
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+use core::cell::Cell;
+use core::ops::{Deref, Range};
 use core::str::FromStr;
 use crc::{CRC_32_CKSUM, Crc, NoTable};
 use heapless::{String, Vec};
 
+use compress::{Decoder, Encoder};
+use storage::Storage;
+
+mod allocator;
+mod compress;
+mod delta;
+mod filesystem;
+mod handle;
+#[cfg(feature = "std")]
+mod host;
+mod image;
+mod readonly;
+mod storage;
+mod transaction;
+
+pub use allocator::{Allocator, BestFit, Buddy, FirstFit, NextFit, PageMap};
+pub use delta::SnapshotId;
+pub use filesystem::{DirEntry, FileSystem, Metadata, ReadDir};
+pub use handle::{FileHandle, OpenOptions, SeekFrom};
+#[cfg(feature = "std")]
+pub use host::{HostFs, HostReadDir};
+pub use readonly::MemoryFsRo;
+pub use transaction::Transaction;
+
+// Upper bound for `MAX_NAME_LEN`, and the longest name a `DirEntry` can hold.
 const MAX_FILE_NAME_LENGTH: usize = 255;
-const MAX_NUM_FILES: usize = 32;
+// Every entry reserves room for this many extents, so keep it small. Growing files are
+// repacked before they fragment, which keeps most files in a single extent.
+const MAX_EXTENTS_PER_FILE: usize = 4;
+
+// Id of the implicit root directory. Entries get ids starting from 1.
+const ROOT_ID: u32 = 0;
 
 pub const DEFAULT_STORAGE_SIZE: usize = 4096;
 pub const DEFAULT_PAGE_SIZE: usize = 32;
+pub const DEFAULT_MAX_FILES: usize = 32;
+pub const DEFAULT_MAX_NAME_LEN: usize = MAX_FILE_NAME_LENGTH;
+pub const DEFAULT_BITMAP_WORDS: usize = 256;
+
+/// Number of page bitmap words needed for `storage_size` bytes split into `page_size` byte
+/// pages. Use it for the `BITMAP_WORDS` parameter of `MemoryFs`.
+pub const fn bitmap_words(storage_size: usize, page_size: usize) -> usize {
+    (storage_size / page_size).div_ceil(32)
+}
+
+// CRC used for both dump integrity and per-file checksums.
+const CHECKSUM: Crc<u32, NoTable> = Crc::<u32, NoTable>::new(&CRC_32_CKSUM);
+
+// Current dump format version, and the flag set in the version byte of compressed dumps.
+const DUMP_VERSION: u8 = 8;
+const DUMP_MINOR_VERSION: u8 = 0;
+const DUMP_COMPRESSED: u8 = 0x80;
+// Length of the header fields of the current minor version.
+const DUMP_HEADER_LEN: u16 = 16;
+// Dump version whose entry encoding, without length prefixes, images and version 1 deltas use.
+const DUMP_ENTRY_VERSION: u8 = 5;
+
+/// Check the magic and version at the start of a dump. Returns the version and whether the
+/// rest of the stream is compressed.
+fn dump_version(head: &[u8; 6]) -> Result<(u8, bool), FsErr> {
+    // Version 2 images predate per-file checksums; these are recomputed after loading.
+    // Versions before 4 store a single extent per file.
+    // Versions before 5 have a flat namespace; `/` in names creates directories.
+    // Versions before 6 contain the whole storage instead of only the used pages.
+    // Versions before 7 have no snapshot id; restoring them starts a new history.
+    // Versions before 8 have no minor version. From 8 on the header and every entry start with
+    // their length, so fields appended by newer minor versions can be skipped.
+    let version = head[5] & !DUMP_COMPRESSED;
+    if &head[..5] != b"MEMFS" {
+        return Err(RestoreCheck::Magic.fail().at(0));
+    }
+    if !(2..=DUMP_VERSION).contains(&version) {
+        return Err(RestoreCheck::Version.fail().at(5));
+    }
+    Ok((version, head[5] & DUMP_COMPRESSED != 0))
+}
+
+/// Header fields of a dump, after the magic and version.
+struct DumpHeader {
+    page_size: usize,
+    num_pages: usize,
+    snapshot: u32,
+    num_entries: usize,
+}
+
+/// Read the header fields of a `version` dump.
+fn read_dump_header(
+    version: u8,
+    read: &mut impl FnMut(&mut [u8]) -> Result<(), FsErr>,
+) -> Result<DumpHeader, FsErr> {
+    // Any minor version can be read, newer ones only append fields.
+    let mut extra = 0;
+    if version >= 8 {
+        let mut minor_and_len = [0u8; 3];
+        read(&mut minor_and_len)?;
+        let header_len = u16::from_le_bytes([minor_and_len[1], minor_and_len[2]]);
+        extra = header_len
+            .checked_sub(DUMP_HEADER_LEN)
+            .ok_or(RestoreCheck::Header.fail())? as usize;
+    }
 
-const MAX_PAGE_BITMAP_WORDS: usize = 256;
+    let page_size = read_u32(read)?;
+    let num_pages = read_u32(read)?;
+    let snapshot = if version >= 7 { read_u32(read)? } else { 0 };
+    let num_entries = read_u32(read)?;
+    skip(read, extra)?;
+    if page_size == 0 {
+        return Err(RestoreCheck::Header.fail());
+    }
+    Ok(DumpHeader {
+        page_size: page_size as usize,
+        num_pages: num_pages as usize,
+        snapshot,
+        num_entries: num_entries as usize,
+    })
+}
+
+fn read_u32(read: &mut impl FnMut(&mut [u8]) -> Result<(), FsErr>) -> Result<u32, FsErr> {
+    let mut bytes = [0u8; size_of::<u32>()];
+    read(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// Read the footer of a dump or delta whose body is `body_len` bytes long with checksum `crc`,
+/// and check it against the body.
+fn read_footer(
+    read: &mut impl FnMut(&mut [u8]) -> Result<(), FsErr>,
+    body_len: u32,
+    crc: u32,
+) -> Result<(), FsErr> {
+    let mut footer_magic = [0u8; 8];
+    let mut footer_len = [0u8; size_of::<u32>()];
+    let mut footer_crc = [0u8; size_of::<u32>()];
+
+    let footer_start = body_len as usize;
+    read(&mut footer_magic).map_err(|err| err.at(footer_start))?;
+    read(&mut footer_len).map_err(|err| err.at(footer_start + 8))?;
+    read(&mut footer_crc).map_err(|err| err.at(footer_start + 12))?;
+
+    if &footer_magic != b"MEMFSEND" {
+        return Err(RestoreCheck::Footer.fail().at(footer_start));
+    }
+    if u32::from_le_bytes(footer_len) != body_len {
+        return Err(RestoreCheck::Length.fail().at(footer_start + 8));
+    }
+    if u32::from_le_bytes(footer_crc) != crc {
+        return Err(RestoreCheck::Checksum.fail().at(footer_start + 12));
+    }
+    Ok(())
+}
+
+/// Read and discard `len` bytes.
+fn skip(
+    read: &mut impl FnMut(&mut [u8]) -> Result<(), FsErr>,
+    mut len: usize,
+) -> Result<(), FsErr> {
+    let mut buf = [0u8; 64];
+    while len > 0 {
+        let n = len.min(buf.len());
+        read(&mut buf[..n])?;
+        len -= n;
+    }
+    Ok(())
+}
 
 #[derive(Debug)]
 pub enum FsErr {
     ReadOnly,
     WouldFragment,
     TooManyExtents,
+    Fragmented, // File spans multiple extents and cannot be returned as a single slice.
     NoSpace,
     NotFound,
     Duplicate,
     FileNameInvalid(&'static str),
     FileNameSealed,
-    TooManyFiles, // TODO: Depricate when possible. Too many files should not be a limiting factor (only OutOfSpace).
+    NotDirectory,
+    IsDirectory,
+    DirectoryNotEmpty,
+    TooManyFiles, // Entry table is full, see the `MAX_FILES` parameter of `MemoryFs`.
     InvalidOp,
     Corrupt,
+    Restore(RestoreError), // A dump or image failed validation while loading it.
+}
+
+impl core::fmt::Display for FsErr {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FsErr::ReadOnly => f.write_str("file is read-only"),
+            FsErr::WouldFragment => f.write_str("file would have to be fragmented"),
+            FsErr::TooManyExtents => f.write_str("file has too many extents"),
+            FsErr::Fragmented => f.write_str("file is fragmented"),
+            FsErr::NoSpace => f.write_str("no space left in storage"),
+            FsErr::NotFound => f.write_str("file not found"),
+            FsErr::Duplicate => f.write_str("file already exists"),
+            FsErr::FileNameInvalid(reason) => write!(f, "invalid file name: {reason}"),
+            FsErr::FileNameSealed => f.write_str("file name is sealed"),
+            FsErr::NotDirectory => f.write_str("not a directory"),
+            FsErr::IsDirectory => f.write_str("is a directory"),
+            FsErr::DirectoryNotEmpty => f.write_str("directory not empty"),
+            FsErr::TooManyFiles => f.write_str("too many files"),
+            FsErr::InvalidOp => f.write_str("invalid operation"),
+            FsErr::Corrupt => f.write_str("data is corrupt"),
+            FsErr::Restore(err) => write!(f, "restore failed: {err}"),
+        }
+    }
+}
+
+impl core::error::Error for FsErr {}
+
+#[cfg(feature = "std")]
+impl From<FsErr> for std::io::Error {
+    fn from(err: FsErr) -> Self {
+        use std::io::ErrorKind;
+
+        let kind = match err {
+            FsErr::NotFound => ErrorKind::NotFound,
+            FsErr::Duplicate => ErrorKind::AlreadyExists,
+            FsErr::ReadOnly | FsErr::FileNameSealed => ErrorKind::PermissionDenied,
+            FsErr::NoSpace | FsErr::TooManyFiles | FsErr::TooManyExtents | FsErr::WouldFragment => {
+                ErrorKind::StorageFull
+            }
+            FsErr::NotDirectory => ErrorKind::NotADirectory,
+            FsErr::IsDirectory => ErrorKind::IsADirectory,
+            FsErr::DirectoryNotEmpty => ErrorKind::DirectoryNotEmpty,
+            FsErr::FileNameInvalid(_) | FsErr::InvalidOp => ErrorKind::InvalidInput,
+            FsErr::Corrupt | FsErr::Restore(_) => ErrorKind::InvalidData,
+            FsErr::Fragmented => ErrorKind::Unsupported,
+        };
+        std::io::Error::new(kind, err)
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl embedded_io::Error for FsErr {
+    fn kind(&self) -> embedded_io::ErrorKind {
+        use embedded_io::ErrorKind;
+
+        match self {
+            FsErr::NotFound => ErrorKind::NotFound,
+            FsErr::Duplicate => ErrorKind::AlreadyExists,
+            FsErr::ReadOnly | FsErr::FileNameSealed => ErrorKind::PermissionDenied,
+            FsErr::NoSpace | FsErr::TooManyFiles | FsErr::TooManyExtents | FsErr::WouldFragment => {
+                ErrorKind::OutOfMemory
+            }
+            FsErr::FileNameInvalid(_)
+            | FsErr::NotDirectory
+            | FsErr::IsDirectory
+            | FsErr::DirectoryNotEmpty
+            | FsErr::InvalidOp => ErrorKind::InvalidInput,
+            FsErr::Corrupt | FsErr::Restore(_) => ErrorKind::InvalidData,
+            FsErr::Fragmented => ErrorKind::Unsupported,
+        }
+    }
+}
+
+impl FsErr {
+    // Set the offset of a restore error; other errors are returned unchanged.
+    fn at(self, offset: usize) -> Self {
+        match self {
+            FsErr::Restore(err) => FsErr::Restore(RestoreError { offset, ..err }),
+            other => other,
+        }
+    }
+}
+
+/// Which check failed while loading a dump or image, see `RestoreError`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RestoreCheck {
+    /// The data ended early, or reading it failed.
+    Truncated,
+    /// The magic bytes do not match.
+    Magic,
+    /// The format version is not supported.
+    Version,
+    /// A header field is invalid.
+    Header,
+    /// The page size or page count does not match the filesystem.
+    Layout,
+    /// A file name is empty, too long, or not valid UTF-8.
+    Name,
+    /// An entry has an invalid id or kind, or a size beyond its pages.
+    Entry,
+    /// There are more entries than the filesystem can hold.
+    EntryCount,
+    /// An extent lies outside storage or overlaps another one.
+    Extent,
+    /// The directory tree has a missing parent, a cycle, or a duplicate name.
+    Tree,
+    /// The length of the data section does not match the entries.
+    DataLength,
+    /// The compressed data is invalid.
+    Compression,
+    /// The footer magic bytes do not match.
+    Footer,
+    /// The length in the footer does not match the data.
+    Length,
+    /// The checksum does not match.
+    Checksum,
+}
+
+impl RestoreCheck {
+    // Error for this check, `FsErr::at` sets the offset.
+    fn fail(self) -> FsErr {
+        FsErr::Restore(RestoreError {
+            check: self,
+            offset: 0,
+        })
+    }
+}
+
+impl core::fmt::Display for RestoreCheck {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            RestoreCheck::Truncated => "data is truncated",
+            RestoreCheck::Magic => "bad magic",
+            RestoreCheck::Version => "unsupported version",
+            RestoreCheck::Header => "invalid header",
+            RestoreCheck::Layout => "page layout mismatch",
+            RestoreCheck::Name => "invalid file name",
+            RestoreCheck::Entry => "invalid entry",
+            RestoreCheck::EntryCount => "too many entries",
+            RestoreCheck::Extent => "invalid extent",
+            RestoreCheck::Tree => "invalid directory tree",
+            RestoreCheck::DataLength => "data length mismatch",
+            RestoreCheck::Compression => "invalid compressed data",
+            RestoreCheck::Footer => "bad footer magic",
+            RestoreCheck::Length => "length mismatch",
+            RestoreCheck::Checksum => "checksum mismatch",
+        })
+    }
+}
+
+/// Details of `FsErr::Restore`: the check that failed, and where.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RestoreError {
+    check: RestoreCheck,
+    offset: usize,
+}
+
+impl RestoreError {
+    /// The check that failed.
+    pub const fn check(&self) -> RestoreCheck {
+        self.check
+    }
+
+    /// Number of bytes of the dump or image that were read when the check failed. For
+    /// compressed dumps this counts decompressed bytes.
+    pub const fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl core::fmt::Display for RestoreError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} at byte {}", self.check, self.offset)
+    }
 }
 
 bitflags::bitflags! {
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
     #[repr(transparent)]
     pub struct FileFlags: u32{
         const IMMUTABLE=1<<0; // reject write/append/delete
-        const DO_NOT_FRAGMENT=1<<1; // append must stay contiguous or fail
-        const CHECKSUMMED=1<<2; // verify on read (unimplemented)
+        const DO_NOT_FRAGMENT=1<<1; // file must stay a single contiguous extent
+        const CHECKSUMMED=1<<2; // verify on read; reads and non-append writes hash the whole file
         const APPEND_ONLY=1<<3; // no write_at
         const SEALED_NAMES=1<<4; // no rename allowed
     }
@@ -40,84 +382,991 @@ bitflags::bitflags! {
 
 #[derive(Copy, Clone)]
 struct Extent {
-    // TODO: Consider u16 / u32 for start_page and len_page.
-    start_page: usize,
-    len_pages: usize,
+    // Page numbers are `u32`, as in dumps, to keep `FileEntry` small.
+    start: u32,
+    len: u32,
 }
 
-pub struct FileEntry {
-    pub name: String<MAX_FILE_NAME_LENGTH>,
+impl Extent {
+    fn new(start_page: usize, len_pages: usize) -> Self {
+        Self {
+            start: start_page as u32,
+            len: len_pages as u32,
+        }
+    }
+
+    fn start_page(&self) -> usize {
+        self.start as usize
+    }
+
+    fn len_pages(&self) -> usize {
+        self.len as usize
+    }
+
+    fn set_start_page(&mut self, page: usize) {
+        self.start = page as u32;
+    }
+
+    fn set_len_pages(&mut self, pages: usize) {
+        self.len = pages as u32;
+    }
+}
+
+type Extents = Vec<Extent, MAX_EXTENTS_PER_FILE>;
+
+/// How a file may obtain additional pages when it needs to grow.
+#[derive(Copy, Clone, PartialEq)]
+enum Growth {
+    /// Only grow into the free pages directly after the last extent.
+    InPlace,
+    /// Grow in place, or move the whole file to a new contiguous extent.
+    Repack,
+    /// Grow in place or repack, and only add new extents when no contiguous run is free.
+    Fragment,
+}
+
+#[derive(Clone)]
+pub struct FileEntry<const MAX_NAME_LEN: usize = DEFAULT_MAX_NAME_LEN> {
+    pub name: String<MAX_NAME_LEN>, // Name within the parent directory.
     pub size: usize,
     flags: FileFlags,
-    extent: Option<Extent>,
+    extents: Extents, // In logical order, empty for files without allocation.
+    crc: u32,         // Only maintained for CHECKSUMMED files.
+    id: u32,
+    parent: u32,
+    is_dir: bool,
+}
+
+impl<const MAX_NAME_LEN: usize> FileEntry<MAX_NAME_LEN> {
+    const fn serialized_max_size() -> usize {
+        29 + MAX_NAME_LEN + 8 * MAX_EXTENTS_PER_FILE
+    }
+
+    // Encoded size in the dump format, without the length prefix of version 8.
+    fn serialized_size(&self) -> usize {
+        25 + self.name.len() + 8 * self.extents.len()
+    }
+
+    /// Whether this entry is a directory.
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+
+    /// Number of extents backing this file.
+    ///
+    /// Files without allocated pages have `0` extents; contiguous files have `1`.
+    pub fn extent_count(&self) -> usize {
+        self.extents.len()
+    }
+
+    fn capacity_pages(&self) -> usize {
+        self.extents.iter().map(|ext| ext.len_pages()).sum()
+    }
+
+    fn max_extents(&self) -> usize {
+        if self.flags.contains(FileFlags::DO_NOT_FRAGMENT) {
+            1
+        } else {
+            MAX_EXTENTS_PER_FILE
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! memfs {
+    () => {{
+        static mut STORAGE: [u8; $crate::DEFAULT_STORAGE_SIZE] = [0; $crate::DEFAULT_STORAGE_SIZE];
+        unsafe { $crate::MemFs::from_backed(&mut *core::ptr::addr_of_mut!(STORAGE)) }
+    }};
+}
+
+pub type MemFs = MemoryFs<'static, DEFAULT_STORAGE_SIZE, DEFAULT_PAGE_SIZE>;
+
+/// A filesystem over a borrowed `STORAGE_SIZE` byte buffer, split into `PAGE_SIZE` byte pages.
+///
+/// `MAX_FILES` is the capacity of the entry table. Every file and directory takes one entry,
+/// so size it for the number of files you need; the table lives outside `storage`.
+/// `MAX_NAME_LEN` is the longest name (a single path component) in bytes, up to 255. Every
+/// entry reserves this much space, so lowering it shrinks the entry table considerably.
+/// `BITMAP_WORDS` is the capacity of the page bitmap, in words of 32 pages. The default covers
+/// 8192 pages; for larger storage use `bitmap_words(STORAGE_SIZE, PAGE_SIZE)`. A bitmap that
+/// is too small is a compile-time error. A second bitmap of this size tracks the pages changed
+/// since the last `checkpoint`, for `dump_delta` and transactions.
+/// `A` decides where new pages are placed, see `Allocator`.
+/// `S` is the type of the backing storage. Keep the default; `MemoryFsRo` uses an immutable
+/// one, for which only methods that never write to storage exist.
+///
+/// With `STORAGE_SIZE` and `PAGE_SIZE` set to `0` the sizes are chosen at runtime instead,
+/// see `DynMemoryFs`.
+pub struct MemoryFs<
+    'a,
+    const STORAGE_SIZE: usize,
+    const PAGE_SIZE: usize,
+    const MAX_FILES: usize = DEFAULT_MAX_FILES,
+    const MAX_NAME_LEN: usize = DEFAULT_MAX_NAME_LEN,
+    const BITMAP_WORDS: usize = DEFAULT_BITMAP_WORDS,
+    A: Allocator = FirstFit,
+    S = Storage<'a>,
+> {
+    entries: Vec<FileEntry<MAX_NAME_LEN>, MAX_FILES>, // Sorted by id, see `find_id`.
+    name_index: Vec<usize, MAX_FILES>,                // Entry indices sorted by (parent, name).
+    storage: S,
+    page_size: usize,
+    image: Option<&'a mut [u8]>, // Superblock region of an image, see `sync`.
+    page_bitmap: heapless::Vec<u32, BITMAP_WORDS>,
+    // Pages written since the last checkpoint. While a transaction runs: pages allocated or
+    // written since it started, all other pages in use are protected.
+    dirty_pages: heapless::Vec<u32, BITMAP_WORDS>,
+    in_transaction: bool,
+    snapshot: u32, // Id of the current state, see `checkpoint`.
+    next_id: u32,
+    allocator: A,
+}
+
+/// A `MemoryFs` over a buffer whose size, and page size, are only known at runtime.
+///
+/// The page bitmap is still sized at compile time through `BITMAP_WORDS`, which limits the
+/// number of pages to `BITMAP_WORDS * 32`.
+pub type DynMemoryFs<
+    'a,
+    const MAX_FILES: usize = DEFAULT_MAX_FILES,
+    const MAX_NAME_LEN: usize = DEFAULT_MAX_NAME_LEN,
+    const BITMAP_WORDS: usize = DEFAULT_BITMAP_WORDS,
+    A = FirstFit,
+> = MemoryFs<'a, 0, 0, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>;
+
+impl<'a, const MAX_FILES: usize, const MAX_NAME_LEN: usize, const BITMAP_WORDS: usize, A: Allocator>
+    DynMemoryFs<'a, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>
+{
+    /// Create a filesystem over `storage`, split into `page_size` byte pages.
+    ///
+    /// Bytes after the last whole page are left unused.
+    ///
+    /// # Errors
+    /// - `FsErr::InvalidOp` if `page_size` is `0` or `storage` has more pages than the page
+    ///   bitmap can track
+    pub fn from_slice(storage: &'a mut [u8], page_size: usize) -> Result<Self, FsErr>
+    where
+        A: Default,
+    {
+        Self::from_slice_with_allocator(storage, page_size, A::default())
+    }
+
+    /// Like `from_slice`, but place new pages with `allocator`.
+    pub fn from_slice_with_allocator(
+        storage: &'a mut [u8],
+        page_size: usize,
+        allocator: A,
+    ) -> Result<Self, FsErr> {
+        if page_size == 0 || bitmap_words(storage.len(), page_size) > BITMAP_WORDS {
+            return Err(FsErr::InvalidOp);
+        }
+        let len = storage.len() / page_size * page_size;
+        Ok(Self::new(
+            Storage::Borrowed(&mut storage[..len]),
+            page_size,
+            allocator,
+        ))
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<const MAX_FILES: usize, const MAX_NAME_LEN: usize, const BITMAP_WORDS: usize, A: Allocator>
+    DynMemoryFs<'static, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>
+{
+    /// Create a filesystem that owns `bytes` of zeroed storage, split into
+    /// `DEFAULT_PAGE_SIZE` byte pages. Only available with the `alloc` feature.
+    ///
+    /// The buffer is freed when the filesystem is dropped. `bytes` is rounded down to whole
+    /// pages.
+    ///
+    /// # Errors
+    /// - `FsErr::InvalidOp` if the storage has more pages than the page bitmap can track
+    pub fn with_capacity(bytes: usize) -> Result<Self, FsErr>
+    where
+        A: Default,
+    {
+        Self::with_capacity_and_page_size(bytes, DEFAULT_PAGE_SIZE)
+    }
+
+    /// Like `with_capacity`, but split storage into `page_size` byte pages.
+    ///
+    /// # Errors
+    /// - `FsErr::InvalidOp` if `page_size` is `0` or the storage has more pages than the page
+    ///   bitmap can track
+    pub fn with_capacity_and_page_size(bytes: usize, page_size: usize) -> Result<Self, FsErr>
+    where
+        A: Default,
+    {
+        if page_size == 0 || bitmap_words(bytes, page_size) > BITMAP_WORDS {
+            return Err(FsErr::InvalidOp);
+        }
+        let storage = alloc::vec![0u8; bytes / page_size * page_size].into_boxed_slice();
+        Ok(Self::new(Storage::Owned(storage), page_size, A::default()))
+    }
 }
 
-impl FileEntry {
-    const fn serialized_max_size() -> usize {
-        18 + MAX_FILE_NAME_LENGTH
+// Everything that works on immutable storage, shared with `MemoryFsRo`.
+impl<
+    'a,
+    const STORAGE_SIZE: usize,
+    const PAGE_SIZE: usize,
+    const MAX_FILES: usize,
+    const MAX_NAME_LEN: usize,
+    const BITMAP_WORDS: usize,
+    A: Allocator,
+    S: Deref<Target = [u8]>,
+> MemoryFs<'a, STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A, S>
+{
+    // Page size, a compile-time constant unless the storage size is chosen at runtime.
+    fn page_size(&self) -> usize {
+        if PAGE_SIZE == 0 {
+            self.page_size
+        } else {
+            PAGE_SIZE
+        }
+    }
+
+    fn num_pages(&self) -> usize {
+        self.storage.len() / self.page_size()
+    }
+
+    // `storage` must be a whole number of pages that fits in the page bitmap.
+    fn new(storage: S, page_size: usize, allocator: A) -> Self {
+        const {
+            assert!(
+                MAX_NAME_LEN > 0 && MAX_NAME_LEN <= MAX_FILE_NAME_LENGTH,
+                "MAX_NAME_LEN must be between 1 and 255"
+            );
+        }
+
+        let mut page_bitmap = heapless::Vec::new();
+        page_bitmap
+            .resize(bitmap_words(storage.len(), page_size), 0)
+            .ok();
+
+        Self {
+            entries: Vec::new(),
+            name_index: Vec::new(),
+            storage,
+            page_size,
+            image: None,
+            dirty_pages: page_bitmap.clone(),
+            page_bitmap,
+            in_transaction: false,
+            snapshot: 0,
+            next_id: ROOT_ID + 1,
+            allocator,
+        }
+    }
+
+    /// Read the contents of a file.
+    ///
+    /// Returns a slice into the internal storage backing the filesystem. Only files stored
+    /// in a single extent can be returned this way; use `read_into` for fragmented files.
+    ///
+    /// Empty files return an empty slice (`&[]`).
+    ///
+    /// # Returns
+    /// - `Ok(&[u8])` if the file exists
+    /// - `Err(FsErr::NotFound)` if the file does not exist
+    /// - `Err(FsErr::Fragmented)` if the file spans multiple extents
+    /// - `Err(FsErr::Corrupt)` if the file has `CHECKSUMMED` and its contents fail verification
+    pub fn read(&self, name: &str) -> Result<&[u8], FsErr> {
+        let index = self.find_file_index(name)?;
+        self.verify_checksum(index)?;
+
+        let entry = &self.entries[index];
+        let mut spans = Self::spans(&entry.extents, self.page_size(), 0, entry.size);
+        match (spans.next(), spans.next()) {
+            (None, _) => Ok(&[]),
+            (Some(range), None) => Ok(&self.storage[range]),
+            _ => Err(FsErr::Fragmented),
+        }
+    }
+
+    /// Copy the contents of a file into `buf`.
+    ///
+    /// Works for both contiguous and fragmented files. At most `buf.len()` bytes are copied;
+    /// compare the returned count with the file size to detect a short buffer.
+    ///
+    /// # Returns
+    /// - `Ok(n)` with the number of bytes copied if the file exists
+    /// - `Err(FsErr::NotFound)` if the file does not exist
+    /// - `Err(FsErr::Corrupt)` if the file has `CHECKSUMMED` and its contents fail verification
+    pub fn read_into(&self, name: &str, buf: &mut [u8]) -> Result<usize, FsErr> {
+        let index = self.find_file_index(name)?;
+        self.verify_checksum(index)?;
+
+        let len = self.entries[index].size.min(buf.len());
+        self.copy_out(index, 0, &mut buf[..len]);
+        Ok(len)
+    }
+
+    /// Read a portion of a file starting at `offset`.
+    ///
+    /// Returns a slice into the internal storage backing the filesystem.
+    ///
+    /// If `offset` is at or past the end of the file, an empty slice (`&[]`)
+    /// is returned. The returned slice is clamped to the file's logical size.
+    ///
+    /// Being zero-copy, the range has to lie within a single extent of the file; use
+    /// `read_at_into` to read across extent boundaries of fragmented files.
+    ///
+    /// # Returns
+    /// - `Ok(&[u8])` containing up to `len` bytes if the file exists
+    /// - `Ok(&[])` if `offset` is at or past the end of the file
+    /// - `Err(FsErr::NotFound)` if the file does not exist
+    /// - `Err(FsErr::Fragmented)` if the range crosses an extent boundary
+    /// - `Err(FsErr::Corrupt)` if internal metadata is inconsistent, or if the file has
+    ///   `CHECKSUMMED` and its contents fail verification
+    pub fn read_at(&self, name: &str, offset: usize, len: usize) -> Result<&[u8], FsErr> {
+        let index = self.find_file_index(name)?;
+        self.verify_checksum(index)?;
+        let entry = &self.entries[index];
+
+        // Offset outside of file
+        if offset >= entry.size {
+            return Ok(&[]);
+        }
+        let len = len.min(entry.size - offset);
+
+        // No bytes to read.
+        if len == 0 {
+            return Ok(&[]);
+        }
+
+        // Read bytes with sanity checks.
+        let capacity = entry.capacity_pages() * self.page_size();
+        if offset.checked_add(len).ok_or(FsErr::Corrupt)? > capacity {
+            return Err(FsErr::Corrupt);
+        }
+        let range = Self::spans(&entry.extents, self.page_size(), offset, len)
+            .next()
+            .ok_or(FsErr::Corrupt)?;
+        if range.end > self.storage.len() {
+            return Err(FsErr::Corrupt);
+        }
+        if range.len() < len {
+            return Err(FsErr::Fragmented);
+        }
+        Ok(&self.storage[range])
+    }
+
+    /// Copy a portion of a file starting at `offset` into `buf`.
+    ///
+    /// Like `read_at`, but works across extent boundaries of fragmented files. At most
+    /// `buf.len()` bytes are copied, clamped to the file's logical size.
+    ///
+    /// # Returns
+    /// - `Ok(n)` with the number of bytes copied if the file exists
+    /// - `Ok(0)` if `offset` is at or past the end of the file
+    /// - `Err(FsErr::NotFound)` if the file does not exist
+    /// - `Err(FsErr::Corrupt)` if the file has `CHECKSUMMED` and its contents fail verification
+    pub fn read_at_into(&self, name: &str, offset: usize, buf: &mut [u8]) -> Result<usize, FsErr> {
+        let index = self.find_file_index(name)?;
+        self.verify_checksum(index)?;
+
+        let len = self.entries[index]
+            .size
+            .saturating_sub(offset)
+            .min(buf.len());
+        self.copy_out(index, offset, &mut buf[..len]);
+        Ok(len)
+    }
+
+    /// Check whether a file or directory exists.
+    ///
+    /// This checks for the presence of an entry at the given path. The root directory
+    /// (`""` or `"/"`) always exists.
+    pub fn exists(&self, name: &str) -> bool {
+        self.resolve(name).is_ok()
+    }
+
+    /// Iterate over all file entries.
+    ///
+    /// The iterator yields metadata only (name, size, flags, extents) for files and
+    /// directories at any depth. File contents can be accessed via `read()` or `read_into()`.
+    pub fn entries(&self) -> impl Iterator<Item = &FileEntry<MAX_NAME_LEN>> {
+        self.entries.iter()
+    }
+
+    /// Read the entry table of a `version` dump with `header`.
+    ///
+    /// With `in_place` the storage is attached by the caller afterwards: pages are not claimed,
+    /// and the extents of a version 6 or later dump are renumbered to follow each other as they
+    /// do in its data section. The same happens when the dump has a different page layout, so
+    /// `relayout_data` can place the files.
+    fn restore_metadata(
+        &mut self,
+        version: u8,
+        header: &DumpHeader,
+        read: &mut impl FnMut(&mut [u8]) -> Result<(), FsErr>,
+        in_place: bool,
+    ) -> Result<(), FsErr> {
+        if !self.entries.is_empty() {
+            return Err(FsErr::InvalidOp);
+        }
+
+        let relayout = !in_place && !self.same_layout(header);
+        let renumber = (in_place || relayout) && version >= 6;
+        self.restore_entries(
+            version,
+            header.num_entries,
+            header.page_size,
+            renumber,
+            read,
+        )?;
+        if !in_place && !relayout {
+            self.claim_pages()?;
+        }
+        self.snapshot = header.snapshot;
+        self.clear_dirty_pages();
+        Ok(())
+    }
+
+    // Whether a dump with `header` has the page size and page count of this filesystem.
+    fn same_layout(&self, header: &DumpHeader) -> bool {
+        header.page_size == self.page_size() && header.num_pages == self.num_pages()
+    }
+
+    /// Read `num_entries` entries of a `version` dump with `page_size` byte pages and check
+    /// the directory tree. Their pages are claimed separately by `claim_pages`.
+    ///
+    /// With `renumber` the extents are placed one after another from page 0 instead of at their
+    /// stored pages.
+    fn restore_entries(
+        &mut self,
+        version: u8,
+        num_entries: usize,
+        page_size: usize,
+        renumber: bool,
+        read: &mut impl FnMut(&mut [u8]) -> Result<(), FsErr>,
+    ) -> Result<(), FsErr> {
+        let mut next_page = 0u32;
+        for _ in 0..num_entries {
+            let mut entry_len = None;
+            if version >= 8 {
+                let mut len = [0u8; size_of::<u16>()];
+                read(&mut len)?;
+                entry_len = Some(u16::from_le_bytes(len) as usize);
+            }
+            let consumed = Cell::new(0);
+            let mut read = |buf: &mut [u8]| {
+                consumed.set(consumed.get() + buf.len());
+                read(buf)
+            };
+
+            let mut name_len = [0u8; size_of::<u16>()];
+            let mut name_bytes = [0u8; MAX_FILE_NAME_LENGTH];
+
+            read(&mut name_len)?;
+            let name_len = u16::from_le_bytes(name_len) as usize;
+            if name_len == 0 || name_len > MAX_FILE_NAME_LENGTH {
+                return Err(RestoreCheck::Name.fail());
+            }
+            read(&mut name_bytes[..name_len])?;
+
+            let name =
+                str::from_utf8(&name_bytes[..name_len]).map_err(|_| RestoreCheck::Name.fail())?;
+
+            let (id, parent, name, is_dir) = if version >= 5 {
+                let mut id = [0u8; size_of::<u32>()];
+                let mut parent = [0u8; size_of::<u32>()];
+                let mut kind = [0u8; 1];
+                read(&mut id)?;
+                read(&mut parent)?;
+                read(&mut kind)?;
+
+                let id = u32::from_le_bytes(id);
+                if id == ROOT_ID || id == u32::MAX || kind[0] > 1 || name.contains('/') {
+                    return Err(RestoreCheck::Entry.fail());
+                }
+                self.next_id = self.next_id.max(id + 1);
+                (id, u32::from_le_bytes(parent), name, kind[0] == 1)
+            } else {
+                let (dir, name) = name.rsplit_once('/').unwrap_or(("", name));
+                let parent = self.restore_legacy_dirs(dir)?;
+                self.next_id += 1;
+                (self.next_id - 1, parent, name, false)
+            };
+
+            let mut file_size = [0u8; size_of::<u32>()];
+            let mut file_flags = [0u8; size_of::<u32>()];
+            let mut file_crc = [0u8; size_of::<u32>()];
+
+            read(&mut file_size)?;
+            read(&mut file_flags)?;
+
+            let file_size = u32::from_le_bytes(file_size) as usize;
+            let file_flags = u32::from_le_bytes(file_flags);
+
+            // Legacy formats store exactly one extent, with a zero length meaning "none".
+            let extent_count = if version >= 4 {
+                read(&mut file_crc)?;
+
+                let mut extent_count = [0u8; size_of::<u16>()];
+                read(&mut extent_count)?;
+                let extent_count = u16::from_le_bytes(extent_count) as usize;
+                if extent_count > MAX_EXTENTS_PER_FILE {
+                    return Err(RestoreCheck::Extent.fail());
+                }
+                extent_count
+            } else {
+                1
+            };
+
+            let mut extents = Extents::new();
+            for _ in 0..extent_count {
+                let mut extent_start = [0u8; size_of::<u32>()];
+                let mut extent_len = [0u8; size_of::<u32>()];
+                read(&mut extent_start)?;
+                read(&mut extent_len)?;
+
+                let extent = Extent {
+                    start: u32::from_le_bytes(extent_start),
+                    len: u32::from_le_bytes(extent_len),
+                };
+                if extent.len_pages() > 0 {
+                    extents
+                        .push(extent)
+                        .map_err(|_| RestoreCheck::Extent.fail())?;
+                } else if version >= 4 {
+                    return Err(RestoreCheck::Extent.fail());
+                }
+            }
+
+            if version == 3 {
+                read(&mut file_crc)?;
+            }
+            let file_crc = u32::from_le_bytes(file_crc);
+
+            // Skip fields appended by newer minor versions.
+            if let Some(entry_len) = entry_len {
+                let extra = entry_len
+                    .checked_sub(consumed.get())
+                    .ok_or(RestoreCheck::Entry.fail())?;
+                skip(&mut read, extra)?;
+            }
+
+            // Sanity checks
+            let mut cap = 0usize;
+            for extent in &mut extents {
+                if renumber {
+                    extent.start = next_page;
+                    next_page = next_page
+                        .checked_add(extent.len)
+                        .ok_or(RestoreCheck::Extent.fail())?;
+                }
+                cap = extent
+                    .len_pages()
+                    .checked_mul(page_size)
+                    .and_then(|len| cap.checked_add(len))
+                    .ok_or(RestoreCheck::Extent.fail())?;
+            }
+            if file_size > cap || (is_dir && cap > 0) {
+                return Err(RestoreCheck::Entry.fail());
+            }
+
+            self.push_entry(FileEntry {
+                name: heapless::String::from_str(name).map_err(|_| RestoreCheck::Name.fail())?,
+                size: file_size,
+                flags: FileFlags::from_bits_truncate(file_flags),
+                extents,
+                crc: file_crc,
+                id,
+                parent,
+                is_dir,
+            })
+            .map_err(|_| RestoreCheck::EntryCount.fail())?;
+        }
+
+        self.validate_tree()
+    }
+
+    /// Check that the extents of restored entries lie within storage and do not overlap, and
+    /// mark their pages in use.
+    fn claim_pages(&mut self) -> Result<(), FsErr> {
+        for index in 0..self.entries.len() {
+            for extent in self.entries[index].extents.clone() {
+                let end = extent
+                    .start_page()
+                    .checked_add(extent.len_pages())
+                    .ok_or(RestoreCheck::Extent.fail())?;
+                if end > self.num_pages()
+                    || self
+                        .check_neighbour_pages_free(extent.start_page(), extent.len_pages())
+                        .is_none()
+                {
+                    return Err(RestoreCheck::Extent.fail());
+                }
+                self.mark_pages(extent.start_page(), extent.len_pages(), true);
+            }
+        }
+        Ok(())
+    }
+
+    /// Create the directories of a legacy flat name such as `textures/ui` while restoring.
+    fn restore_legacy_dirs(&mut self, path: &str) -> Result<u32, FsErr> {
+        let mut parent = ROOT_ID;
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            parent = match self.find_child(parent, component) {
+                Some(index) if self.entries[index].is_dir => self.entries[index].id,
+                Some(_) => return Err(RestoreCheck::Tree.fail()),
+                None => {
+                    self.push_entry(FileEntry {
+                        name: String::from_str(component).map_err(|_| RestoreCheck::Name.fail())?,
+                        size: 0,
+                        flags: FileFlags::empty(),
+                        extents: Extents::new(),
+                        crc: 0,
+                        id: self.next_id,
+                        parent,
+                        is_dir: true,
+                    })
+                    .map_err(|_| RestoreCheck::EntryCount.fail())?;
+                    self.next_id += 1;
+                    self.next_id - 1
+                }
+            };
+        }
+        Ok(parent)
+    }
+
+    /// Check that restored entries form a proper tree with unique, valid names.
+    fn validate_tree(&self) -> Result<(), FsErr> {
+        for (index, entry) in self.entries.iter().enumerate() {
+            if entry.name.is_empty()
+                || entry.name.contains(' ')
+                || entry.name == "."
+                || entry.name == ".."
+            {
+                return Err(RestoreCheck::Name.fail());
+            }
+            if entry.parent != ROOT_ID {
+                match self.find_id(entry.parent) {
+                    Some(parent) if self.entries[parent].is_dir => {}
+                    _ => return Err(RestoreCheck::Tree.fail()),
+                }
+            }
+            // Every entry must lead back to the root without cycles.
+            if !self.is_within(entry.parent, ROOT_ID) {
+                return Err(RestoreCheck::Tree.fail());
+            }
+            // Ids must be strictly increasing, which also makes them unique.
+            if index > 0 && self.entries[index - 1].id >= entry.id {
+                return Err(RestoreCheck::Tree.fail());
+            }
+        }
+        // Entries with the same parent and name are next to each other in the name index.
+        let clash = self.name_index.windows(2).any(|pair| {
+            let (a, b) = (&self.entries[pair[0]], &self.entries[pair[1]]);
+            a.parent == b.parent && a.name == b.name
+        });
+        if clash {
+            return Err(RestoreCheck::Tree.fail());
+        }
+        Ok(())
+    }
+
+    // Page allocator functions
+    fn pages(&self) -> PageMap<'_> {
+        PageMap::new(&self.page_bitmap, self.num_pages())
+    }
+    // Bit masks covering pages `start..start + len`, one per bitmap word.
+    fn page_masks(start: usize, len: usize) -> impl Iterator<Item = (usize, u32)> {
+        let end = start + len;
+        (start / 32..end.div_ceil(32)).map(move |index| {
+            let lo = start.max(index * 32) - index * 32;
+            let hi = end.min(index * 32 + 32) - index * 32;
+            (
+                index,
+                u32::MAX.checked_shr((32 - (hi - lo)) as u32).unwrap_or(0) << lo,
+            )
+        })
+    }
+
+    fn mark_pages(&mut self, start: usize, len: usize, used: bool) {
+        // Reusing pages the superblock still assigns to a file makes it invalid.
+        if used && self.superblock_uses_pages(start, len) {
+            self.invalidate_superblock();
+        }
+        for (index, mask) in Self::page_masks(start, len) {
+            if used {
+                if self.in_transaction {
+                    // Pages allocated during the transaction are not protected.
+                    self.dirty_pages[index] |= mask & !self.page_bitmap[index];
+                }
+                self.page_bitmap[index] |= mask;
+            } else if self.in_transaction {
+                // Pages in use when the running transaction started stay in use until it commits.
+                self.page_bitmap[index] &= !(mask & self.dirty_pages[index]);
+            } else {
+                self.page_bitmap[index] &= !mask;
+            }
+        }
+    }
+
+    fn clear_dirty_pages(&mut self) {
+        self.dirty_pages.iter_mut().for_each(|word| *word = 0);
+    }
+
+    /// Drop all entries, for example after a failed restore. Storage is left as it is.
+    fn clear_metadata(&mut self) {
+        self.entries.clear();
+        self.name_index.clear();
+        self.page_bitmap.iter_mut().for_each(|word| *word = 0);
+        self.clear_dirty_pages();
+        self.snapshot = 0;
+        self.next_id = ROOT_ID + 1;
+    }
+
+    fn find_file_index(&self, name: &str) -> Result<usize, FsErr> {
+        match self.resolve(name)? {
+            Some(index) if !self.entries[index].is_dir => Ok(index),
+            _ => Err(FsErr::IsDirectory),
+        }
+    }
+
+    fn find_dir_id(&self, name: &str) -> Result<u32, FsErr> {
+        match self.resolve(name)? {
+            None => Ok(ROOT_ID),
+            Some(index) if self.entries[index].is_dir => Ok(self.entries[index].id),
+            Some(_) => Err(FsErr::NotDirectory),
+        }
+    }
+
+    fn find_child(&self, parent: u32, name: &str) -> Option<usize> {
+        let pos = self.name_index_search(parent, name).ok()?;
+        Some(self.name_index[pos])
+    }
+
+    // Binary search `name_index` for (parent, name). `Err` holds the insert position.
+    fn name_index_search(&self, parent: u32, name: &str) -> Result<usize, usize> {
+        self.name_index.binary_search_by(|&index| {
+            let entry = &self.entries[index];
+            (entry.parent, entry.name.as_str()).cmp(&(parent, name))
+        })
+    }
+
+    /// Add an entry to the table and the name index, returning its index.
+    fn push_entry(&mut self, entry: FileEntry<MAX_NAME_LEN>) -> Result<usize, FsErr> {
+        let (Ok(pos) | Err(pos)) = self.name_index_search(entry.parent, &entry.name);
+        self.entries.push(entry).map_err(|_| FsErr::TooManyFiles)?;
+
+        let index = self.entries.len() - 1;
+        // Both have capacity MAX_FILES, so this cannot fail.
+        self.name_index.insert(pos, index).ok();
+        Ok(index)
+    }
+
+    /// Resolve a path to an entry index, or `None` for the root directory.
+    ///
+    /// Empty components and `.` are skipped, `..` moves to the parent directory
+    /// (the root is its own parent).
+    fn resolve(&self, path: &str) -> Result<Option<usize>, FsErr> {
+        let mut current: Option<usize> = None;
+
+        for component in path.split('/') {
+            if component.is_empty() || component == "." {
+                continue;
+            }
+            if let Some(index) = current
+                && !self.entries[index].is_dir
+            {
+                return Err(FsErr::NotDirectory);
+            }
+
+            current = if component == ".." {
+                current.and_then(|index| self.find_id(self.entries[index].parent))
+            } else {
+                let dir = current.map_or(ROOT_ID, |index| self.entries[index].id);
+                Some(self.find_child(dir, component).ok_or(FsErr::NotFound)?)
+            };
+        }
+        Ok(current)
+    }
+
+    /// Split a path into the id of its (existing) parent directory and its final component.
+    fn split_path<'p>(&self, path: &'p str) -> Result<(u32, &'p str), FsErr> {
+        let path = path.trim_end_matches('/');
+        let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+        Ok((self.find_dir_id(dir)?, name))
+    }
+
+    /// Storage byte ranges backing `len` bytes of a file, starting at logical `offset`.
+    ///
+    /// The caller must make sure `offset + len` does not exceed the capacity of `extents`.
+    fn spans(
+        extents: &[Extent],
+        page_size: usize,
+        offset: usize,
+        len: usize,
+    ) -> impl Iterator<Item = Range<usize>> {
+        let mut skip = offset;
+        let mut remaining = len;
+        extents.iter().filter_map(move |extent| {
+            let capacity = extent.len_pages() * page_size;
+            if remaining == 0 {
+                return None;
+            }
+            if skip >= capacity {
+                skip -= capacity;
+                return None;
+            }
+
+            let start = extent.start_page() * page_size + skip;
+            let len = (capacity - skip).min(remaining);
+            skip = 0;
+            remaining -= len;
+            Some(start..start + len)
+        })
+    }
+
+    /// Copy `buf.len()` bytes of file `index` starting at logical `offset` into `buf`.
+    fn copy_out(&self, index: usize, offset: usize, buf: &mut [u8]) {
+        let mut copied = 0;
+        for range in Self::spans(
+            &self.entries[index].extents,
+            self.page_size(),
+            offset,
+            buf.len(),
+        ) {
+            let len = range.len();
+            buf[copied..copied + len].copy_from_slice(&self.storage[range]);
+            copied += len;
+        }
     }
-}
 
-#[macro_export]
-macro_rules! memfs {
-    () => {{
-        static mut STORAGE: [u8; $crate::DEFAULT_STORAGE_SIZE] = [0; $crate::DEFAULT_STORAGE_SIZE];
-        unsafe { $crate::MemFs::from_backed(&mut *core::ptr::addr_of_mut!(STORAGE)) }
-    }};
-}
+    fn file_checksum(&self, index: usize) -> u32 {
+        let entry = &self.entries[index];
+        let mut digest = CHECKSUM.digest();
+        for range in Self::spans(&entry.extents, self.page_size(), 0, entry.size) {
+            digest.update(&self.storage[range]);
+        }
+        digest.finalize()
+    }
 
-pub type MemFs = MemoryFs<'static, DEFAULT_STORAGE_SIZE, DEFAULT_PAGE_SIZE>;
-pub struct MemoryFs<'a, const STORAGE_SIZE: usize, const PAGE_SIZE: usize> {
-    entries: Vec<FileEntry, MAX_NUM_FILES>,
-    storage: &'a mut [u8; STORAGE_SIZE],
-    page_bitmap: heapless::Vec<u32, MAX_PAGE_BITMAP_WORDS>,
-}
+    /// Recompute the stored checksum of a `CHECKSUMMED` file after its contents changed.
+    fn update_checksum(&mut self, index: usize) {
+        if self.entries[index].flags.contains(FileFlags::CHECKSUMMED) {
+            self.entries[index].crc = self.file_checksum(index);
+        }
+    }
 
-impl<'a, const STORAGE_SIZE: usize, const PAGE_SIZE: usize> MemoryFs<'a, STORAGE_SIZE, PAGE_SIZE> {
-    const fn num_pages() -> usize {
-        STORAGE_SIZE / PAGE_SIZE
+    /// Continue the stored checksum of a `CHECKSUMMED` file over bytes appended at `old_size`,
+    /// so appending costs only the new bytes instead of a pass over the whole file.
+    fn extend_checksum(&mut self, index: usize, old_size: usize) {
+        let entry = &self.entries[index];
+        if !entry.flags.contains(FileFlags::CHECKSUMMED) {
+            return;
+        }
+        // `finalize` applies the output xor; undo it to resume from the raw register.
+        let mut digest = CHECKSUM.digest_with_initial(entry.crc ^ CRC_32_CKSUM.xorout);
+        for range in Self::spans(
+            &entry.extents,
+            self.page_size(),
+            old_size,
+            entry.size - old_size,
+        ) {
+            digest.update(&self.storage[range]);
+        }
+        self.entries[index].crc = digest.finalize();
     }
 
-    const fn bitmap_words() -> usize {
-        Self::num_pages().div_ceil(32)
+    fn verify_checksum(&self, index: usize) -> Result<(), FsErr> {
+        let entry = &self.entries[index];
+        if entry.flags.contains(FileFlags::CHECKSUMMED) && self.file_checksum(index) != entry.crc {
+            return Err(FsErr::Corrupt);
+        }
+        Ok(())
     }
 
-    pub fn from_backed(storage: &'a mut [u8; STORAGE_SIZE]) -> Self {
-        assert!(PAGE_SIZE > 0);
-        assert!(STORAGE_SIZE.is_multiple_of(PAGE_SIZE));
+    // Check if the next `need_pages` pages are free.
+    fn check_neighbour_pages_free(&self, start: usize, need_pages: usize) -> Option<Extent> {
+        assert!(start <= self.num_pages());
+        assert_ne!(need_pages, 0);
 
-        let mut page_bitmap: heapless::Vec<u32, MAX_PAGE_BITMAP_WORDS> = heapless::Vec::new();
-        let words = Self::bitmap_words();
+        (self.pages().free_run_len(start, need_pages) == need_pages)
+            .then_some(Extent::new(start, need_pages))
+    }
 
-        assert!(words <= MAX_PAGE_BITMAP_WORDS);
+    // Entries are only ever appended with a fresh id and removed in place, so the table
+    // stays sorted by id. Restores check this in `validate_tree`.
+    fn find_id(&self, id: u32) -> Option<usize> {
+        self.entries.binary_search_by_key(&id, |f| f.id).ok()
+    }
 
-        for _ in 0..words {
-            page_bitmap.push(0).ok();
+    /// Check whether directory `dir` is `ancestor` or lies somewhere below it.
+    fn is_within(&self, dir: u32, ancestor: u32) -> bool {
+        let mut current = dir;
+        // Bounded walk, a well-formed tree is never deeper than the number of entries.
+        for _ in 0..=self.entries.len() {
+            if current == ancestor {
+                return true;
+            }
+            match self.find_id(current) {
+                Some(index) => current = self.entries[index].parent,
+                None => return false,
+            }
         }
+        false
+    }
+}
 
-        Self {
-            entries: Vec::new(),
-            storage,
-            page_bitmap,
+impl<
+    'a,
+    const STORAGE_SIZE: usize,
+    const PAGE_SIZE: usize,
+    const MAX_FILES: usize,
+    const MAX_NAME_LEN: usize,
+    const BITMAP_WORDS: usize,
+    A: Allocator,
+> MemoryFs<'a, STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>
+{
+    pub fn from_backed(storage: &'a mut [u8; STORAGE_SIZE]) -> Self
+    where
+        A: Default,
+    {
+        Self::with_allocator(storage, A::default())
+    }
+
+    /// Create a filesystem that places new pages with `allocator`.
+    pub fn with_allocator(storage: &'a mut [u8; STORAGE_SIZE], allocator: A) -> Self {
+        const {
+            assert!(PAGE_SIZE > 0, "PAGE_SIZE must not be 0");
+            assert!(
+                STORAGE_SIZE.is_multiple_of(PAGE_SIZE),
+                "STORAGE_SIZE must be a multiple of PAGE_SIZE"
+            );
+            assert!(
+                bitmap_words(STORAGE_SIZE, PAGE_SIZE) <= BITMAP_WORDS,
+                "page bitmap too small, use `bitmap_words(STORAGE_SIZE, PAGE_SIZE)` for BITMAP_WORDS"
+            );
         }
+
+        Self::new(Storage::Borrowed(storage), PAGE_SIZE, allocator)
     }
 
     // File system operations
-    // TODO: Implement a filesystem trait for these functions
-    // TODO: Support atomic operations
 
     /// Create a new file with default flags.
     ///
-    /// The file name must be non-empty, contain no whitespace, and be unique.
-    /// Files are stored contiguously in page-backed storage.
+    /// `name` is a path: components are separated by `/`, and `.` and `..` refer to the
+    /// current and parent directory. The parent directory must exist. The final component
+    /// must be non-empty, contain no whitespace, and be unique within its directory.
+    /// Files are stored in page-backed storage, preferably as a single contiguous extent.
+    /// If no contiguous run is available, the file is split over multiple extents.
     ///
-    /// Creating an empty file is allowed. Empty files have `size == 0` and `extent == None`.
+    /// Creating an empty file is allowed. Empty files have `size == 0` and no extents.
     ///
     /// # Errors
     /// - `FsErr::FileNameInvalid` if the name is invalid or too long
     /// - `FsErr::Duplicate` if the name already exists
+    /// - `FsErr::NotFound` / `FsErr::NotDirectory` if the parent directory does not exist
     /// - `FsErr::TooManyFiles` if the entry table is full
-    /// - `FsErr::TooManyExtents` if no contiguous run of pages is available
+    /// - `FsErr::NoSpace` if there are not enough free pages
+    /// - `FsErr::TooManyExtents` if the free pages are too fragmented to hold the file
     pub fn create(&mut self, name: &str, data: &[u8]) -> Result<(), FsErr> {
         self.create_with_flags(name, data, FileFlags::empty())
     }
@@ -127,161 +1376,207 @@ impl<'a, const STORAGE_SIZE: usize, const PAGE_SIZE: usize> MemoryFs<'a, STORAGE
     /// This is identical to `create`, but allows setting file behavior flags such as
     /// `IMMUTABLE`, `APPEND_ONLY`, or `SEALED_NAMES`.
     ///
-    /// Creating an empty file is allowed. Empty files have `size == 0` and `extent == None`.
+    /// Files created with `CHECKSUMMED` store a CRC32 of their contents, which is kept up to
+    /// date on every modification and verified on read.
+    ///
+    /// Files created with `DO_NOT_FRAGMENT` always occupy a single contiguous extent.
+    ///
+    /// Creating an empty file is allowed. Empty files have `size == 0` and no extents.
     ///
     /// # Errors
     /// - `FsErr::FileNameInvalid` if the name is invalid or too long
     /// - `FsErr::Duplicate` if the name already exists
+    /// - `FsErr::NotFound` / `FsErr::NotDirectory` if the parent directory does not exist
     /// - `FsErr::TooManyFiles` if the entry table is full
-    /// - `FsErr::TooManyExtents` if no contiguous run of pages is available
+    /// - `FsErr::NoSpace` if there are not enough free pages
+    /// - `FsErr::WouldFragment` if the file has `DO_NOT_FRAGMENT` and no contiguous run is available
+    /// - `FsErr::TooManyExtents` if the free pages are too fragmented to hold the file
     pub fn create_with_flags(
         &mut self,
         name: &str,
         data: &[u8],
         flags: FileFlags,
     ) -> Result<(), FsErr> {
+        let (parent, name) = self.split_path(name)?;
+
         // Check if we have space for another entry
-        if name.len() > MAX_FILE_NAME_LENGTH {
+        if name.len() > MAX_NAME_LEN {
             return Err(FsErr::FileNameInvalid("File name too long"));
         }
 
-        let required_pages = data.len().div_ceil(PAGE_SIZE);
-        let extent = if required_pages > 0 {
-            if let Some(extent) = self.find_free_pages(required_pages) {
-                Some(extent)
-            } else {
-                return Err(FsErr::TooManyExtents);
-            }
+        let max_extents = if flags.contains(FileFlags::DO_NOT_FRAGMENT) {
+            1
         } else {
-            None
+            MAX_EXTENTS_PER_FILE
+        };
+        let required_pages = data.len().div_ceil(self.page_size());
+        let extents = if required_pages > 0 {
+            self.find_free_extents(required_pages, max_extents)?
+        } else {
+            Extents::new()
         };
 
-        let file_name: String<MAX_FILE_NAME_LENGTH> =
+        let file_name: String<MAX_NAME_LEN> =
             String::from_str(name).expect("Error while processing filename");
 
         // Check for invalid or duplicate names.
-        let file_name = self.validate_file_name(file_name)?;
-
-        self.entries
-            .push(FileEntry {
-                name: file_name,
-                size: data.len(),
-                flags,
-                extent,
-            })
-            // FIXME: FileEntry should not be a limiting factor for adding files, storage space should be the only limit.
-            .map_err(|_| FsErr::TooManyFiles)?;
-
-        if let Some(extent) = extent {
-            self.mark_pages(extent.start_page, extent.len_pages, true);
-
-            let offset = extent.start_page * PAGE_SIZE;
-            self.storage[offset..offset + data.len()].copy_from_slice(data);
+        let file_name = self.validate_file_name(parent, file_name)?;
+
+        let index = self.push_entry(FileEntry {
+            name: file_name,
+            size: data.len(),
+            flags,
+            extents: extents.clone(),
+            crc: 0,
+            id: self.next_id,
+            parent,
+            is_dir: false,
+        })?;
+
+        self.next_id += 1;
+
+        for extent in &extents {
+            self.mark_pages(extent.start_page(), extent.len_pages(), true);
         }
+        self.copy_in(index, 0, data);
+        self.update_checksum(index);
 
         Ok(())
     }
 
-    /// Read the contents of a file.
-    ///
-    /// Returns a slice into the internal storage backing the filesystem.
-    ///
-    /// Empty files return an empty slice (`&[]`).
-    ///
-    /// # Returns
-    /// - `Some(&[u8])` if the file exists
-    /// - `None` if the file does not exist
-    pub fn read(&self, name: &str) -> Option<&[u8]> {
-        self.entries.iter().find(|f| f.name == name).map(|f| {
-            if f.size == 0 {
-                &self.storage[0..0]
-            } else {
-                let e = f.extent.expect("non-empty file must have extent");
-                let start = e.start_page * PAGE_SIZE;
-                &self.storage[start..start + f.size]
-            }
-        })
-    }
-    /// Read a portion of a file starting at `offset`.
-    ///
-    /// Returns a slice into the internal storage backing the filesystem.
+    /// Rename or move an existing file or directory.
     ///
-    /// If `offset` is at or past the end of the file, an empty slice (`&[]`)
-    /// is returned. The returned slice is clamped to the file's logical size.
+    /// `new_name` is a path and may point into another directory, which must exist.
+    /// Its final component must be non-empty, contain no whitespace, and be unique within
+    /// its directory. Moving a directory moves everything below it.
     ///
-    /// # Returns
-    /// - `Ok(&[u8])` containing up to `len` bytes if the file exists
-    /// - `Ok(&[])` if `offset` is at or past the end of the file
-    /// - `Err(FsErr::NotFound)` if the file does not exist
-    /// - `Err(FsErr::Corrupt)` if internal metadata is inconsistent
-    pub fn read_at(&self, name: &str, offset: usize, len: usize) -> Result<&[u8], FsErr> {
-        let index = self.find_file_index(name)?;
-        let entry = &self.entries[index];
-        if let Some(extent) = entry.extent {
-            // Offset outside of file
-            let extent_capacity = extent.len_pages * PAGE_SIZE;
-            if offset >= entry.size {
-                return Ok(&[]);
-            }
-            if offset > extent_capacity {
-                return Err(FsErr::Corrupt);
-            }
+    /// # Errors
+    /// - `FsErr::NotFound` if the file or the target directory does not exist
+    /// - `FsErr::FileNameSealed` if the file has `SEALED_NAMES`
+    /// - `FsErr::FileNameInvalid` if the new name is invalid or too long
+    /// - `FsErr::Duplicate` if `new_name` already exists
+    /// - `FsErr::InvalidOp` if a directory would be moved into itself
+    pub fn rename(&mut self, name: &str, new_name: &str) -> Result<(), FsErr> {
+        let index = self.resolve(name)?.ok_or(FsErr::InvalidOp)?;
 
-            let readable_bytes = entry.size.checked_sub(offset).unwrap_or(0);
-            let len = len.min(readable_bytes);
+        if self.entries[index].flags.contains(FileFlags::SEALED_NAMES) {
+            return Err(FsErr::FileNameSealed);
+        }
 
-            // No bytes to read.
-            if len == 0 {
-                return Ok(&[]);
-            }
-            if offset.checked_add(len).ok_or(FsErr::Corrupt)? > extent_capacity {
-                return Err(FsErr::Corrupt);
-            }
+        let (parent, new_name) = self.split_path(new_name)?;
+        let new_name = self.validate_file_name(
+            parent,
+            String::from_str(new_name)
+                .map_err(|_| FsErr::FileNameInvalid("Error while processing file name"))?,
+        )?;
 
-            // Read bytes with sanity checks.
-            if let Some(start) = (extent.start_page * PAGE_SIZE).checked_add(offset) {
-                if let Some(end) = start.checked_add(len) {
-                    if end > self.storage.len() {
-                        return Err(FsErr::Corrupt);
-                    }
-                    return Ok(&self.storage[start..end]);
-                }
-            }
-            Err(FsErr::Corrupt)
-        } else {
-            return Ok(&[]);
+        if self.entries[index].is_dir && self.is_within(parent, self.entries[index].id) {
+            return Err(FsErr::InvalidOp);
         }
-    }
 
-    /// Check whether a file exists.
-    ///
-    /// This checks for the presence of a file entry by name.
-    pub fn exists(&self, name: &str) -> bool {
-        self.entries.iter().any(|f| f.name == name)
+        self.rename_entry(index, parent, new_name);
+        Ok(())
     }
 
-    /// Rename an existing file.
+    /// Rename or move a file, replacing `new_name` if it already exists.
+    ///
+    /// The replaced file is removed and its pages are freed in the same step as the rename,
+    /// so `new_name` always refers to either the old or the new file. On error, both files
+    /// are left untouched. If `new_name` does not exist this behaves like `rename`.
     ///
-    /// The new name must be non-empty, contain no whitespace, and be unique.
+    /// Only files can be renamed this way; use `rename` for directories.
     ///
     /// # Errors
-    /// - `FsErr::NotFound` if the file does not exist
+    /// - `FsErr::NotFound` if the file or the target directory does not exist
+    /// - `FsErr::IsDirectory` if either path refers to a directory
     /// - `FsErr::FileNameSealed` if the file has `SEALED_NAMES`
+    /// - `FsErr::ReadOnly` if the replaced file has `IMMUTABLE` or `SEALED_NAMES`
     /// - `FsErr::FileNameInvalid` if the new name is invalid or too long
-    /// - `FsErr::Duplicate` if `new_name` already exists
-    pub fn rename(&mut self, name: &str, new_name: &str) -> Result<(), FsErr> {
+    pub fn rename_replace(&mut self, name: &str, new_name: &str) -> Result<(), FsErr> {
         let index = self.find_file_index(name)?;
+        let target = match self.find_file_index(new_name) {
+            Ok(target) => target,
+            Err(FsErr::NotFound) => return self.rename(name, new_name),
+            Err(e) => return Err(e),
+        };
 
         if self.entries[index].flags.contains(FileFlags::SEALED_NAMES) {
             return Err(FsErr::FileNameSealed);
         }
+        if index == target {
+            return Ok(());
+        }
+        if self.entries[target]
+            .flags
+            .intersects(FileFlags::IMMUTABLE | FileFlags::SEALED_NAMES)
+        {
+            return Err(FsErr::ReadOnly);
+        }
 
-        let new_name = self.validate_file_name(
-            String::from_str(new_name)
-                .map_err(|_| FsErr::FileNameInvalid("Error while processing file name"))?,
-        )?;
+        // Nothing can fail from here on.
+        let replaced = self.remove_entry(target);
+        let index = if target < index { index - 1 } else { index };
+        self.rename_entry(index, replaced.parent, replaced.name);
+        for extent in &replaced.extents {
+            self.mark_pages(extent.start_page(), extent.len_pages(), false);
+        }
+        Ok(())
+    }
+
+    /// Replace the entire contents of a file without ever overwriting the old contents.
+    ///
+    /// Unlike `write`, the new data is always written to freshly allocated pages. The file
+    /// only switches to them once the data is in place, after which the old pages are freed.
+    /// Readers therefore see either the old or the new contents, and on error the file is
+    /// left untouched. This requires enough free space to hold both versions at once.
+    ///
+    /// If the file does not exist, it is created with default flags.
+    ///
+    /// # Errors
+    /// - `FsErr::ReadOnly` if the file has `IMMUTABLE`
+    /// - `FsErr::NoSpace` if there are not enough free pages for the new contents
+    /// - `FsErr::WouldFragment` if the file has `DO_NOT_FRAGMENT` and no contiguous run is available
+    /// - `FsErr::TooManyExtents` if the free pages are too fragmented to hold the file
+    /// - `FsErr::TooManyFiles` / `FsErr::Duplicate` / `FsErr::FileNameInvalid` (when creating)
+    pub fn atomic_write(&mut self, name: &str, data: &[u8]) -> Result<(), FsErr> {
+        let index = match self.find_file_index(name) {
+            Ok(i) => i,
+            Err(FsErr::NotFound) => return self.create(name, data),
+            Err(e) => return Err(e),
+        };
+
+        if self.entries[index].flags.contains(FileFlags::IMMUTABLE) {
+            return Err(FsErr::ReadOnly);
+        }
+
+        let new_extents = if data.is_empty() {
+            Extents::new()
+        } else {
+            self.find_free_extents(
+                data.len().div_ceil(self.page_size()),
+                self.entries[index].max_extents(),
+            )?
+        };
+        for extent in &new_extents {
+            self.mark_pages(extent.start_page(), extent.len_pages(), true);
+            self.mark_dirty(extent.start_page(), extent.len_pages());
+        }
+
+        let mut copied = 0;
+        for range in Self::spans(&new_extents, self.page_size(), 0, data.len()) {
+            let len = range.len();
+            self.storage[range].copy_from_slice(&data[copied..copied + len]);
+            copied += len;
+        }
 
-        self.entries[index].name = new_name;
+        // Switch the file over to the new pages.
+        let old_extents = core::mem::replace(&mut self.entries[index].extents, new_extents);
+        self.entries[index].size = data.len();
+        self.update_checksum(index);
+
+        for extent in &old_extents {
+            self.mark_pages(extent.start_page(), extent.len_pages(), false);
+        }
         Ok(())
     }
 
@@ -291,12 +1586,14 @@ impl<'a, const STORAGE_SIZE: usize, const PAGE_SIZE: usize> MemoryFs<'a, STORAGE
     ///
     /// If `data` is empty, the file becomes empty (`size = 0`) and any allocated pages are freed.
     ///
-    /// This operation may relocate the file to a new contiguous extent if the current allocation
-    /// is too small.
+    /// If the current allocation is too small, the file is moved to newly allocated pages.
+    /// These may span multiple extents unless the file has `DO_NOT_FRAGMENT`.
     ///
     /// # Errors
     /// - `FsErr::ReadOnly` if the file has `IMMUTABLE`
-    /// - `FsErr::NoSpace` if the file cannot be allocated contiguously
+    /// - `FsErr::NoSpace` if there are not enough free pages
+    /// - `FsErr::WouldFragment` if the file has `DO_NOT_FRAGMENT` and no contiguous run is available
+    /// - `FsErr::TooManyExtents` if the free pages are too fragmented to hold the file
     /// - `FsErr::TooManyFiles` / `FsErr::Duplicate` / `FsErr::FileNameInvalid` (when creating)
     pub fn write(&mut self, name: &str, data: &[u8]) -> Result<(), FsErr> {
         let index = match self.find_file_index(name) {
@@ -310,58 +1607,50 @@ impl<'a, const STORAGE_SIZE: usize, const PAGE_SIZE: usize> MemoryFs<'a, STORAGE
             return Err(FsErr::ReadOnly);
         }
 
-        let current_pages = self.entries[index].extent.map_or(0, |ext| ext.len_pages);
-        let required_pages = data.len().div_ceil(PAGE_SIZE);
+        let current_pages = self.entries[index].capacity_pages();
+        let required_pages = data.len().div_ceil(self.page_size());
 
         // Free pages if data is empty
         if required_pages == 0 {
-            if let Some(old_extent) = self.entries[index].extent.take() {
-                self.mark_pages(old_extent.start_page, old_extent.len_pages, false);
-            }
+            self.release_pages(index, 0);
             self.entries[index].size = 0;
+            self.update_checksum(index);
 
             return Ok(());
         }
 
-        // Find new extent if needed
+        // Find new extents if needed
         if required_pages > current_pages {
             // Unmark old pages
-            let old_extent = self.entries[index].extent;
-
-            if let Some(old_extent) = old_extent {
-                self.mark_pages(old_extent.start_page, old_extent.len_pages, false);
+            let old_extents = core::mem::take(&mut self.entries[index].extents);
+            for extent in &old_extents {
+                self.mark_pages(extent.start_page(), extent.len_pages(), false);
             }
 
-            let new_extent = self.find_free_pages(required_pages);
-            match new_extent {
-                Some(extent) => {
-                    self.mark_pages(extent.start_page, extent.len_pages, true);
-
-                    self.entries[index].extent = Some(extent);
-                    self.entries[index].size = data.len();
-
-                    let offset = extent.start_page * PAGE_SIZE;
-                    self.storage[offset..offset + data.len()].copy_from_slice(data);
+            match self.find_free_extents(required_pages, self.entries[index].max_extents()) {
+                Ok(extents) => {
+                    for extent in &extents {
+                        self.mark_pages(extent.start_page(), extent.len_pages(), true);
+                    }
+                    self.entries[index].extents = extents;
                 }
-                None => {
+                Err(e) => {
                     // Search failed, remark pages.
-                    if let Some(old_extent) = old_extent {
-                        self.mark_pages(old_extent.start_page, old_extent.len_pages, true);
+                    for extent in &old_extents {
+                        self.mark_pages(extent.start_page(), extent.len_pages(), true);
                     }
-                    return Err(FsErr::NoSpace);
+                    self.entries[index].extents = old_extents;
+                    return Err(e);
                 }
             }
-        } else {
-            let extent = self.entries[index]
-                .extent
-                .expect("required_pages > 0 implies extent exists");
+        }
 
-            self.entries[index].size = data.len();
-            let offset = extent.start_page * PAGE_SIZE;
-            self.storage[offset..offset + data.len()].copy_from_slice(data);
-        };
+        self.protect_pages(index, 0, data.len())?;
+        self.entries[index].size = data.len();
+        self.copy_in(index, 0, data);
+        self.update_checksum(index);
 
-        return Ok(());
+        Ok(())
     }
 
     /// Write bytes to an existing file at the given `offset`.
@@ -370,12 +1659,14 @@ impl<'a, const STORAGE_SIZE: usize, const PAGE_SIZE: usize> MemoryFs<'a, STORAGE
     /// - `offset > size` is rejected.
     /// - `offset == size` is allowed and is equivalent to appending.
     ///
-    /// If the write exceeds currently allocated capacity, the filesystem will attempt to grow
-    /// the file **in place** by consuming neighbouring free pages. If the neighbouring pages are
-    /// not free, the operation fails with `WouldFragment` (no relocation is performed here).
+    /// If the write exceeds currently allocated capacity, the file first grows **in place** by
+    /// consuming neighbouring free pages, then like `append`: it is repacked into a contiguous
+    /// run if one is free, and otherwise the remaining pages are added as new extents. Files
+    /// with `DO_NOT_FRAGMENT` only grow in place and fail with `WouldFragment` otherwise
+    /// (no relocation is performed here).
     ///
-    /// For empty files (`extent == None`), only `offset == 0` is allowed; the call will allocate
-    /// a new extent and write the provided data.
+    /// For empty files, only `offset == 0` is allowed; the call will allocate pages and write
+    /// the provided data.
     ///
     /// Passing an empty `data` slice is a no-op.
     ///
@@ -383,16 +1674,21 @@ impl<'a, const STORAGE_SIZE: usize, const PAGE_SIZE: usize> MemoryFs<'a, STORAGE
     /// - `FsErr::NotFound` if the file does not exist
     /// - `FsErr::ReadOnly` if the file has `IMMUTABLE`
     /// - `FsErr::InvalidOp` if `offset > size` or the file has `APPEND_ONLY`
-    /// - `FsErr::NoSpace` if allocation is required but no contiguous run exists
-    /// - `FsErr::WouldFragment` if growth would require non-contiguous allocation
+    /// - `FsErr::NoSpace` if there are not enough free pages
+    /// - `FsErr::WouldFragment` if the file has `DO_NOT_FRAGMENT` and cannot grow in place
+    /// - `FsErr::TooManyExtents` if growth would exceed the extent limit of the file
     pub fn write_at(&mut self, name: &str, offset: usize, data: &[u8]) -> Result<(), FsErr> {
         // No Op
         if data.is_empty() {
             return Ok(());
         }
 
-        // Find file and check flags.
         let index = self.find_file_index(name)?;
+        self.write_at_index(index, offset, data)
+    }
+
+    fn write_at_index(&mut self, index: usize, offset: usize, data: &[u8]) -> Result<(), FsErr> {
+        // Check flags.
         let entry = &self.entries[index];
 
         if entry.flags.contains(FileFlags::IMMUTABLE) {
@@ -404,90 +1700,49 @@ impl<'a, const STORAGE_SIZE: usize, const PAGE_SIZE: usize> MemoryFs<'a, STORAGE
             return Err(FsErr::InvalidOp);
         }
 
-        let current_extent = self.entries[index].extent;
-        let current_capacity = current_extent.map_or(0, |ext| ext.len_pages) * PAGE_SIZE;
-
-        let write_end = offset + data.len();
-
-        // If no extent is present, treat as first write.
-        if current_extent.is_none() {
-            // No hole check
-            if offset != 0 {
-                return Err(FsErr::InvalidOp);
-            }
-
-            // Treat as first write (without creating file)
-            let required_pages = write_end.div_ceil(PAGE_SIZE);
-            if let Some(extent) = self.find_free_pages(required_pages) {
-                self.mark_pages(extent.start_page, extent.len_pages, true);
-
-                self.entries[index].extent = Some(extent);
-                self.entries[index].size = data.len();
-
-                let offset = extent.start_page * PAGE_SIZE;
-                self.storage[offset..offset + data.len()].copy_from_slice(data);
-
-                return Ok(());
-            } else {
-                return Err(FsErr::NoSpace);
-            }
-        }
-
-        let current_extent = current_extent.expect("Extent should exsist.");
-
-        // Try growing into neighbouring space.
-        if write_end > current_capacity {
-            let required_pages = write_end.div_ceil(PAGE_SIZE);
-            let extra_pages = required_pages - current_extent.len_pages;
-
-            assert!(extra_pages > 0);
-
-            if let Some(neighbour_extent) = self.check_neighbour_pages_free(
-                current_extent.start_page + current_extent.len_pages,
-                extra_pages,
-            ) {
-                self.mark_pages(
-                    neighbour_extent.start_page,
-                    neighbour_extent.len_pages,
-                    true,
-                );
+        let growth = if entry.flags.contains(FileFlags::DO_NOT_FRAGMENT) {
+            Growth::InPlace
+        } else {
+            Growth::Fragment
+        };
+        let current_pages = entry.capacity_pages();
+        let required_pages = (offset + data.len()).div_ceil(self.page_size());
 
-                self.entries[index]
-                    .extent
-                    .expect("Extent should exsist here.")
-                    .len_pages += neighbour_extent.len_pages;
-            } else {
-                return Err(FsErr::WouldFragment);
-            }
+        if required_pages > current_pages {
+            self.grow(index, required_pages - current_pages, growth)?;
         }
 
-        let start = current_extent.start_page * PAGE_SIZE + offset;
-
-        self.storage[start..start + data.len()].copy_from_slice(data);
+        self.protect_pages(index, offset, data.len())?;
+        self.copy_in(index, offset, data);
         self.entries[index].size = self.entries[index].size.max(offset + data.len());
+        self.update_checksum(index);
 
         Ok(())
     }
 
     /// Append data to a file.
     ///
-    /// This is the default append mode: it will keep the file contiguous, and may relocate
-    /// (repack) the file to a new contiguous extent if needed.
+    /// This is the default append mode: the file grows into neighbouring pages where possible,
+    /// is otherwise relocated (repacked) to a new contiguous extent, and only gains a new extent
+    /// when no contiguous run of pages is large enough. Files with `DO_NOT_FRAGMENT` are never
+    /// split and fail instead.
     ///
     /// Passing an empty `data` slice is a no-op.
     ///
     /// # Errors
     /// - `FsErr::NotFound` if the file does not exist
     /// - `FsErr::ReadOnly` if the file has `IMMUTABLE`
-    /// - `FsErr::NoSpace` if no contiguous run of pages is available
-    /// - `FsErr::WouldFragment` if contiguity cannot be maintained (when repack is not possible)
+    /// - `FsErr::NoSpace` if there are not enough free pages
+    /// - `FsErr::TooManyExtents` if growth would exceed the extent limit of the file
+    /// - `FsErr::WouldFragment` if a `DO_NOT_FRAGMENT` file cannot be kept contiguous
     pub fn append(&mut self, name: &str, data: &[u8]) -> Result<(), FsErr> {
-        self.append_impl(name, data, true)
+        self.append_impl(name, data, Growth::Fragment)
     }
     /// Append data to a file, requiring contiguous growth.
     ///
-    /// This function forces the file to remain contiguous and does **not** relocate existing data.
-    /// If the file cannot be extended into neighbouring free pages, the operation fails.
+    /// This function forces the file to grow into the pages directly after its last extent
+    /// and does **not** relocate existing data or add extents. If the file cannot be extended
+    /// into neighbouring free pages, the operation fails.
     ///
     /// Passing an empty `data` slice is a no-op.
     ///
@@ -496,127 +1751,78 @@ impl<'a, const STORAGE_SIZE: usize, const PAGE_SIZE: usize> MemoryFs<'a, STORAGE
     /// # Errors
     /// - `FsErr::NotFound` if the file does not exist
     /// - `FsErr::ReadOnly` if the file has `IMMUTABLE`
-    /// - `FsErr::NoSpace` if allocation is required and no contiguous run exists
+    /// - `FsErr::NoSpace` if allocation is required and there are not enough free pages
     /// - `FsErr::WouldFragment` if the file cannot be extended contiguously
     pub fn append_strict(&mut self, name: &str, data: &[u8]) -> Result<(), FsErr> {
-        self.append_impl(name, data, false)
+        self.append_impl(name, data, Growth::InPlace)
     }
     /// Append data to a file, keeping it contiguous and allowing relocation.
     ///
     /// This behaves like `append_strict`, but if neighbouring pages are not available, the file
     /// may be moved (repacked) to a new contiguous extent large enough to hold the result.
+    /// Repacking a fragmented file merges it into a single extent.
     ///
     /// Passing an empty `data` slice is a no-op.
     ///
     /// # Errors
     /// - `FsErr::NotFound` if the file does not exist
     /// - `FsErr::ReadOnly` if the file has `IMMUTABLE`
-    /// - `FsErr::NoSpace` if no contiguous run of pages is available
-    /// - `FsErr::WouldFragment` if contiguity cannot be maintained and repack is disabled
+    /// - `FsErr::NoSpace` if there are not enough free pages
+    /// - `FsErr::WouldFragment` if no contiguous run of pages is available
     pub fn append_strict_or_repack(&mut self, name: &str, data: &[u8]) -> Result<(), FsErr> {
-        self.append_impl(name, data, true)
+        self.append_impl(name, data, Growth::Repack)
     }
-    fn append_impl(&mut self, name: &str, data: &[u8], repack: bool) -> Result<(), FsErr> {
+    fn append_impl(&mut self, name: &str, data: &[u8], growth: Growth) -> Result<(), FsErr> {
         // No Op
         if data.is_empty() {
             return Ok(());
         }
 
-        // Find file and check flags.
         let index = self.find_file_index(name)?;
+        self.append_index(index, data, growth)
+    }
+
+    fn append_index(&mut self, index: usize, data: &[u8], growth: Growth) -> Result<(), FsErr> {
+        // Check flags.
         let entry = &self.entries[index];
 
         if entry.flags.contains(FileFlags::IMMUTABLE) {
             return Err(FsErr::ReadOnly);
         }
 
-        // Current allocation and required space.
-        let required_size = self.entries[index].size + data.len();
-        let current_extent = if let Some(extent) = self.entries[index].extent {
-            extent
-        } else {
-            // No extent present, we can allocate a new page.
-            let extent = self
-                .find_free_pages(required_size.div_ceil(PAGE_SIZE))
-                .ok_or(FsErr::NoSpace)?;
-
-            self.mark_pages(extent.start_page, extent.len_pages, true);
-
-            self.entries[index].extent = Some(extent);
-            self.entries[index].size = required_size;
-
-            let offset = extent.start_page * PAGE_SIZE;
-            self.storage[offset..offset + data.len()].copy_from_slice(data);
-
-            return Ok(());
-        };
-        let current_capacity = current_extent.len_pages * PAGE_SIZE;
+        let growth =
+            if growth == Growth::Fragment && entry.flags.contains(FileFlags::DO_NOT_FRAGMENT) {
+                Growth::Repack
+            } else {
+                growth
+            };
 
-        // Case 1: Fits current allocation.
-        if required_size <= current_capacity {
-            let offset = (current_extent.start_page * PAGE_SIZE) + self.entries[index].size;
-            self.storage[offset..offset + data.len()].copy_from_slice(data);
-            self.entries[index].size += data.len();
+        // Current allocation and required space.
+        let old_size = entry.size;
+        let required_size = old_size + data.len();
+        let current_pages = entry.capacity_pages();
+        let required_pages = required_size.div_ceil(self.page_size());
 
-            return Ok(());
+        if required_pages > current_pages {
+            self.grow(index, required_pages - current_pages, growth)?;
         }
 
-        let required_pages = required_size.div_ceil(PAGE_SIZE);
-        let extra_pages = required_pages.saturating_sub(current_extent.len_pages);
-
-        assert!(extra_pages > 0);
-
-        // Case 2: Can we extent the page?
-        let next_page = current_extent.start_page + current_extent.len_pages;
-        if let Some(neighbour) = self.check_neighbour_pages_free(next_page, extra_pages) {
-            self.mark_pages(neighbour.start_page, neighbour.len_pages, true);
-            self.entries[index].extent = Some(Extent {
-                start_page: current_extent.start_page,
-                len_pages: current_extent.len_pages + neighbour.len_pages,
-            });
-
-            let offset = (current_extent.start_page * PAGE_SIZE) + self.entries[index].size;
-            self.storage[offset..offset + data.len()].copy_from_slice(data);
-            self.entries[index].size = required_size;
-            return Ok(());
-        };
-
-        // Case 3: Relocate (repack) if allowed.
-        if repack && let Some(new_extent) = self.find_free_pages(required_pages) {
-            let old_start = current_extent.start_page * PAGE_SIZE;
-            let old_len = self.entries[index].size;
-            let old_range = old_start..old_start + old_len;
-
-            let new_start = new_extent.start_page * PAGE_SIZE;
-
-            self.mark_pages(new_extent.start_page, new_extent.len_pages, true);
-
-            // Move exsisting bytes
-            if old_len > 0 && old_start != new_start {
-                self.storage.copy_within(old_range, new_start);
-            }
-
-            // Append new bytes.
-            let append_offset = new_start + old_len;
-            self.storage[append_offset..append_offset + data.len()].copy_from_slice(data);
-
-            self.mark_pages(current_extent.start_page, current_extent.len_pages, false);
+        self.protect_pages(index, old_size, data.len())?;
+        self.copy_in(index, old_size, data);
+        self.entries[index].size = required_size;
+        self.extend_checksum(index, old_size);
 
-            self.entries[index].extent = Some(new_extent);
-            self.entries[index].size = required_size;
-            return Ok(());
-        }
-        // Can't extend and repack is not allowed.
-        Err(FsErr::WouldFragment)
+        Ok(())
     }
 
     /// Shrink a file to `new_size` bytes.
     ///
     /// If `new_size` is smaller than the current size, the file size is reduced and any fully
-    /// unused pages at the end of the extent are returned to the free list.
+    /// unused pages at the end of the file are returned to the free list. Extents that are
+    /// no longer needed are released entirely.
     ///
     /// `truncate(name, 0)` frees the entire allocation and turns the file into an empty file
-    /// (`size = 0`, `extent = None`).
+    /// (`size = 0`, no extents).
     ///
     /// This function does not support growing a file (preallocation).
     ///
@@ -625,8 +1831,12 @@ impl<'a, const STORAGE_SIZE: usize, const PAGE_SIZE: usize> MemoryFs<'a, STORAGE
     /// - `FsErr::ReadOnly` if the file has `IMMUTABLE`
     /// - `FsErr::InvalidOp` if `new_size` is greater than the current size
     pub fn truncate(&mut self, name: &str, new_size: usize) -> Result<(), FsErr> {
-        // Find file and check flags.
         let index = self.find_file_index(name)?;
+        self.truncate_index(index, new_size)
+    }
+
+    fn truncate_index(&mut self, index: usize, new_size: usize) -> Result<(), FsErr> {
+        // Check flags.
         let entry = &self.entries[index];
 
         if entry.flags.contains(FileFlags::IMMUTABLE) {
@@ -638,34 +1848,10 @@ impl<'a, const STORAGE_SIZE: usize, const PAGE_SIZE: usize> MemoryFs<'a, STORAGE
             return Err(FsErr::InvalidOp);
         }
 
-        // Free all
-        if new_size == 0 {
-            if let Some(old_extent) = self.entries[index].extent.take() {
-                self.mark_pages(old_extent.start_page, old_extent.len_pages, false);
-            }
-            self.entries[index].size = 0;
-            return Ok(());
-        }
-
         // Free unused pages
-        if let Some(current_extent) = entry.extent {
-            let current_pages = current_extent.len_pages;
-            let required_pages = new_size.div_ceil(PAGE_SIZE);
-
-            if required_pages < current_pages {
-                let unused = Extent {
-                    start_page: current_extent.start_page + required_pages,
-                    len_pages: current_extent.len_pages - required_pages,
-                };
-                self.mark_pages(unused.start_page, unused.len_pages, false);
-            }
-
-            self.entries[index].extent = Some(Extent {
-                start_page: current_extent.start_page,
-                len_pages: required_pages,
-            });
-            self.entries[index].size = new_size;
-        }
+        self.release_pages(index, new_size.div_ceil(self.page_size()));
+        self.entries[index].size = new_size;
+        self.update_checksum(index);
 
         Ok(())
     }
@@ -677,110 +1863,62 @@ impl<'a, const STORAGE_SIZE: usize, const PAGE_SIZE: usize> MemoryFs<'a, STORAGE
     ///
     /// Growth is only allowed if it can be done contiguously:
     /// - If the file has no extent yet (empty file), a new extent is allocated.
-    /// - If the file has an extent, it will only grow into neighbouring free pages.
+    /// - If the file has extents, the last one will only grow into neighbouring free pages.
     /// - If neighbouring pages are not free, this function returns `WouldFragment`.
     ///
     /// # Errors
     /// - `FsErr::NotFound` if the file does not exist
     /// - `FsErr::ReadOnly` if the file has `IMMUTABLE`
-    /// - `FsErr::NoSpace` if a new allocation is required but no contiguous run is available
+    /// - `FsErr::NoSpace` if a new allocation is required but there are not enough free pages
     /// - `FsErr::WouldFragment` if growth would require relocation
     pub fn reserve(&mut self, name: &str, new_size: usize) -> Result<(), FsErr> {
-        self.reserve_impl(name, new_size, false)
+        self.reserve_impl(name, new_size, Growth::InPlace)
     }
     /// Ensure a file has at least `new_size` bytes of *capacity* allocated, allowing relocation.
     ///
     /// This is identical to `reserve`, except that if the file cannot grow into neighbouring pages,
     /// it may be relocated (repacked) to a new contiguous extent large enough to satisfy the request.
-    ///
-    /// This operation does **not** change the file's logical `size` and does not write/zero any bytes.
-    ///
-    /// # Errors
-    /// - `FsErr::NotFound` if the file does not exist
-    /// - `FsErr::ReadOnly` if the file has `IMMUTABLE`
-    /// - `FsErr::NoSpace` if no contiguous run of pages is available
-    /// - `FsErr::WouldFragment` if relocation is not possible (e.g. no large enough run)
-    pub fn reserve_or_repack(&mut self, name: &str, new_size: usize) -> Result<(), FsErr> {
-        self.reserve_impl(name, new_size, true)
-    }
-
-    fn reserve_impl(&mut self, name: &str, new_size: usize, repack: bool) -> Result<(), FsErr> {
-        let index = self.find_file_index(name)?;
-
-        if self.entries[index].flags.contains(FileFlags::IMMUTABLE) {
-            return Err(FsErr::ReadOnly);
-        }
-
-        let required_pages = new_size.div_ceil(PAGE_SIZE);
-        let current_pages = self.entries[index].extent.map_or(0, |ext| ext.len_pages);
-
-        // Already big enough
-        if required_pages <= current_pages {
-            return Ok(());
-        }
-
-        let current_extent = if let Some(extent) = self.entries[index].extent {
-            extent
-        } else {
-            // No extent present, we can allocate a new page.
-            let extent = self.find_free_pages(required_pages).ok_or(FsErr::NoSpace)?;
-            self.mark_pages(extent.start_page, extent.len_pages, true);
-            self.entries[index].extent = Some(extent);
-
-            return Ok(());
-        };
-
-        // Can we extent the page?
-        let next_page = current_extent.start_page + current_extent.len_pages;
-        if let Some(neighbour) =
-            self.check_neighbour_pages_free(next_page, required_pages - current_pages)
-        {
-            self.mark_pages(neighbour.start_page, neighbour.len_pages, true);
-            self.entries[index].extent = Some(Extent {
-                start_page: current_extent.start_page,
-                len_pages: current_extent.len_pages + neighbour.len_pages,
-            });
-            return Ok(());
-        };
-
-        // Relocate (repack) if allowed.
-        if repack && let Some(new_extent) = self.find_free_pages(required_pages) {
-            let old_start = current_extent.start_page * PAGE_SIZE;
-            let old_len = self.entries[index].size;
-            let old_range = old_start..old_start + old_len;
-
-            let new_start = new_extent.start_page * PAGE_SIZE;
+    ///
+    /// This operation does **not** change the file's logical `size` and does not write/zero any bytes.
+    ///
+    /// # Errors
+    /// - `FsErr::NotFound` if the file does not exist
+    /// - `FsErr::ReadOnly` if the file has `IMMUTABLE`
+    /// - `FsErr::NoSpace` if there are not enough free pages
+    /// - `FsErr::WouldFragment` if relocation is not possible (e.g. no large enough run)
+    pub fn reserve_or_repack(&mut self, name: &str, new_size: usize) -> Result<(), FsErr> {
+        self.reserve_impl(name, new_size, Growth::Repack)
+    }
 
-            self.mark_pages(new_extent.start_page, new_extent.len_pages, true);
+    fn reserve_impl(&mut self, name: &str, new_size: usize, growth: Growth) -> Result<(), FsErr> {
+        let index = self.find_file_index(name)?;
 
-            // Move exsisting bytes
-            if old_len > 0 && old_start != new_start {
-                self.storage.copy_within(old_range, new_start);
-            }
+        if self.entries[index].flags.contains(FileFlags::IMMUTABLE) {
+            return Err(FsErr::ReadOnly);
+        }
 
-            self.mark_pages(current_extent.start_page, current_extent.len_pages, false);
+        let required_pages = new_size.div_ceil(self.page_size());
+        let current_pages = self.entries[index].capacity_pages();
 
-            self.entries[index].extent = Some(new_extent);
+        // Already big enough
+        if required_pages <= current_pages {
             return Ok(());
         }
-        // Can't extend and repack is not allowed.
-        Err(FsErr::WouldFragment)
+
+        self.grow(index, required_pages - current_pages, growth)
     }
 
     /// Return the allocated capacity of a file in bytes.
     ///
-    /// Capacity is `extent.len_pages * PAGE_SIZE`. Empty files (no extent) have capacity `0`.
+    /// Capacity is the total number of pages over all extents times `PAGE_SIZE`.
+    /// Empty files (no extents) have capacity `0`.
     ///
     /// # Returns
     /// - `Some(capacity_bytes)` if the file exists
     /// - `None` if the file does not exist
     pub fn capacity(&self, name: &str) -> Option<usize> {
         if let Ok(index) = self.find_file_index(name) {
-            Some(
-                self.entries[index]
-                    .extent
-                    .map_or(0, |ext| ext.len_pages * PAGE_SIZE),
-            )
+            Some(self.entries[index].capacity_pages() * self.page_size())
         } else {
             None
         }
@@ -791,39 +1929,175 @@ impl<'a, const STORAGE_SIZE: usize, const PAGE_SIZE: usize> MemoryFs<'a, STORAGE
     /// Removing a file does not zero the underlying storage; freed pages may be reused and
     /// overwritten by future allocations.
     ///
+    /// Directories are removed with `rmdir`.
+    ///
     /// # Errors
     /// - `FsErr::NotFound` if the file does not exist
+    /// - `FsErr::IsDirectory` if the entry is a directory
     /// - `FsErr::ReadOnly` if the file has `IMMUTABLE`
     pub fn delete(&mut self, name: &str) -> Result<(), FsErr> {
         let index = self.find_file_index(name)?;
         if self.entries[index].flags.contains(FileFlags::IMMUTABLE) {
             return Err(FsErr::ReadOnly);
         };
-        let page_extent = self.entries[index].extent;
 
-        self.entries.remove(index);
-        if let Some(page_extent) = page_extent {
-            self.mark_pages(page_extent.start_page, page_extent.len_pages, false);
+        let entry = self.remove_entry(index);
+        for extent in &entry.extents {
+            self.mark_pages(extent.start_page(), extent.len_pages(), false);
         }
 
         // No need to clear data from storage, can be overwritten.
         Ok(())
     }
 
-    /// Iterate over all file entries.
+    // Directories
+
+    /// Create a new, empty directory.
     ///
-    /// The iterator yields metadata only (name, size, flags, extent).
-    /// File contents can be accessed via `read()`.
-    pub fn entries(&self) -> impl Iterator<Item = &FileEntry> {
-        self.entries.iter()
+    /// The parent directory must exist. The final path component follows the same rules
+    /// as file names.
+    ///
+    /// # Errors
+    /// - `FsErr::FileNameInvalid` if the name is invalid or too long
+    /// - `FsErr::Duplicate` if the name already exists
+    /// - `FsErr::NotFound` / `FsErr::NotDirectory` if the parent directory does not exist
+    /// - `FsErr::TooManyFiles` if the entry table is full
+    pub fn mkdir(&mut self, name: &str) -> Result<(), FsErr> {
+        let (parent, name) = self.split_path(name)?;
+        let dir_name =
+            String::from_str(name).map_err(|_| FsErr::FileNameInvalid("File name too long"))?;
+        let dir_name = self.validate_file_name(parent, dir_name)?;
+
+        self.push_entry(FileEntry {
+            name: dir_name,
+            size: 0,
+            flags: FileFlags::empty(),
+            extents: Extents::new(),
+            crc: 0,
+            id: self.next_id,
+            parent,
+            is_dir: true,
+        })?;
+        self.next_id += 1;
+
+        Ok(())
+    }
+
+    /// Remove an empty directory.
+    ///
+    /// # Errors
+    /// - `FsErr::NotFound` if the directory does not exist
+    /// - `FsErr::NotDirectory` if the entry is a file
+    /// - `FsErr::DirectoryNotEmpty` if the directory still has entries
+    /// - `FsErr::InvalidOp` when trying to remove the root directory
+    pub fn rmdir(&mut self, name: &str) -> Result<(), FsErr> {
+        let index = self.resolve(name)?.ok_or(FsErr::InvalidOp)?;
+        if !self.entries[index].is_dir {
+            return Err(FsErr::NotDirectory);
+        }
+
+        if self.has_children(self.entries[index].id) {
+            return Err(FsErr::DirectoryNotEmpty);
+        }
+
+        self.remove_entry(index);
+        Ok(())
+    }
+
+    /// Iterate over the entries directly inside a directory.
+    ///
+    /// Use `""` or `"/"` for the root directory.
+    ///
+    /// # Errors
+    /// - `FsErr::NotFound` if the directory does not exist
+    /// - `FsErr::NotDirectory` if the entry is a file
+    pub fn read_dir(
+        &self,
+        name: &str,
+    ) -> Result<impl Iterator<Item = &FileEntry<MAX_NAME_LEN>>, FsErr> {
+        let id = self.find_dir_id(name)?;
+        Ok(self.entries.iter().filter(move |f| f.parent == id))
+    }
+
+    // Compaction
+
+    /// Defragment storage by sliding every extent toward page 0.
+    ///
+    /// Afterwards all free pages form a single contiguous run at the end of storage, so any
+    /// allocation that fits in the free space can be made contiguously. Extents of the same
+    /// file that end up adjacent are merged.
+    ///
+    /// File contents are preserved; only their location in storage changes.
+    pub fn compact(&mut self) {
+        while !self.compact_step(usize::MAX) {}
+    }
+
+    /// Perform a bounded amount of compaction work.
+    ///
+    /// Moves extents toward page 0, lowest first, until roughly `max_pages` pages have been
+    /// copied. At least one extent is moved per call, so progress is guaranteed even if that
+    /// extent is larger than `max_pages`.
+    ///
+    /// Intended to be called repeatedly (e.g. from an idle loop) until it reports completion.
+    ///
+    /// # Returns
+    /// - `true` if storage is fully compacted
+    /// - `false` if more work remains
+    pub fn compact_step(&mut self, max_pages: usize) -> bool {
+        let mut moved_pages = 0;
+
+        loop {
+            // First hole in storage. All pages before it are in use.
+            let Some(hole) = self.pages().next_free(0) else {
+                return true;
+            };
+
+            // Lowest extent after the hole; everything in between is free.
+            let next = self
+                .entries
+                .iter()
+                .enumerate()
+                .flat_map(|(file, entry)| {
+                    entry
+                        .extents
+                        .iter()
+                        .enumerate()
+                        .map(move |(ext, extent)| (file, ext, *extent))
+                })
+                .filter(|(_, _, extent)| extent.start_page() > hole)
+                .min_by_key(|(_, _, extent)| extent.start_page());
+            let Some((file, ext, extent)) = next else {
+                return true;
+            };
+
+            if moved_pages > 0 && moved_pages + extent.len_pages() > max_pages {
+                return false;
+            }
+
+            let page_size = self.page_size();
+            let old_start = extent.start_page() * page_size;
+            let old_range = old_start..old_start + extent.len_pages() * page_size;
+            self.storage.copy_within(old_range, hole * page_size);
+
+            self.mark_pages(extent.start_page(), extent.len_pages(), false);
+            self.mark_pages(hole, extent.len_pages(), true);
+            self.mark_dirty(hole, extent.len_pages());
+            self.entries[file].extents[ext].set_start_page(hole);
+            self.merge_extents(file);
+
+            moved_pages += extent.len_pages();
+        }
     }
 
     // Dump / Restore
     const fn serialized_header_size() -> usize {
         5  // "MEMFS"
         + 1  // version
+        + 1  // minor version
+        + 2  // header length (u16)
         + 4  // page size (u32)
         + 4  // num_pages (u32)
+        + 4  // snapshot id (u32)
         + 4 // entry_count (u32)
     }
 
@@ -833,70 +2107,125 @@ impl<'a, const STORAGE_SIZE: usize, const PAGE_SIZE: usize> MemoryFs<'a, STORAGE
         + 4 // checksum (u32)
     }
 
+    const fn serialized_metadata_max_size() -> usize {
+        Self::serialized_header_size()
+        + MAX_FILES * FileEntry::<MAX_NAME_LEN>::serialized_max_size()
+        + 4 // storage_len (u32)
+        + Self::serialized_footer_size()
+    }
+
     /// Return the maximum serialized size of a filesystem dump.
     ///
-    /// This is an upper bound that includes header, maximum number of entries, raw storage bytes,
-    /// and footer (magic, total length, checksum).
+    /// This is an upper bound that includes header, maximum number of entries, a completely
+    /// used storage, and footer (magic, total length, checksum).
+    /// For runtime-sized storage use `dump_max_size` instead, and `dump_size` for the size of
+    /// the current contents.
     pub const fn serialized_max_size() -> usize {
+        Self::serialized_metadata_max_size() + STORAGE_SIZE
+    }
+
+    /// Like `serialized_max_size`, but for the storage of this filesystem. Also works for
+    /// runtime-sized storage.
+    pub fn dump_max_size(&self) -> usize {
+        Self::serialized_metadata_max_size() + self.storage.len()
+    }
+
+    /// Exact number of bytes `dump` writes for the current contents.
+    pub fn dump_size(&self) -> usize {
+        let entries: usize = self.entries.iter().map(|f| 2 + f.serialized_size()).sum();
         Self::serialized_header_size()
-        + MAX_NUM_FILES * FileEntry::serialized_max_size()
-        + 4 // storage_len (u32)
-        + STORAGE_SIZE
-        + Self::serialized_footer_size()
+            + entries
+            + 4 // data_len (u32)
+            + self.used_data_len()
+            + Self::serialized_footer_size()
+    }
+
+    // Bytes of storage covered by extents, the data section of a dump.
+    fn used_data_len(&self) -> usize {
+        let pages: usize = self
+            .entries
+            .iter()
+            .flat_map(|f| &f.extents)
+            .map(|e| e.len_pages())
+            .sum();
+        pages * self.page_size()
     }
 
     /// Serialize the filesystem into a byte stream.
     ///
     /// The dump includes:
-    /// - header (magic/version/page size/num pages)
-    /// - file table (name, size, flags, extent start/len)
-    /// - raw storage bytes
+    /// - header (magic/version/minor version/page size/num pages/snapshot id, see `checkpoint`)
+    /// - entry table (name, id, parent directory, kind, size, flags, checksum, extent list)
+    /// - the pages of every extent, in entry table order; free pages are left out
     /// - footer (magic, total length, CRC32 checksum)
     ///
     /// The checksum covers everything except the footer itself.
-    pub fn dump<W: FnMut(&[u8])>(&self, mut write: W) -> Result<(), FsErr> {
-        let crc = Crc::<u32, NoTable>::new(&CRC_32_CKSUM);
-        let mut digest = crc.digest();
-        let mut total_len: u32 = 0;
+    pub fn dump<W: FnMut(&[u8])>(&self, write: W) -> Result<(), FsErr> {
+        self.dump_with(write, false)
+    }
+
+    /// Like `dump`, but compresses everything after the magic and version with LZSS.
+    ///
+    /// A flag in the version byte marks the stream as compressed, `restore` detects it. The
+    /// footer stays uncompressed; its length and checksum cover the decompressed stream.
+    /// Compression needs about 6 KiB of stack, decompression 1 KiB.
+    ///
+    /// Data that does not compress, such as encrypted or already compressed files, grows by
+    /// up to an eighth. Use `dump` for filesystems that mostly hold such data.
+    pub fn dump_compressed<W: FnMut(&[u8])>(&self, write: W) -> Result<(), FsErr> {
+        self.dump_with(write, true)
+    }
+
+    fn dump_with<W: FnMut(&[u8])>(&self, mut write: W, compress: bool) -> Result<(), FsErr> {
+        let mut digest = CHECKSUM.digest();
+
+        // Magic and version, never compressed
+        let mut head = *b"MEMFS\0";
+        head[5] = if compress {
+            DUMP_VERSION | DUMP_COMPRESSED
+        } else {
+            DUMP_VERSION
+        };
+        digest.update(&head);
+        write(&head);
+        let mut total_len = head.len() as u32;
+        let mut encoder = compress.then(Encoder::new);
 
         {
             let mut write = |bytes: &[u8]| {
                 digest.update(bytes);
-                write(bytes);
+                match &mut encoder {
+                    Some(encoder) => encoder.write(bytes, &mut write),
+                    None => write(bytes),
+                }
                 total_len = total_len.wrapping_add(bytes.len() as u32);
             };
 
-            // Header
-            write(b"MEMFS"); // Magic
-            write(&[2u8]); // Version
-            write(&(PAGE_SIZE as u32).to_le_bytes());
+            write(&[DUMP_MINOR_VERSION]);
+            write(&DUMP_HEADER_LEN.to_le_bytes());
+            write(&(self.page_size() as u32).to_le_bytes());
 
-            let num_pages: u32 = Self::num_pages() as u32;
+            let num_pages: u32 = self.num_pages() as u32;
             write(&num_pages.to_le_bytes());
+            write(&self.snapshot.to_le_bytes());
 
             // Entries
             let entry_count: u32 = self.entries.len() as u32;
             write(&entry_count.to_le_bytes());
 
-            for file in &self.entries {
-                let name_bytes = file.name.as_str().as_bytes();
-                let name_len: u16 = name_bytes
-                    .len()
-                    .try_into()
-                    .map_err(|_| FsErr::FileNameInvalid("Invalid filename"))?;
-                write(&name_len.to_le_bytes());
-                write(name_bytes);
-
-                write(&(file.size as u32).to_le_bytes());
-                write(&file.flags.bits().to_le_bytes());
-                write(&(file.extent.map_or(0, |ext| ext.start_page) as u32).to_le_bytes());
-                write(&(file.extent.map_or(0, |ext| ext.len_pages) as u32).to_le_bytes());
-            }
+            Self::dump_entries(&self.entries, true, &mut write)?;
 
             // Data
-            let storage_len: u32 = self.storage.len() as u32;
-            write(&storage_len.to_le_bytes());
-            write(self.storage);
+            let page_size = self.page_size();
+            let data_len: u32 = self.used_data_len() as u32;
+            write(&data_len.to_le_bytes());
+            for extent in self.entries.iter().flat_map(|f| &f.extents) {
+                let start = extent.start_page() * page_size;
+                write(&self.storage[start..start + extent.len_pages() * page_size]);
+            }
+        }
+        if let Some(encoder) = encoder {
+            encoder.finish(&mut write);
         }
 
         // Footer
@@ -911,256 +2240,600 @@ impl<'a, const STORAGE_SIZE: usize, const PAGE_SIZE: usize> MemoryFs<'a, STORAGE
     ///
     /// This validates:
     /// - header magic/version
-    /// - page size and number of pages
     /// - entry table sanity (sizes, extents, bounds)
     /// - footer magic, total length, and CRC32 checksum
     ///
-    /// The restore operation requires the filesystem to be empty.
+    /// Compressed streams written by `dump_compressed` are decompressed on the fly.
+    /// Pages that are not used by any file are zeroed. Dumps of older format versions, and of
+    /// newer minor versions of the current one, are accepted as well.
+    ///
+    /// A dump with a different page size or number of pages is re-laid out: every file is
+    /// copied into a single extent, packed from the start of storage. Reserved capacity beyond
+    /// the file size is not kept.
+    ///
+    /// The restore operation requires the filesystem to be empty. If it fails, the filesystem
+    /// is left empty again, so the restore can be retried.
     ///
     /// # Errors
     /// - `FsErr::InvalidOp` if the filesystem already contains entries
-    /// - `FsErr::Corrupt` if the stream is malformed, inconsistent, or checksum validation fails
-    pub fn restore<R>(&mut self, mut read: R) -> Result<(), FsErr>
+    /// - `FsErr::NoSpace` if the files of a dump with a different page layout do not fit
+    /// - `FsErr::Restore` if the stream is malformed, inconsistent, or checksum validation
+    ///   fails; the error tells which check failed and at which byte of the stream
+    /// - `FsErr::Restore` with `RestoreCheck::Truncated` if `read` fails, for example when the
+    ///   stream ends early
+    pub fn restore<R>(&mut self, read: R) -> Result<(), FsErr>
+    where
+        R: FnMut(&mut [u8]) -> Result<(), FsErr>,
+    {
+        if !self.entries.is_empty() {
+            return Err(FsErr::InvalidOp);
+        }
+        self.restore_stream(read)
+            .inspect_err(|_| self.clear_metadata())
+    }
+
+    fn restore_stream<R>(&mut self, mut read: R) -> Result<(), FsErr>
     where
         R: FnMut(&mut [u8]) -> Result<(), FsErr>,
     {
-        let crc = Crc::<u32, NoTable>::new(&CRC_32_CKSUM);
-        let mut digest = crc.digest();
-        let mut total_len: u32 = 0;
+        // A failing reader usually means the stream ended early, e.g. an interrupted upload.
+        let mut read = |buf: &mut [u8]| read(buf).map_err(|_| RestoreCheck::Truncated.fail());
+        let mut digest = CHECKSUM.digest();
+
+        let mut head = [0u8; 6];
+        read(&mut head)?;
+        digest.update(&head);
+        let total_len = Cell::new(head.len() as u32);
+        let (version, compressed) = dump_version(&head)?;
+        let mut decoder = compressed.then(Decoder::new);
 
         {
             let mut read = |buf: &mut [u8]| -> Result<(), FsErr> {
-                read(buf)?;
+                match &mut decoder {
+                    Some(decoder) => decoder.read(buf, &mut read)?,
+                    None => read(buf)?,
+                }
                 digest.update(buf);
-                total_len = total_len.wrapping_add(buf.len() as u32);
+                total_len.set(total_len.get().wrapping_add(buf.len() as u32));
                 Ok(())
             };
+            self.restore_contents(version, &mut read)
+                .map_err(|err| err.at(total_len.get() as usize))?;
+        }
 
-            let mut magic = [0u8; 5];
-            let mut version = [0u8; 1];
-
-            read(&mut magic)?;
-            read(&mut version)?;
+        read_footer(&mut read, total_len.get(), digest.finalize())
+    }
 
-            // Validate Header
-            if &magic != b"MEMFS" || version[0] != 2 {
-                return Err(FsErr::Corrupt);
+    /// Read everything of a `version` dump between the version and the footer.
+    fn restore_contents(
+        &mut self,
+        version: u8,
+        read: &mut impl FnMut(&mut [u8]) -> Result<(), FsErr>,
+    ) -> Result<(), FsErr> {
+        let header = read_dump_header(version, read)?;
+        self.restore_metadata(version, &header, read, false)?;
+
+        // Storage data
+        let data_len = read_u32(read)? as usize;
+        if !self.same_layout(&header) {
+            self.relayout_data(version, &header, data_len, read)?;
+        } else if version >= 6 {
+            if data_len != self.used_data_len() {
+                return Err(RestoreCheck::DataLength.fail());
+            }
+            self.storage.fill(0);
+            let page_size = self.page_size();
+            for index in 0..self.entries.len() {
+                for extent in self.entries[index].extents.clone() {
+                    let start = extent.start_page() * page_size;
+                    read(&mut self.storage[start..start + extent.len_pages() * page_size])?;
+                }
+            }
+        } else {
+            if data_len != self.storage.len() {
+                return Err(RestoreCheck::DataLength.fail());
             }
+            read(&mut self.storage[..data_len])?;
+        }
 
-            // Validate Sizes
-            let mut page_size = [0u8; size_of::<u32>()];
-            read(&mut page_size)?;
-            let page_size = u32::from_le_bytes(page_size);
-            if page_size as usize != PAGE_SIZE {
-                return Err(FsErr::Corrupt);
+        if version < 3 {
+            for index in 0..self.entries.len() {
+                self.update_checksum(index);
             }
+        }
+        Ok(())
+    }
+
+    /// Read the data section of a dump whose page layout differs from this filesystem, and
+    /// give each file a single extent, packed from page 0.
+    ///
+    /// The entries hold their extents in the dump's pages, see `restore_metadata`.
+    fn relayout_data(
+        &mut self,
+        version: u8,
+        header: &DumpHeader,
+        data_len: usize,
+        read: &mut impl FnMut(&mut [u8]) -> Result<(), FsErr>,
+    ) -> Result<(), FsErr> {
+        let (src_page_size, page_size) = (header.page_size, self.page_size());
 
-            let mut num_pages = [0u8; size_of::<u32>()];
-            let mut num_entries = [0u8; size_of::<u32>()];
+        let src_pages = if version >= 6 {
+            let pages = self.entries.iter().flat_map(|f| &f.extents);
+            pages.map(|e| e.len_pages()).sum()
+        } else {
+            header.num_pages
+        };
+        if src_pages.checked_mul(src_page_size) != Some(data_len) {
+            return Err(RestoreCheck::DataLength.fail());
+        }
+        let in_data = |e: &Extent| {
+            (e.start_page().checked_add(e.len_pages())).is_some_and(|end| end <= src_pages)
+        };
+        if !self.entries.iter().flat_map(|f| &f.extents).all(in_data) {
+            return Err(RestoreCheck::Extent.fail());
+        }
 
-            read(&mut num_pages)?;
-            read(&mut num_entries)?;
+        let mut starts: Vec<usize, MAX_FILES> = Vec::new();
+        let mut next_page = 0;
+        for entry in &self.entries {
+            starts.push(next_page).ok();
+            next_page += entry.size.div_ceil(page_size);
+        }
+        if next_page > self.num_pages() {
+            return Err(FsErr::NoSpace);
+        }
 
-            let num_pages = u32::from_le_bytes(num_pages);
-            let num_entries = u32::from_le_bytes(num_entries);
+        // Every extent as (source page, pages, file, file offset), grouped by its position
+        // in the file and sorted by source page, so the data is mapped in a single pass.
+        let mut runs: [Vec<(usize, usize, usize, usize), MAX_FILES>; MAX_EXTENTS_PER_FILE] =
+            Default::default();
+        for (index, entry) in self.entries.iter().enumerate() {
+            let mut offset = 0;
+            for (run, extent) in runs.iter_mut().zip(&entry.extents) {
+                run.push((extent.start_page(), extent.len_pages(), index, offset))
+                    .ok();
+                offset += extent.len_pages() * src_page_size;
+            }
+        }
+        for run in &mut runs {
+            run.sort_unstable();
+        }
+        let next_run = |cursors: &mut [usize; MAX_EXTENTS_PER_FILE]| {
+            let (k, run) = (runs.iter().enumerate())
+                .filter_map(|(k, run)| run.get(cursors[k]).map(|&run| (k, run)))
+                .min_by_key(|(_, run)| run.0)?;
+            cursors[k] += 1;
+            Some(run)
+        };
 
-            if num_pages as usize != Self::num_pages() {
-                return Err(FsErr::Corrupt);
+        // A page in two extents would be copied into both files.
+        let mut cursors = [0; MAX_EXTENTS_PER_FILE];
+        let mut end = 0;
+        while let Some((start, len, ..)) = next_run(&mut cursors) {
+            if start < end {
+                return Err(RestoreCheck::Extent.fail());
             }
+            end = start + len;
+        }
 
-            if !self.entries.is_empty() {
-                return Err(FsErr::InvalidOp);
+        self.storage.fill(0);
+        let mut cursors = [0; MAX_EXTENTS_PER_FILE];
+        let mut src_page = 0;
+        while let Some((start, len, index, offset)) = next_run(&mut cursors) {
+            skip(read, (start - src_page) * src_page_size)?;
+
+            // Copy the part within the file size, skip the rest.
+            let run_len = len * src_page_size;
+            let copied = self.entries[index].size.saturating_sub(offset).min(run_len);
+            let dest = starts[index] * page_size + offset;
+            read(&mut self.storage[dest..dest + copied])?;
+            skip(read, run_len - copied)?;
+            src_page = start + len;
+        }
+        skip(read, (src_pages - src_page) * src_page_size)?;
+
+        for (index, start) in starts.into_iter().enumerate() {
+            let len_pages = self.entries[index].size.div_ceil(page_size);
+            let mut extents = Extents::new();
+            if len_pages > 0 {
+                extents.push(Extent::new(start, len_pages)).ok();
             }
+            self.entries[index].extents = extents;
+        }
+        self.claim_pages()
+    }
 
-            for _ in 0..num_entries {
-                let mut name_len = [0u8; size_of::<u16>()];
-                let mut name_bytes = [0u8; MAX_FILE_NAME_LENGTH];
+    /// Write the entry table in the current dump format, without the entry count.
+    ///
+    /// With `prefixed` every entry starts with its length, as in version 8; otherwise the
+    /// entries are in the version 5 format.
+    fn dump_entries(
+        entries: &[FileEntry<MAX_NAME_LEN>],
+        prefixed: bool,
+        write: &mut impl FnMut(&[u8]),
+    ) -> Result<(), FsErr> {
+        for file in entries {
+            if prefixed {
+                write(&(file.serialized_size() as u16).to_le_bytes());
+            }
+            let name_bytes = file.name.as_str().as_bytes();
+            let name_len: u16 = name_bytes
+                .len()
+                .try_into()
+                .map_err(|_| FsErr::FileNameInvalid("Invalid filename"))?;
+            write(&name_len.to_le_bytes());
+            write(name_bytes);
+
+            write(&file.id.to_le_bytes());
+            write(&file.parent.to_le_bytes());
+            write(&[file.is_dir as u8]);
+            write(&(file.size as u32).to_le_bytes());
+            write(&file.flags.bits().to_le_bytes());
+            write(&file.crc.to_le_bytes());
+            write(&(file.extents.len() as u16).to_le_bytes());
+            for extent in &file.extents {
+                write(&extent.start.to_le_bytes());
+                write(&extent.len.to_le_bytes());
+            }
+        }
+        Ok(())
+    }
 
-                read(&mut name_len)?;
-                let name_len = u16::from_le_bytes(name_len) as usize;
-                if name_len == 0 || name_len > MAX_FILE_NAME_LENGTH {
-                    return Err(FsErr::Corrupt);
+    // Page allocator functions
+    // Record that the contents of these pages changed, for `dump_delta`.
+    fn mark_dirty(&mut self, start: usize, len: usize) {
+        for (index, mask) in Self::page_masks(start, len) {
+            self.dirty_pages[index] |= mask;
+        }
+    }
+    fn rebuild_page_bitmap(&mut self) {
+        self.page_bitmap.iter_mut().for_each(|word| *word = 0);
+        for entry in &self.entries {
+            for extent in &entry.extents {
+                for (index, mask) in Self::page_masks(extent.start_page(), extent.len_pages()) {
+                    self.page_bitmap[index] |= mask;
                 }
-                read(&mut name_bytes[..name_len])?;
+            }
+        }
+    }
 
-                let name = str::from_utf8(&name_bytes[..name_len]).map_err(|_| FsErr::Corrupt)?;
+    // Contiguous run search, the location is chosen by the allocator.
+    fn find_free_pages(&mut self, need_pages: usize) -> Option<Extent> {
+        assert_ne!(need_pages, 0);
 
-                let mut file_size = [0u8; size_of::<u32>()];
-                let mut file_flags = [0u8; size_of::<u32>()];
-                let mut file_extent_start = [0u8; size_of::<u32>()];
-                let mut file_extent_len = [0u8; size_of::<u32>()];
+        let pages = PageMap::new(&self.page_bitmap, self.num_pages());
+        let run = self.allocator.find_run(&pages, need_pages).filter(|run| {
+            run.len() >= need_pages
+                && run.end <= self.num_pages()
+                && pages.free_run_len(run.start, run.len()) == run.len()
+        })?;
 
-                read(&mut file_size)?;
-                read(&mut file_flags)?;
-                read(&mut file_extent_start)?;
-                read(&mut file_extent_len)?;
+        Some(Extent::new(run.start, run.len()))
+    }
+    // Split an allocation over the largest free runs if no single run is large enough.
+    fn find_free_extents(
+        &mut self,
+        need_pages: usize,
+        max_extents: usize,
+    ) -> Result<Extents, FsErr> {
+        assert_ne!(need_pages, 0);
 
-                let file_size = u32::from_le_bytes(file_size);
-                let file_flags = u32::from_le_bytes(file_flags);
-                let file_extent_start = u32::from_le_bytes(file_extent_start) as usize;
-                let file_extent_len = u32::from_le_bytes(file_extent_len) as usize;
+        if max_extents == 0 {
+            return Err(FsErr::TooManyExtents);
+        }
 
-                // Sanity checks
-                if (file_size == 0) != (file_extent_len == 0) {
-                    return Err(FsErr::Corrupt);
-                }
-                let cap = file_extent_len
-                    .checked_mul(PAGE_SIZE)
-                    .ok_or(FsErr::Corrupt)?;
-                if (file_size as usize) > cap {
-                    return Err(FsErr::Corrupt);
-                }
-                let end = file_extent_start
-                    .checked_add(file_extent_len)
-                    .ok_or(FsErr::Corrupt)?;
-                if end > num_pages as usize {
-                    return Err(FsErr::Corrupt);
-                }
+        let mut extents = Extents::new();
+        if let Some(extent) = self.find_free_pages(need_pages) {
+            extents.push(extent).ok();
+            return Ok(extents);
+        }
 
-                let extent = if file_extent_len > 0 {
-                    self.mark_pages(file_extent_start, file_extent_len, true);
-                    Some(Extent {
-                        start_page: file_extent_start,
-                        len_pages: file_extent_len,
-                    })
-                } else {
-                    None
-                };
+        let pages = self.pages();
+        if pages.free_count() < need_pages {
+            return Err(FsErr::NoSpace);
+        }
+        if max_extents == 1 {
+            return Err(FsErr::WouldFragment);
+        }
 
-                self.entries
-                    .push(FileEntry {
-                        name: heapless::String::from_str(name).map_err(|_| FsErr::Corrupt)?,
-                        size: file_size as usize,
-                        flags: FileFlags::from_bits_truncate(file_flags),
-                        extent,
-                    })
-                    .map_err(|_| FsErr::Corrupt)?;
+        // Use the largest runs, so small holes do not use up the extents of the file.
+        for run in pages.free_runs(0) {
+            let extent = Extent::new(run.start, run.len());
+            let pos = extents
+                .iter()
+                .position(|other| other.len_pages() < extent.len_pages())
+                .unwrap_or(extents.len());
+            if pos < max_extents {
+                if extents.len() == max_extents {
+                    extents.pop();
+                }
+                extents.insert(pos, extent).ok();
             }
+        }
 
-            // Storage data
-            let mut storage_len = [0u8; size_of::<u32>()];
-            read(&mut storage_len)?;
-            let storage_len = u32::from_le_bytes(storage_len) as usize;
-            if storage_len != STORAGE_SIZE {
-                return Err(FsErr::Corrupt);
+        let mut remaining = need_pages;
+        let mut used = 0;
+        for extent in &mut extents {
+            extent.set_len_pages(extent.len_pages().min(remaining));
+            remaining -= extent.len_pages();
+            used += 1;
+            if remaining == 0 {
+                break;
             }
-            read(&mut self.storage[..storage_len])?;
         }
+        if remaining > 0 {
+            return Err(FsErr::TooManyExtents);
+        }
+        extents.truncate(used);
+        extents.sort_unstable_by_key(|extent| extent.start_page());
+        Ok(extents)
+    }
 
-        let mut footer_magic = [0u8; 8];
-        let mut footer_len = [0u8; size_of::<u32>()];
-        let mut footer_crc = [0u8; size_of::<u32>()];
-
-        read(&mut footer_magic)?;
-        read(&mut footer_len)?;
-        read(&mut footer_crc)?;
+    // Helper functions
 
-        if &footer_magic != b"MEMFSEND" {
-            return Err(FsErr::Corrupt);
+    /// Check for invalid or duplicate names within directory `parent`.
+    fn validate_file_name(
+        &self,
+        parent: u32,
+        name: String<MAX_NAME_LEN>,
+    ) -> Result<String<MAX_NAME_LEN>, FsErr> {
+        // Check for invalid or duplicate names.
+        if name.is_empty() || name.contains(" ") {
+            return Err(FsErr::FileNameInvalid(
+                "File name cannot be empty or a whitespace.",
+            ));
         }
-
-        let expected_len = u32::from_le_bytes(footer_len);
-        if expected_len != total_len {
-            return Err(FsErr::Corrupt);
+        if name == "." || name == ".." {
+            return Err(FsErr::FileNameInvalid("File name cannot be '.' or '..'."));
         }
-
-        let expected_crc = u32::from_le_bytes(footer_crc);
-        let actual_crc = digest.finalize();
-        if expected_crc != actual_crc {
-            return Err(FsErr::Corrupt);
+        if self.find_child(parent, &name).is_some() {
+            return Err(FsErr::Duplicate);
         }
 
-        Ok(())
+        Ok(name)
     }
 
-    // Page allocator functions
-    fn page_is_free(&self, page: usize) -> bool {
-        (self.page_bitmap[page / 32] & (1 << (page % 32))) == 0
+    fn has_children(&self, dir: u32) -> bool {
+        let pos = self
+            .name_index
+            .partition_point(|&index| self.entries[index].parent < dir);
+        self.name_index
+            .get(pos)
+            .is_some_and(|&index| self.entries[index].parent == dir)
     }
-    fn mark_pages(&mut self, start: usize, len: usize, used: bool) {
-        for page in start..start + len {
-            let page_bit = &mut self.page_bitmap[page / 32];
-            let bit = 1 << (page % 32);
-            if used {
-                *page_bit |= bit;
-            } else {
-                *page_bit &= !bit;
+
+    /// Remove entry `index` from the table and the name index.
+    fn remove_entry(&mut self, index: usize) -> FileEntry<MAX_NAME_LEN> {
+        self.name_index.retain(|&i| i != index);
+        for i in self.name_index.iter_mut().filter(|i| **i > index) {
+            *i -= 1;
+        }
+        self.entries.remove(index)
+    }
+
+    /// Give entry `index` a new parent and name, keeping the name index sorted.
+    fn rename_entry(&mut self, index: usize, parent: u32, name: String<MAX_NAME_LEN>) {
+        self.name_index.retain(|&i| i != index);
+        self.entries[index].name = name;
+        self.entries[index].parent = parent;
+
+        let (Ok(pos) | Err(pos)) = self.name_index_search(parent, &self.entries[index].name);
+        self.name_index.insert(pos, index).ok();
+    }
+
+    /// Copy `data` into file `index` at logical `offset`, across extent boundaries.
+    fn copy_in(&mut self, index: usize, offset: usize, data: &[u8]) {
+        let mut copied = 0;
+        for range in Self::spans(
+            &self.entries[index].extents,
+            self.page_size(),
+            offset,
+            data.len(),
+        ) {
+            let len = range.len();
+            let first_page = range.start / self.page_size();
+            let end_page = range.end.div_ceil(self.page_size());
+            for (word, mask) in Self::page_masks(first_page, end_page - first_page) {
+                self.dirty_pages[word] |= mask;
             }
+            self.storage[range].copy_from_slice(&data[copied..copied + len]);
+            copied += len;
         }
     }
 
-    // First-fit run search.
-    fn find_free_pages(&self, need_pages: usize) -> Option<Extent> {
-        assert_ne!(need_pages, 0);
+    /// Give file `index` `extra_pages` more capacity according to `growth`.
+    ///
+    /// On failure the file and page bitmap are left untouched.
+    fn grow(&mut self, index: usize, extra_pages: usize, growth: Growth) -> Result<(), FsErr> {
+        let Some(&last) = self.entries[index].extents.last() else {
+            // Nothing allocated yet, any free run will do.
+            let max_extents = if growth == Growth::Fragment {
+                self.entries[index].max_extents()
+            } else {
+                1
+            };
+            let extents = self.find_free_extents(extra_pages, max_extents)?;
+            for extent in &extents {
+                self.mark_pages(extent.start_page(), extent.len_pages(), true);
+            }
+            self.entries[index].extents = extents;
+            return Ok(());
+        };
 
-        let mut run_start = None; //TODO: Use previous alloction marker, potentially speeds up search.
-        let mut run_len = 0;
+        // Can we extend the last extent?
+        let next_page = last.start_page() + last.len_pages();
+        let neighbour_pages = self.pages().free_run_len(next_page, extra_pages);
+        if neighbour_pages == extra_pages {
+            self.mark_pages(next_page, neighbour_pages, true);
+            self.extend_last_extent(index, neighbour_pages);
+            return Ok(());
+        }
 
-        for page in 0..Self::num_pages() {
-            if self.page_is_free(page) {
-                if run_start.is_none() {
-                    run_start = Some(page)
+        match growth {
+            Growth::InPlace => Err(FsErr::WouldFragment),
+            Growth::Repack => {
+                let required_pages = self.entries[index].capacity_pages() + extra_pages;
+                let new_extent = self
+                    .find_free_pages(required_pages)
+                    .ok_or(FsErr::WouldFragment)?;
+                self.relocate(index, new_extent);
+                Ok(())
+            }
+            Growth::Fragment => {
+                // Keep the file contiguous, and so readable with `read`, while storage allows.
+                let required_pages = self.entries[index].capacity_pages() + extra_pages;
+                if let Some(new_extent) = self.find_free_pages(required_pages) {
+                    self.relocate(index, new_extent);
+                    return Ok(());
                 }
-                run_len += 1;
-                if run_len >= need_pages {
-                    return Some(Extent {
-                        start_page: run_start.unwrap(),
-                        len_pages: run_len,
-                    });
+
+                // Claim the neighbouring pages first so they are not handed out twice.
+                self.mark_pages(next_page, neighbour_pages, true);
+
+                let free_slots =
+                    self.entries[index].max_extents() - self.entries[index].extents.len();
+                match self.find_free_extents(extra_pages - neighbour_pages, free_slots) {
+                    Ok(extents) => {
+                        for extent in &extents {
+                            self.mark_pages(extent.start_page(), extent.len_pages(), true);
+                        }
+                        self.extend_last_extent(index, neighbour_pages);
+                        for extent in extents {
+                            self.entries[index].extents.push(extent).ok();
+                        }
+                        Ok(())
+                    }
+                    Err(e) => {
+                        self.mark_pages(next_page, neighbour_pages, false);
+                        Err(e)
+                    }
                 }
-            } else {
-                run_start = None;
-                run_len = 0;
             }
         }
-        None
     }
-    // Check if next pages are free. Early return if not the case.
-    fn check_neighbour_pages_free(&self, start: usize, need_pages: usize) -> Option<Extent> {
-        assert!(start <= Self::num_pages());
-        assert_ne!(need_pages, 0);
-        let mut run_len = 0;
 
-        for page in start..Self::num_pages() {
-            if self.page_is_free(page) {
-                run_len += 1;
+    /// Merge logically consecutive extents of file `index` that are also adjacent in storage.
+    fn merge_extents(&mut self, index: usize) {
+        let extents = &mut self.entries[index].extents;
+        let mut i = 1;
+        while i < extents.len() {
+            let prev = extents[i - 1];
+            if prev.start_page() + prev.len_pages() == extents[i].start_page() {
+                let next = extents.remove(i);
+                extents[i - 1].set_len_pages(prev.len_pages() + next.len_pages());
             } else {
-                return None;
-            }
-            if run_len >= need_pages {
-                return Some(Extent {
-                    start_page: start,
-                    len_pages: run_len,
-                });
+                i += 1;
             }
         }
-        None
     }
 
-    // Helper functions
+    fn extend_last_extent(&mut self, index: usize, pages: usize) {
+        if let Some(last) = self.entries[index].extents.last_mut() {
+            last.set_len_pages(last.len_pages() + pages);
+        }
+    }
 
-    /// Check for invalid or duplicate names.
-    fn validate_file_name(
-        &self,
-        name: String<MAX_FILE_NAME_LENGTH>,
-    ) -> Result<String<MAX_FILE_NAME_LENGTH>, FsErr> {
-        // Check for invalid or duplicate names.
-        if name.is_empty() || name.contains(" ") {
-            return Err(FsErr::FileNameInvalid(
-                "File name cannot be empty or a whitespace.",
-            ));
+    /// Move file `index` into `new_extent`, which must be free and large enough.
+    fn relocate(&mut self, index: usize, new_extent: Extent) {
+        self.mark_pages(new_extent.start_page(), new_extent.len_pages(), true);
+        self.mark_dirty(new_extent.start_page(), new_extent.len_pages());
+
+        let mut new_extents = Extents::new();
+        new_extents.push(new_extent).ok();
+        let old_extents = core::mem::replace(&mut self.entries[index].extents, new_extents);
+
+        // Move exsisting bytes
+        let mut dest = new_extent.start_page() * self.page_size();
+        for range in Self::spans(&old_extents, self.page_size(), 0, self.entries[index].size) {
+            let len = range.len();
+            self.storage.copy_within(range, dest);
+            dest += len;
         }
-        if self.entries.iter().any(|f| f.name == name) {
-            return Err(FsErr::Duplicate);
+
+        for extent in &old_extents {
+            self.mark_pages(extent.start_page(), extent.len_pages(), false);
         }
+    }
 
-        Ok(name)
+    /// Copy file `index` to fresh pages if writing `len` bytes at `offset` would overwrite
+    /// pages that are protected by the running transaction.
+    ///
+    /// On failure the file and page bitmap are left untouched.
+    fn protect_pages(&mut self, index: usize, offset: usize, len: usize) -> Result<(), FsErr> {
+        if !self.in_transaction {
+            return Ok(());
+        }
+        let entry = &self.entries[index];
+        let touches_protected =
+            Self::spans(&entry.extents, self.page_size(), offset, len).any(|range| {
+                let first_page = range.start / self.page_size();
+                let end_page = range.end.div_ceil(self.page_size());
+                Self::page_masks(first_page, end_page - first_page)
+                    .any(|(index, mask)| mask & !self.dirty_pages[index] != 0)
+            });
+        if !touches_protected {
+            return Ok(());
+        }
+
+        let new_extents = self.find_free_extents(entry.capacity_pages(), entry.max_extents())?;
+        for extent in &new_extents {
+            self.mark_pages(extent.start_page(), extent.len_pages(), true);
+            self.mark_dirty(extent.start_page(), extent.len_pages());
+        }
+        let old_extents = core::mem::replace(&mut self.entries[index].extents, new_extents);
+
+        // Copy existing bytes, both sides may be fragmented.
+        let mut copied = 0;
+        for src in Self::spans(&old_extents, self.page_size(), 0, self.entries[index].size) {
+            let mut src_start = src.start;
+            for dest in Self::spans(
+                &self.entries[index].extents,
+                self.page_size(),
+                copied,
+                src.len(),
+            ) {
+                let len = dest.len();
+                self.storage
+                    .copy_within(src_start..src_start + len, dest.start);
+                src_start += len;
+            }
+            copied += src.len();
+        }
+
+        for extent in &old_extents {
+            self.mark_pages(extent.start_page(), extent.len_pages(), false);
+        }
+        Ok(())
     }
 
-    fn find_file_index(&self, name: &str) -> Result<usize, FsErr> {
-        match self.entries.iter().position(|f| f.name == name) {
-            Some(index) => Ok(index),
-            None => Err(FsErr::NotFound),
+    /// Shrink the allocation of file `index` to `keep_pages`, freeing everything after it.
+    fn release_pages(&mut self, index: usize, keep_pages: usize) {
+        let mut remaining = keep_pages;
+        let mut kept = 0;
+        let mut released = Extents::new();
+
+        for extent in self.entries[index].extents.iter_mut() {
+            if remaining >= extent.len_pages() {
+                remaining -= extent.len_pages();
+                kept += 1;
+            } else if remaining > 0 {
+                released
+                    .push(Extent::new(
+                        extent.start_page() + remaining,
+                        extent.len_pages() - remaining,
+                    ))
+                    .ok();
+                extent.set_len_pages(remaining);
+                remaining = 0;
+                kept += 1;
+            } else {
+                released.push(*extent).ok();
+            }
+        }
+        self.entries[index].extents.truncate(kept);
+
+        for extent in &released {
+            self.mark_pages(extent.start_page(), extent.len_pages(), false);
         }
     }
 
@@ -1168,21 +2841,34 @@ impl<'a, const STORAGE_SIZE: usize, const PAGE_SIZE: usize> MemoryFs<'a, STORAGE
 
     /// Print a list of files (debug helper).
     ///
-    /// Shows each file's name, size, and start offset (or `NO DATA` for empty files).
-    /// Only available when compiled with the `std` feature.
+    /// Shows the directory tree with each file's name, size, and start offset of every extent
+    /// (or `NO DATA` for empty files). Only available when compiled with the `std` feature.
     #[cfg(feature = "std")]
     pub fn list_files(&self) {
         println!("File entries:");
-        for entry in &self.entries {
-            if let Some(extent) = entry.extent {
-                println!(
-                    "\t{} ({} bytes @ {})",
+        self.list_dir(ROOT_ID, 1);
+    }
+
+    #[cfg(feature = "std")]
+    fn list_dir(&self, dir: u32, depth: usize) {
+        let indent = "\t".repeat(depth);
+        for entry in self.entries.iter().filter(|f| f.parent == dir) {
+            if entry.is_dir {
+                println!("{indent}{}/", entry.name);
+                self.list_dir(entry.id, depth + 1);
+            } else if let Some(first) = entry.extents.first() {
+                print!(
+                    "{indent}{} ({} bytes @ {}",
                     entry.name,
                     entry.size,
-                    extent.start_page * PAGE_SIZE
+                    first.start_page() * self.page_size()
                 );
+                for extent in entry.extents.iter().skip(1) {
+                    print!(", {}", extent.start_page() * self.page_size());
+                }
+                println!(")");
             } else {
-                println!("\t{} (NO DATA)", entry.name,);
+                println!("{indent}{} (NO DATA)", entry.name,);
             }
         }
     }
@@ -1193,7 +2879,7 @@ impl<'a, const STORAGE_SIZE: usize, const PAGE_SIZE: usize> MemoryFs<'a, STORAGE
     /// Only available when compiled with the `std` feature.
     #[cfg(feature = "std")]
     pub fn hex_dump(&self, start: usize, len: usize) {
-        let end = (start + len).min(STORAGE_SIZE);
+        let end = (start + len).min(self.storage.len());
         for (i, chunk) in self.storage[start..end].chunks(16).enumerate() {
             print!("{:#06x} | ", start + i * 16);
             for b in chunk {
@@ -0,0 +1,235 @@
+use core::cell::Cell;
+
+use crate::image::ImageHeader;
+use crate::{
+    CHECKSUM, DEFAULT_BITMAP_WORDS, DEFAULT_MAX_FILES, DEFAULT_MAX_NAME_LEN, FileEntry, FileSystem,
+    FirstFit, FsErr, MemoryFs, Metadata, ReadDir, RestoreCheck, bitmap_words, dump_version,
+    read_dump_header,
+};
+
+// A `MemoryFs` over the image itself; it has no methods that write to storage.
+type ImageFs<'a, const MAX_FILES: usize, const MAX_NAME_LEN: usize, const BITMAP_WORDS: usize> =
+    MemoryFs<'a, 0, 0, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, FirstFit, &'a [u8]>;
+
+/// A read-only filesystem over an immutable image, for example in memory-mapped flash.
+///
+/// The image is either a `dump` or an image created by `DynMemoryFs::format`. Only the entry
+/// table is parsed into RAM; `read` and `read_at` return slices into the image itself.
+/// Mutating operations of the `FileSystem` trait fail with `FsErr::ReadOnly`.
+///
+/// The parameters are those of `MemoryFs`; the image has to fit within them.
+pub struct MemoryFsRo<
+    'a,
+    const MAX_FILES: usize = DEFAULT_MAX_FILES,
+    const MAX_NAME_LEN: usize = DEFAULT_MAX_NAME_LEN,
+    const BITMAP_WORDS: usize = DEFAULT_BITMAP_WORDS,
+> {
+    fs: ImageFs<'a, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS>,
+}
+
+impl<'a, const MAX_FILES: usize, const MAX_NAME_LEN: usize, const BITMAP_WORDS: usize>
+    MemoryFsRo<'a, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS>
+{
+    /// Open a stream written by `MemoryFs::dump`, in any format version `restore` accepts.
+    ///
+    /// The whole image is checked against its checksum once, without copying it. Compressed
+    /// streams from `dump_compressed` cannot be read in place.
+    ///
+    /// # Errors
+    /// - `FsErr::Restore` if the image is malformed or fails its checksum; the error tells
+    ///   which check failed and at which byte of the image
+    /// - `FsErr::InvalidOp` if the image is compressed, or has more pages than the page bitmap
+    ///   can track
+    pub fn from_dump(dump: &'a [u8]) -> Result<Self, FsErr> {
+        // A `u32` at `offset`, which has to lie within `bytes`.
+        let field = |bytes: &[u8], offset: usize| -> Result<usize, FsErr> {
+            let bytes = bytes
+                .get(offset..offset + 4)
+                .ok_or(RestoreCheck::Truncated.fail().at(bytes.len()))?;
+            Ok(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+        };
+        // The checksum of a compressed dump covers the decompressed stream, check this first.
+        let head = dump
+            .first_chunk::<6>()
+            .ok_or(RestoreCheck::Truncated.fail().at(dump.len()))?;
+        let (version, compressed) = dump_version(head)?;
+        if compressed {
+            return Err(FsErr::InvalidOp);
+        }
+
+        let payload_len = dump
+            .len()
+            .checked_sub(16)
+            .ok_or(RestoreCheck::Truncated.fail().at(dump.len()))?;
+        // Footer: magic, total length, checksum.
+        let (payload, footer) = dump.split_at(payload_len);
+        if &footer[..8] != b"MEMFSEND" {
+            return Err(RestoreCheck::Footer.fail().at(payload_len));
+        }
+        if field(footer, 8)? != payload_len {
+            return Err(RestoreCheck::Length.fail().at(payload_len + 8));
+        }
+        if field(footer, 12)? != CHECKSUM.checksum(payload) as usize {
+            return Err(RestoreCheck::Checksum.fail().at(payload_len + 12));
+        }
+
+        let pos = Cell::new(head.len());
+        let mut read = |buf: &mut [u8]| {
+            let bytes = payload
+                .get(pos.get()..pos.get() + buf.len())
+                .ok_or(RestoreCheck::Truncated.fail())?;
+            buf.copy_from_slice(bytes);
+            pos.set(pos.get() + buf.len());
+            Ok(())
+        };
+        let at_pos = |err: FsErr| err.at(pos.get());
+        let header = read_dump_header(version, &mut read).map_err(at_pos)?;
+
+        // The data section follows the entry table, so the storage is attached afterwards.
+        let mut fs = Self::empty(header.page_size)?;
+        fs.restore_metadata(version, &header, &mut read, true)
+            .map_err(at_pos)?;
+        let data_len = field(payload, pos.get())?;
+        let data = payload.get(pos.get() + 4..).unwrap_or_default();
+        if data.len() != data_len {
+            return Err(RestoreCheck::DataLength.fail().at(pos.get()));
+        }
+        Self::attach(&mut fs, data).map_err(at_pos)?;
+        fs.claim_pages().map_err(at_pos)?;
+
+        if version < 3 {
+            for index in 0..fs.entries.len() {
+                fs.update_checksum(index);
+            }
+        }
+        Ok(Self { fs })
+    }
+
+    /// Open an image created by `DynMemoryFs::format` and written by `sync`.
+    ///
+    /// # Errors
+    /// - `FsErr::Restore` if the superblock is invalid or does not match its checksum
+    /// - `FsErr::InvalidOp` if the image has more pages than the page bitmap can track
+    pub fn from_image(image: &'a [u8]) -> Result<Self, FsErr> {
+        let header = ImageHeader::parse(image)?;
+        let (superblock, data) = image.split_at(header.data_offset);
+
+        let mut fs = Self::empty(header.page_size)?;
+        Self::attach(&mut fs, &data[..header.data_len])?;
+        fs.load_superblock(superblock, &header)?;
+        Ok(Self { fs })
+    }
+
+    // Filesystem without entries or storage, to be filled from the image.
+    fn empty(
+        page_size: usize,
+    ) -> Result<ImageFs<'a, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS>, FsErr> {
+        if page_size == 0 {
+            return Err(RestoreCheck::Header.fail());
+        }
+        Ok(ImageFs::new(&[], page_size, FirstFit))
+    }
+
+    // Use `storage` as the pages of `fs`.
+    fn attach(
+        fs: &mut ImageFs<'a, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS>,
+        storage: &'a [u8],
+    ) -> Result<(), FsErr> {
+        let page_size = fs.page_size();
+        if !storage.len().is_multiple_of(page_size) {
+            return Err(RestoreCheck::DataLength.fail());
+        }
+        let words = bitmap_words(storage.len(), page_size);
+        if words > BITMAP_WORDS {
+            return Err(FsErr::InvalidOp);
+        }
+        fs.storage = storage;
+        fs.page_bitmap.resize(words, 0).ok();
+        fs.dirty_pages.resize(words, 0).ok();
+        Ok(())
+    }
+
+    /// See `MemoryFs::read`. The slice points into the image.
+    pub fn read(&self, name: &str) -> Result<&[u8], FsErr> {
+        self.fs.read(name)
+    }
+
+    /// See `MemoryFs::read_into`.
+    pub fn read_into(&self, name: &str, buf: &mut [u8]) -> Result<usize, FsErr> {
+        self.fs.read_into(name, buf)
+    }
+
+    /// See `MemoryFs::read_at`. The slice points into the image.
+    pub fn read_at(&self, name: &str, offset: usize, len: usize) -> Result<&[u8], FsErr> {
+        self.fs.read_at(name, offset, len)
+    }
+
+    /// See `MemoryFs::read_at_into`.
+    pub fn read_at_into(&self, name: &str, offset: usize, buf: &mut [u8]) -> Result<usize, FsErr> {
+        self.fs.read_at_into(name, offset, buf)
+    }
+
+    /// See `MemoryFs::exists`.
+    pub fn exists(&self, name: &str) -> bool {
+        self.fs.exists(name)
+    }
+
+    /// See `MemoryFs::entries`.
+    pub fn entries(&self) -> impl Iterator<Item = &FileEntry<MAX_NAME_LEN>> {
+        self.fs.entries()
+    }
+}
+
+impl<const MAX_FILES: usize, const MAX_NAME_LEN: usize, const BITMAP_WORDS: usize> FileSystem
+    for MemoryFsRo<'_, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS>
+{
+    type Error = FsErr;
+    type ReadDir<'s>
+        = ReadDir<'s, MAX_NAME_LEN>
+    where
+        Self: 's;
+
+    fn create(&mut self, _path: &str, _data: &[u8]) -> Result<(), FsErr> {
+        Err(FsErr::ReadOnly)
+    }
+
+    fn read_into(&self, path: &str, buf: &mut [u8]) -> Result<usize, FsErr> {
+        self.fs.read_into(path, buf)
+    }
+
+    fn write(&mut self, _path: &str, _data: &[u8]) -> Result<(), FsErr> {
+        Err(FsErr::ReadOnly)
+    }
+
+    fn write_at(&mut self, _path: &str, _offset: usize, _data: &[u8]) -> Result<(), FsErr> {
+        Err(FsErr::ReadOnly)
+    }
+
+    fn append(&mut self, _path: &str, _data: &[u8]) -> Result<(), FsErr> {
+        Err(FsErr::ReadOnly)
+    }
+
+    fn truncate(&mut self, _path: &str, _new_size: usize) -> Result<(), FsErr> {
+        Err(FsErr::ReadOnly)
+    }
+
+    fn rename(&mut self, _path: &str, _new_path: &str) -> Result<(), FsErr> {
+        Err(FsErr::ReadOnly)
+    }
+
+    fn delete(&mut self, _path: &str) -> Result<(), FsErr> {
+        Err(FsErr::ReadOnly)
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.fs.exists(path)
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata, FsErr> {
+        self.fs.path_metadata(path)
+    }
+
+    fn read_dir(&self, path: &str) -> Result<ReadDir<'_, MAX_NAME_LEN>, FsErr> {
+        self.fs.dir_entries(path)
+    }
+}
@@ -0,0 +1,33 @@
+use core::ops::{Deref, DerefMut};
+
+#[cfg(feature = "alloc")]
+use alloc::boxed::Box;
+
+/// Backing bytes of a `MemoryFs`, either borrowed or (with `alloc`) owned.
+pub enum Storage<'a> {
+    Borrowed(&'a mut [u8]),
+    #[cfg(feature = "alloc")]
+    Owned(Box<[u8]>),
+}
+
+impl Deref for Storage<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Storage::Borrowed(bytes) => bytes,
+            #[cfg(feature = "alloc")]
+            Storage::Owned(bytes) => bytes,
+        }
+    }
+}
+
+impl DerefMut for Storage<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            Storage::Borrowed(bytes) => bytes,
+            #[cfg(feature = "alloc")]
+            Storage::Owned(bytes) => bytes,
+        }
+    }
+}
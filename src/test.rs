@@ -4,6 +4,34 @@ mod tests {
     use mem_fs::FsErr;
     use mem_fs::MemFs;
 
+    /// Dump `fs` into a buffer.
+    fn dump_to_vec(fs: &MemFs) -> Vec<u8> {
+        let mut out = Vec::new();
+        fs.dump(|chunk| out.extend_from_slice(chunk)).unwrap();
+        out
+    }
+
+    const FOOTER_SIZE: usize = 16;
+
+    /// Recompute the footer checksum after patching a dump or delta.
+    fn reseal(data: &mut [u8]) {
+        let crc = crc::Crc::<u32, crc::NoTable>::new(&crc::CRC_32_CKSUM);
+        let body = data.len() - FOOTER_SIZE;
+        let sum = crc.checksum(&data[..body]);
+        let crc_off = data.len() - 4;
+        data[crc_off..].copy_from_slice(&sum.to_le_bytes());
+    }
+
+    /// Reader for `restore` and `restore_delta` over `data`, which fails once it runs out.
+    fn reader(data: &[u8]) -> impl FnMut(&mut [u8]) -> Result<(), FsErr> + '_ {
+        let mut pos = 0;
+        move |buf| {
+            buf.copy_from_slice(data.get(pos..pos + buf.len()).ok_or(FsErr::Corrupt)?);
+            pos += buf.len();
+            Ok(())
+        }
+    }
+
     #[test]
     fn create_read() {
         let mut fs = mem_fs::memfs!();
@@ -50,19 +78,19 @@ mod tests {
     fn file_exists() {
         let mut fs = mem_fs::memfs!();
         fs.create("foo", b"test").expect("Failed to create file.");
-        assert_eq!(fs.exists("foo"), true);
+        assert!(fs.exists("foo"));
     }
 
     #[test]
     fn file_not_existsing() {
         let fs = mem_fs::memfs!();
-        assert_eq!(fs.exists("foo"), false);
+        assert!(!fs.exists("foo"));
     }
 
     #[test]
     fn read_non_existing_file() {
         let fs = mem_fs::memfs!();
-        assert!(fs.read("foo").is_none());
+        assert!(matches!(fs.read("foo"), Err(FsErr::NotFound)));
     }
 
     #[test]
@@ -193,7 +221,7 @@ mod tests {
         fs.create("foo", b"test").expect("Failed to create file");
         assert_eq!(fs.read("foo").unwrap(), b"test");
         fs.rename("foo", "bar").expect("Failed to rename file.");
-        assert!(fs.read("foo").is_none());
+        assert!(matches!(fs.read("foo"), Err(FsErr::NotFound)));
         assert_eq!(fs.read("bar").unwrap(), b"test");
     }
 
@@ -386,7 +414,7 @@ mod tests {
         let mut fs = mem_fs::memfs!();
         fs.create("foo", b"test").unwrap();
         fs.delete("foo").expect("Failed to delete file");
-        assert!(fs.read("foo").is_none());
+        assert!(matches!(fs.read("foo"), Err(FsErr::NotFound)));
     }
 
     #[test]
@@ -411,6 +439,410 @@ mod tests {
         assert!(fs.delete("foo").is_err());
     }
 
+    #[test]
+    fn checksummed_file_survives_modifications() {
+        let mut fs = mem_fs::memfs!();
+        fs.create_with_flags("cal", b"Hello World!", FileFlags::CHECKSUMMED)
+            .unwrap();
+
+        fs.write_at("cal", 6, b"Rust!").unwrap();
+        fs.append("cal", b" More data").unwrap();
+        assert_eq!(fs.read("cal").unwrap(), b"Hello Rust!! More data");
+
+        fs.truncate("cal", 5).unwrap();
+        assert_eq!(fs.read_at("cal", 1, 3).unwrap(), b"ell");
+
+        fs.write("cal", b"").unwrap();
+        assert_eq!(fs.read("cal").unwrap(), b"");
+    }
+
+    #[test]
+    fn checksummed_appends_across_pages_and_extents() {
+        let mut fs = mem_fs::memfs!();
+        fs.create_with_flags("log", b"", FileFlags::CHECKSUMMED)
+            .unwrap();
+
+        let mut expected = Vec::new();
+        for i in 0..20u8 {
+            let chunk = [i; 40];
+            fs.append("log", &chunk).unwrap();
+            expected.extend_from_slice(&chunk);
+            // Force the next growth into a separate extent now and then.
+            if i % 5 == 0 {
+                fs.create(&format!("gap{i}"), b"x").unwrap();
+            }
+        }
+
+        let mut buf = [0u8; 800];
+        assert_eq!(fs.read_into("log", &mut buf).unwrap(), expected.len());
+        assert_eq!(&buf[..], expected);
+
+        // In-place writes still recompute the checksum over the whole file.
+        fs.write_at("log", 0, b"!").unwrap();
+        expected[0] = b'!';
+        fs.read_into("log", &mut buf).unwrap();
+        assert_eq!(&buf[..], expected);
+    }
+
+    /// Fill the default fs with 8 files of 16 pages and delete every other one,
+    /// leaving four separate 16 page holes.
+    fn fragment_free_space(fs: &mut mem_fs::MemFs) {
+        let chunk = [0xEEu8; 16 * mem_fs::DEFAULT_PAGE_SIZE];
+        for i in 0..8 {
+            fs.create(&format!("fill{i}"), &chunk).unwrap();
+        }
+        for i in (0..8).step_by(2) {
+            fs.delete(&format!("fill{i}")).unwrap();
+        }
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn create_fragmented_file() {
+        let mut fs = mem_fs::memfs!();
+        fragment_free_space(&mut fs);
+
+        let data = pattern(40 * mem_fs::DEFAULT_PAGE_SIZE);
+        fs.create("big", &data).unwrap();
+
+        let entry = fs.entries().find(|e| e.name == "big").unwrap();
+        assert_eq!(entry.extent_count(), 3);
+        assert!(matches!(fs.read("big"), Err(FsErr::Fragmented)));
+
+        let mut buf = vec![0u8; data.len()];
+        assert_eq!(fs.read_into("big", &mut buf).unwrap(), data.len());
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn read_at_rejects_range_across_extents() {
+        let mut fs = mem_fs::memfs!();
+        fragment_free_space(&mut fs);
+
+        let data = pattern(20 * mem_fs::DEFAULT_PAGE_SIZE);
+        fs.create("big", &data).unwrap();
+
+        let boundary = 16 * mem_fs::DEFAULT_PAGE_SIZE;
+        let first = fs.read_at("big", boundary - 4, 4).unwrap();
+        assert_eq!(first, &data[boundary - 4..boundary]);
+        let second = fs.read_at("big", boundary, 4).unwrap();
+        assert_eq!(second, &data[boundary..boundary + 4]);
+        assert!(matches!(
+            fs.read_at("big", boundary - 4, 8),
+            Err(FsErr::Fragmented)
+        ));
+    }
+
+    #[test]
+    fn read_at_into_crosses_extents() {
+        let mut fs = mem_fs::memfs!();
+        fragment_free_space(&mut fs);
+
+        let data = pattern(20 * mem_fs::DEFAULT_PAGE_SIZE);
+        fs.create("big", &data).unwrap();
+
+        let boundary = 16 * mem_fs::DEFAULT_PAGE_SIZE;
+        let mut buf = [0u8; 8];
+        assert_eq!(fs.read_at_into("big", boundary - 4, &mut buf).unwrap(), 8);
+        assert_eq!(buf, data[boundary - 4..boundary + 4]);
+
+        // Clamped to the end of the file.
+        assert_eq!(fs.read_at_into("big", data.len() - 3, &mut buf).unwrap(), 3);
+        assert_eq!(buf[..3], data[data.len() - 3..]);
+        assert_eq!(fs.read_at_into("big", data.len() + 1, &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn fragmented_file_uses_largest_holes() {
+        let mut fs = mem_fs::memfs!();
+        let page = [0xEEu8; mem_fs::DEFAULT_PAGE_SIZE];
+        for i in 0..16 {
+            fs.create(&format!("small{i}"), &page).unwrap();
+        }
+        fs.create("a", &[0u8; 15 * mem_fs::DEFAULT_PAGE_SIZE])
+            .unwrap();
+        fs.create("gap", &page).unwrap();
+        fs.create("b", &[0u8; 15 * mem_fs::DEFAULT_PAGE_SIZE])
+            .unwrap();
+        fs.create("rest", &[0u8; 81 * mem_fs::DEFAULT_PAGE_SIZE])
+            .unwrap();
+        // Eight one-page holes in front of two 15 page holes.
+        for i in (0..16).step_by(2) {
+            fs.delete(&format!("small{i}")).unwrap();
+        }
+        fs.delete("a").unwrap();
+        fs.delete("b").unwrap();
+
+        let data = pattern(20 * mem_fs::DEFAULT_PAGE_SIZE);
+        fs.create("big", &data).unwrap();
+
+        let entry = fs.entries().find(|e| e.name == "big").unwrap();
+        assert_eq!(entry.extent_count(), 2);
+        let mut buf = vec![0u8; data.len()];
+        fs.read_into("big", &mut buf).unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn append_repacks_when_neighbour_is_used() {
+        let mut fs = mem_fs::memfs!();
+        fs.create("log", &[1u8; 10]).unwrap();
+        fs.create("other", &[2u8; 10]).unwrap();
+
+        fs.append("log", &[3u8; 40]).unwrap();
+
+        let entry = fs.entries().find(|e| e.name == "log").unwrap();
+        assert_eq!(entry.extent_count(), 1);
+        let data = fs.read("log").unwrap();
+        assert_eq!(&data[..10], &[1u8; 10]);
+        assert_eq!(&data[10..], &[3u8; 40]);
+        assert_eq!(fs.read("other").unwrap(), &[2u8; 10]);
+    }
+
+    #[test]
+    fn append_adds_extent_when_no_run_is_large_enough() {
+        let mut fs = mem_fs::memfs!();
+        fs.create("log", &[1u8; 10]).unwrap();
+        fs.create("other", &[2u8; 10]).unwrap();
+        // Leave a single free page at the end of storage.
+        fs.create("filler", &[0u8; 125 * mem_fs::DEFAULT_PAGE_SIZE])
+            .unwrap();
+
+        fs.append("log", &[3u8; 40]).unwrap();
+
+        let entry = fs.entries().find(|e| e.name == "log").unwrap();
+        assert_eq!(entry.extent_count(), 2);
+
+        let mut buf = [0u8; 50];
+        fs.read_into("log", &mut buf).unwrap();
+        assert_eq!(&buf[..10], &[1u8; 10]);
+        assert_eq!(&buf[10..], &[3u8; 40]);
+        assert_eq!(fs.read("other").unwrap(), &[2u8; 10]);
+    }
+
+    #[test]
+    fn append_do_not_fragment_stays_contiguous() {
+        let mut fs = mem_fs::memfs!();
+        fs.create_with_flags("log", &[1u8; 10], FileFlags::DO_NOT_FRAGMENT)
+            .unwrap();
+        fs.create("other", &[2u8; 10]).unwrap();
+
+        fs.append("log", &[3u8; 40]).unwrap();
+
+        let entry = fs.entries().find(|e| e.name == "log").unwrap();
+        assert_eq!(entry.extent_count(), 1);
+        assert_eq!(&fs.read("log").unwrap()[10..], &[3u8; 40]);
+    }
+
+    #[test]
+    fn do_not_fragment_requires_contiguous_run() {
+        let mut fs = mem_fs::memfs!();
+        fragment_free_space(&mut fs);
+
+        let data = [0u8; 20 * mem_fs::DEFAULT_PAGE_SIZE];
+        assert!(matches!(
+            fs.create_with_flags("big", &data, FileFlags::DO_NOT_FRAGMENT),
+            Err(FsErr::WouldFragment)
+        ));
+        assert!(matches!(
+            fs.create("huge", &[0u8; 4096]),
+            Err(FsErr::NoSpace)
+        ));
+    }
+
+    #[test]
+    fn write_at_and_truncate_across_extents() {
+        let mut fs = mem_fs::memfs!();
+        fragment_free_space(&mut fs);
+
+        let mut data = pattern(40 * mem_fs::DEFAULT_PAGE_SIZE);
+        fs.create("big", &data).unwrap();
+
+        // Overwrite bytes straddling the first extent boundary.
+        let boundary = 16 * mem_fs::DEFAULT_PAGE_SIZE;
+        fs.write_at("big", boundary - 2, b"ABCD").unwrap();
+        data[boundary - 2..boundary + 2].copy_from_slice(b"ABCD");
+
+        let mut buf = vec![0u8; data.len()];
+        fs.read_into("big", &mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        // Shrinking back into the first extent releases the others.
+        fs.truncate("big", 10).unwrap();
+        let entry = fs.entries().find(|e| e.name == "big").unwrap();
+        assert_eq!(entry.extent_count(), 1);
+        assert_eq!(fs.read("big").unwrap(), &data[..10]);
+
+        fs.delete("big").unwrap();
+        fs.create("again", &[0u8; 48 * mem_fs::DEFAULT_PAGE_SIZE])
+            .unwrap();
+    }
+
+    #[test]
+    fn compact_leaves_single_free_run() {
+        let mut fs = mem_fs::memfs!();
+        fragment_free_space(&mut fs);
+
+        let data = [0u8; 64 * mem_fs::DEFAULT_PAGE_SIZE];
+        assert!(
+            fs.create_with_flags("big", &data, FileFlags::DO_NOT_FRAGMENT)
+                .is_err()
+        );
+
+        fs.compact();
+
+        fs.create_with_flags("big", &data, FileFlags::DO_NOT_FRAGMENT)
+            .unwrap();
+        for i in (1..8).step_by(2) {
+            let name = format!("fill{i}");
+            assert_eq!(
+                fs.read(&name).unwrap(),
+                &[0xEEu8; 16 * mem_fs::DEFAULT_PAGE_SIZE]
+            );
+        }
+    }
+
+    #[test]
+    fn compact_merges_file_extents() {
+        let mut fs = mem_fs::memfs!();
+        fs.create("a", b"first").unwrap();
+        fs.create("b", b"blocker").unwrap();
+        fs.append("a", &[7u8; 64]).unwrap();
+        fs.delete("b").unwrap();
+
+        fs.compact();
+
+        let entry = fs.entries().find(|e| e.name == "a").unwrap();
+        assert_eq!(entry.extent_count(), 1);
+        let data = fs.read("a").unwrap();
+        assert_eq!(&data[..5], b"first");
+        assert_eq!(&data[5..], &[7u8; 64]);
+    }
+
+    #[test]
+    fn compact_step_is_incremental() {
+        let mut fs = mem_fs::memfs!();
+        fragment_free_space(&mut fs);
+        let data = pattern(20 * mem_fs::DEFAULT_PAGE_SIZE);
+        fs.create("big", &data).unwrap();
+
+        let mut steps = 1;
+        while !fs.compact_step(16) {
+            steps += 1;
+        }
+        assert!(steps > 1);
+        assert!(fs.compact_step(16));
+
+        let mut buf = vec![0u8; data.len()];
+        fs.read_into("big", &mut buf).unwrap();
+        assert_eq!(buf, data);
+        fs.create_with_flags(
+            "rest",
+            &[0u8; 44 * mem_fs::DEFAULT_PAGE_SIZE],
+            FileFlags::DO_NOT_FRAGMENT,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn directories_and_paths() {
+        let mut fs = mem_fs::memfs!();
+        fs.mkdir("textures").unwrap();
+        fs.mkdir("/textures/ui").unwrap();
+        fs.create("textures/ui/button.png", b"png").unwrap();
+
+        assert!(fs.exists("textures/ui"));
+        assert_eq!(fs.read("/textures/ui/button.png").unwrap(), b"png");
+        assert_eq!(fs.read("textures/./ui/../ui/button.png").unwrap(), b"png");
+        assert_eq!(fs.read("../textures/ui/button.png").unwrap(), b"png");
+        assert!(matches!(fs.read("textures/ui"), Err(FsErr::IsDirectory)));
+    }
+
+    #[test]
+    fn same_name_in_different_directories() {
+        let mut fs = mem_fs::memfs!();
+        fs.mkdir("a").unwrap();
+        fs.mkdir("b").unwrap();
+        fs.create("a/x", b"in a").unwrap();
+        fs.create("b/x", b"in b").unwrap();
+
+        assert_eq!(fs.read("a/x").unwrap(), b"in a");
+        assert_eq!(fs.read("b/x").unwrap(), b"in b");
+        assert!(matches!(fs.create("a/x", b""), Err(FsErr::Duplicate)));
+    }
+
+    #[test]
+    fn create_requires_parent_directory() {
+        let mut fs = mem_fs::memfs!();
+        fs.create("file", b"data").unwrap();
+
+        assert!(matches!(fs.create("missing/x", b""), Err(FsErr::NotFound)));
+        assert!(matches!(fs.create("file/x", b""), Err(FsErr::NotDirectory)));
+        assert!(matches!(fs.mkdir("missing/dir"), Err(FsErr::NotFound)));
+        assert!(fs.create("dir/..", b"").is_err());
+    }
+
+    #[test]
+    fn read_dir_lists_direct_children() {
+        let mut fs = mem_fs::memfs!();
+        fs.mkdir("audio").unwrap();
+        fs.mkdir("audio/music").unwrap();
+        fs.create("audio/click.wav", b"").unwrap();
+        fs.create("audio/music/theme.ogg", b"").unwrap();
+        fs.create("readme", b"").unwrap();
+
+        let mut names: Vec<_> = fs
+            .read_dir("audio")
+            .unwrap()
+            .map(|e| (e.name.as_str(), e.is_dir()))
+            .collect();
+        names.sort();
+        assert_eq!(names, [("click.wav", false), ("music", true)]);
+
+        assert_eq!(fs.read_dir("/").unwrap().count(), 2);
+        assert!(matches!(fs.read_dir("readme"), Err(FsErr::NotDirectory)));
+    }
+
+    #[test]
+    fn rmdir_requires_empty_directory() {
+        let mut fs = mem_fs::memfs!();
+        fs.mkdir("dir").unwrap();
+        fs.create("dir/file", b"data").unwrap();
+
+        assert!(matches!(fs.rmdir("dir"), Err(FsErr::DirectoryNotEmpty)));
+        assert!(matches!(fs.delete("dir"), Err(FsErr::IsDirectory)));
+        assert!(matches!(fs.rmdir("dir/file"), Err(FsErr::NotDirectory)));
+
+        fs.delete("dir/file").unwrap();
+        fs.rmdir("dir").unwrap();
+        assert!(!fs.exists("dir"));
+    }
+
+    #[test]
+    fn rename_moves_between_directories() {
+        let mut fs = mem_fs::memfs!();
+        fs.mkdir("src").unwrap();
+        fs.mkdir("dst").unwrap();
+        fs.mkdir("src/sub").unwrap();
+        fs.create("src/sub/file", b"data").unwrap();
+
+        fs.rename("src/sub", "dst/moved").unwrap();
+        assert_eq!(fs.read("dst/moved/file").unwrap(), b"data");
+        assert!(!fs.exists("src/sub"));
+
+        assert!(matches!(
+            fs.rename("dst", "dst/moved/inner"),
+            Err(FsErr::InvalidOp)
+        ));
+        assert!(matches!(
+            fs.rename("dst/moved/file", "missing/file"),
+            Err(FsErr::NotFound)
+        ));
+    }
+
     #[test]
     fn large_file() {
         let mut fs = mem_fs::memfs!();
@@ -425,100 +857,1708 @@ mod tests {
         assert!(fs.create("foo", &data).is_err());
     }
 
-    mod persistence {
-        use mem_fs::DEFAULT_STORAGE_SIZE;
+    mod handles {
+        use mem_fs::FileFlags;
         use mem_fs::FsErr;
-        use mem_fs::MemFs;
+        use mem_fs::OpenOptions;
+        use mem_fs::SeekFrom;
 
-        fn dump_to_vec(fs: &MemFs) -> Vec<u8> {
-            let mut out = Vec::new();
-            fs.dump(|chunk| out.extend_from_slice(chunk)).unwrap();
-            out
+        #[test]
+        fn write_seek_read() {
+            let mut fs = mem_fs::memfs!();
+            let options = OpenOptions::new().read(true).write(true).create(true);
+            let mut file = fs.open("foo", options).unwrap();
+
+            assert_eq!(file.write(b"Hello World!").unwrap(), 12);
+            assert_eq!(file.tell(), 12);
+
+            assert_eq!(file.seek(SeekFrom::Start(6)).unwrap(), 6);
+            file.write(b"Rust!").unwrap();
+            assert_eq!(file.seek(SeekFrom::Current(-5)).unwrap(), 6);
+
+            let mut buf = [0u8; 16];
+            assert_eq!(file.read(&mut buf).unwrap(), 6);
+            assert_eq!(&buf[..6], b"Rust!!");
+            assert_eq!(file.read(&mut buf).unwrap(), 0);
+            file.close();
+
+            assert_eq!(fs.read("foo").unwrap(), b"Hello Rust!!");
         }
 
-        fn restore_from_slice(fs: &mut MemFs, data: &[u8]) -> Result<(), FsErr> {
-            let mut pos = 0usize;
-            fs.restore(|buf| {
-                let end = pos + buf.len();
-                if end > data.len() {
-                    return Err(FsErr::Corrupt);
-                }
-                buf.copy_from_slice(&data[pos..end]);
-                pos = end;
-                Ok(())
-            })
+        #[test]
+        fn append_mode_ignores_cursor() {
+            let mut fs = mem_fs::memfs!();
+            fs.create("log", b"one").unwrap();
+
+            let mut file = fs.open("log", OpenOptions::new().append(true)).unwrap();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.write(b",two").unwrap();
+            assert_eq!(file.tell(), 7);
+            file.close();
+
+            assert_eq!(fs.read("log").unwrap(), b"one,two");
         }
 
         #[test]
-        fn dump_restore_roundtrip_basic() {
+        fn read_across_extents() {
             let mut fs = mem_fs::memfs!();
-            fs.create("foo", b"hello").unwrap();
-            fs.create("bar", b"world!!").unwrap();
+            fs.create("a", &[1u8; 20]).unwrap();
+            fs.create("b", b"blocker").unwrap();
+            fs.append("a", &[2u8; 40]).unwrap();
+
+            let mut file = fs.open("a", OpenOptions::new().read(true)).unwrap();
+            file.seek(SeekFrom::End(-45)).unwrap();
+            let mut buf = [0u8; 45];
+            assert_eq!(file.read(&mut buf).unwrap(), 45);
+            assert_eq!(&buf[..5], &[1u8; 5]);
+            assert_eq!(&buf[5..], &[2u8; 40]);
+        }
 
-            let data = dump_to_vec(&fs);
+        #[test]
+        fn open_options_are_enforced() {
+            let mut fs = mem_fs::memfs!();
+            fs.create("foo", b"data").unwrap();
+            fs.create_with_flags("locked", b"data", FileFlags::IMMUTABLE)
+                .unwrap();
 
-            let mut fs2 = mem_fs::memfs!();
-            restore_from_slice(&mut fs2, &data).unwrap();
+            assert!(matches!(
+                fs.open("missing", OpenOptions::new().read(true)),
+                Err(FsErr::NotFound)
+            ));
+            assert!(matches!(
+                fs.open("foo", OpenOptions::new()),
+                Err(FsErr::InvalidOp)
+            ));
+            assert!(matches!(
+                fs.open("locked", OpenOptions::new().write(true)),
+                Err(FsErr::ReadOnly)
+            ));
 
-            assert_eq!(fs2.read("foo").unwrap(), b"hello");
-            assert_eq!(fs2.read("bar").unwrap(), b"world!!");
+            let mut file = fs.open("foo", OpenOptions::new().read(true)).unwrap();
+            assert!(matches!(file.write(b"x"), Err(FsErr::InvalidOp)));
+            assert!(matches!(
+                file.seek(SeekFrom::Start(5)),
+                Err(FsErr::InvalidOp)
+            ));
+            assert!(matches!(
+                file.seek(SeekFrom::Current(-1)),
+                Err(FsErr::InvalidOp)
+            ));
         }
 
         #[test]
-        fn dump_restore_empty_fs() {
-            let fs = mem_fs::memfs!();
-            let data = dump_to_vec(&fs);
+        fn open_truncate() {
+            let mut fs = mem_fs::memfs!();
+            fs.create("foo", b"old contents").unwrap();
 
-            let mut fs2 = mem_fs::memfs!();
-            restore_from_slice(&mut fs2, &data).unwrap();
+            let options = OpenOptions::new().write(true).truncate(true);
+            let mut file = fs.open("foo", options).unwrap();
+            assert_eq!(file.size(), 0);
+            file.write(b"new").unwrap();
+            file.close();
 
-            assert_eq!(fs2.entries().count(), 0);
+            assert_eq!(fs.read("foo").unwrap(), b"new");
         }
 
         #[test]
-        fn restore_rejects_bad_magic() {
-            let fs = mem_fs::memfs!();
-            let mut data = dump_to_vec(&fs);
+        fn std_io_traits() {
+            use std::io::{BufRead, Read, Seek, Write};
 
-            data[0] ^= 0xFF; // corrupt first magic byte
+            let mut fs = mem_fs::memfs!();
+            let options = OpenOptions::new().read(true).write(true).create(true);
+            let mut file = fs.open("lines", options).unwrap();
+
+            writeln!(file, "first").unwrap();
+            file.write_all(b"second\nthird").unwrap();
+            file.flush().unwrap();
+
+            file.rewind().unwrap();
+            let lines: Vec<String> = (&mut file).lines().map(Result::unwrap).collect();
+            assert_eq!(lines, ["first", "second", "third"]);
+
+            Seek::seek(&mut file, std::io::SeekFrom::Start(6)).unwrap();
+            let mut rest = String::new();
+            file.read_to_string(&mut rest).unwrap();
+            assert_eq!(rest, "second\nthird");
+
+            let mut copy = Vec::new();
+            file.rewind().unwrap();
+            std::io::copy(&mut file, &mut copy).unwrap();
+            assert_eq!(copy, b"first\nsecond\nthird");
+        }
 
-            let mut fs2 = mem_fs::memfs!();
-            assert!(matches!(
-                restore_from_slice(&mut fs2, &data),
-                Err(FsErr::Corrupt)
-            ));
+        #[test]
+        fn buf_read_across_extents() {
+            use std::io::BufRead;
+
+            let mut fs = mem_fs::memfs!();
+            fs.create("a", &[b'x'; 20]).unwrap();
+            fs.create("b", b"blocker").unwrap();
+            fs.create("filler", &[0u8; 125 * mem_fs::DEFAULT_PAGE_SIZE])
+                .unwrap();
+            fs.append("a", b"\nyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyy\n")
+                .unwrap();
+
+            let mut file = fs.open("a", OpenOptions::new().read(true)).unwrap();
+            assert_eq!(file.fill_buf().unwrap().len(), 32);
+
+            let mut line = String::new();
+            file.read_line(&mut line).unwrap();
+            assert_eq!(line.len(), 21);
+            line.clear();
+            file.read_line(&mut line).unwrap();
+            assert_eq!(line, "yyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyy\n");
+            assert!(file.fill_buf().unwrap().is_empty());
         }
 
         #[test]
-        fn restore_rejects_truncated_dump() {
+        fn fs_err_into_io_error() {
+            use std::io::{ErrorKind, Write};
+
             let mut fs = mem_fs::memfs!();
-            fs.create("foo", b"hello").unwrap();
+            fs.create("foo", b"data").unwrap();
+
+            let err: std::io::Error = fs.create("foo", b"").unwrap_err().into();
+            assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+            let err: std::io::Error = fs.delete("missing").unwrap_err().into();
+            assert_eq!(err.kind(), ErrorKind::NotFound);
+            let err: std::io::Error = FsErr::ReadOnly.into();
+            assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+
+            let mut file = fs.open("foo", OpenOptions::new().append(true)).unwrap();
+            let err = file.write_all(&[0u8; 8192]).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::StorageFull);
+        }
 
-            let data = dump_to_vec(&fs);
-            let truncated = &data[..data.len() - 1];
+        #[cfg(feature = "embedded-io")]
+        #[test]
+        fn embedded_io_traits() {
+            use embedded_io::{BufRead, Error, ErrorKind, Read, Seek, Write};
 
-            let mut fs2 = mem_fs::memfs!();
-            assert!(restore_from_slice(&mut fs2, truncated).is_err());
+            let mut fs = mem_fs::memfs!();
+            fs.create("b", b"blocker").unwrap();
+            let options = OpenOptions::new().read(true).write(true).create(true);
+            let mut file = fs.open("log", options).unwrap();
+
+            file.write_all(b"boot ok;").unwrap();
+            file.write_fmt(format_args!("uptime={}s", 42)).unwrap();
+            file.flush().unwrap();
+
+            file.rewind().unwrap();
+            let mut buf = [0u8; 18];
+            file.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"boot ok;uptime=42s");
+
+            Seek::seek(&mut file, embedded_io::SeekFrom::Start(5)).unwrap();
+            assert_eq!(BufRead::fill_buf(&mut file).unwrap(), b"ok;uptime=42s");
+            BufRead::consume(&mut file, 3);
+            assert_eq!(file.stream_position().unwrap(), 8);
+
+            let err = Seek::seek(&mut file, embedded_io::SeekFrom::End(1)).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+            assert_eq!(FsErr::NoSpace.kind(), ErrorKind::OutOfMemory);
+        }
+    }
+
+    mod entry_table {
+        use super::{dump_to_vec, reader};
+        use mem_fs::FsErr;
+        use mem_fs::MemoryFs;
+        use mem_fs::RestoreCheck;
+
+        #[test]
+        fn entry_table_size_is_configurable() {
+            let storage = Box::leak(Box::new([0u8; 65536]));
+            let mut fs = MemoryFs::<65536, 64, 320>::from_backed(storage);
+
+            fs.mkdir("assets").unwrap();
+            for i in 0..300 {
+                let name = format!("assets/file{i}");
+                fs.create(&name, name.as_bytes()).unwrap();
+            }
+            assert_eq!(fs.entries().count(), 301);
+            assert_eq!(fs.read("assets/file299").unwrap(), b"assets/file299");
         }
 
         #[test]
-        fn restore_rejects_storage_len_mismatch() {
-            let fs = mem_fs::memfs!();
-            let mut data = dump_to_vec(&fs);
+        fn full_entry_table_is_reported() {
+            let storage = Box::leak(Box::new([0u8; 1024]));
+            let mut fs = MemoryFs::<1024, 32, 2>::from_backed(storage);
 
-            // Patch storage_len (last 4 bytes before storage).
-            // Since storage is the final block, storage_len starts at: data.len() - STORAGE_SIZE - 4
-            let off = data.len() - DEFAULT_STORAGE_SIZE - 4;
-            let bad = (DEFAULT_STORAGE_SIZE as u32 - 1).to_le_bytes();
-            data[off..off + 4].copy_from_slice(&bad);
+            fs.create("a", b"a").unwrap();
+            fs.mkdir("dir").unwrap();
+            assert!(matches!(fs.create("b", b"b"), Err(FsErr::TooManyFiles)));
+            assert!(matches!(fs.mkdir("dir2"), Err(FsErr::TooManyFiles)));
 
-            let mut fs2 = mem_fs::memfs!();
+            fs.delete("a").unwrap();
+            fs.create("dir/b", b"b").unwrap();
+        }
+
+        #[test]
+        fn lookups_stay_consistent() {
+            let storage = Box::leak(Box::new([0u8; 65536]));
+            let mut fs = MemoryFs::<65536, 64, 256>::from_backed(storage);
+
+            fs.mkdir("d").unwrap();
+            for i in (0..200).rev() {
+                let dir = if i % 2 == 0 { "d/" } else { "" };
+                fs.create(&format!("{dir}f{i}"), &[i as u8]).unwrap();
+            }
+            for i in (0..200).step_by(3) {
+                let dir = if i % 2 == 0 { "d/" } else { "" };
+                fs.delete(&format!("{dir}f{i}")).unwrap();
+            }
+            fs.rename("f1", "d/renamed").unwrap();
+            fs.rename_replace("d/f2", "f5").unwrap();
+            let _ = fs.transaction(|tx| {
+                tx.rename("f7", "d/f7")?;
+                tx.delete("d/f8")?;
+                Err::<(), _>(FsErr::InvalidOp)
+            });
+
+            for i in 0..200 {
+                let dir = if i % 2 == 0 { "d/" } else { "" };
+                let name = format!("{dir}f{i}");
+                let expected = match i {
+                    1 | 2 => None,
+                    5 => Some(2),
+                    _ if i % 3 == 0 => None,
+                    _ => Some(i as u8),
+                };
+                assert_eq!(fs.read(&name).ok().map(|data| data[0]), expected, "{name}");
+            }
+            assert_eq!(fs.read("d/renamed").unwrap(), &[1]);
+            assert!(matches!(fs.rmdir("d"), Err(FsErr::DirectoryNotEmpty)));
+        }
+
+        #[test]
+        fn name_length_is_configurable() {
+            let storage = Box::leak(Box::new([0u8; 1024]));
+            let mut fs = MemoryFs::<1024, 32, 8, 12>::from_backed(storage);
+            assert!(size_of::<mem_fs::FileEntry<12>>() < size_of::<mem_fs::FileEntry>() / 2);
+
+            fs.mkdir("twelve_bytes").unwrap();
+            fs.create("twelve_bytes/short.txt", b"ok").unwrap();
+            assert!(matches!(
+                fs.create("thirteen_byte", b""),
+                Err(FsErr::FileNameInvalid(_))
+            ));
+            assert!(matches!(
+                fs.rename("twelve_bytes/short.txt", "much_too_long.txt"),
+                Err(FsErr::FileNameInvalid(_))
+            ));
+            assert!(matches!(
+                fs.mkdir("thirteen_byte"),
+                Err(FsErr::FileNameInvalid(_))
+            ));
+        }
+
+        #[test]
+        fn restore_rejects_names_longer_than_configured() {
+            let mut source = mem_fs::memfs!();
+            source.create("a_rather_long_name", b"data").unwrap();
+            let dump = dump_to_vec(&source);
+
+            let storage = Box::leak(Box::new([0u8; 4096]));
+            let mut fs = MemoryFs::<4096, 32, 8, 12>::from_backed(storage);
+            let result = fs.restore(reader(&dump));
             assert!(matches!(
-                restore_from_slice(&mut fs2, &data),
-                Err(FsErr::Corrupt)
+                result,
+                Err(FsErr::Restore(err)) if err.check() == RestoreCheck::Name && err.offset() > 0
             ));
         }
     }
+
+    mod allocators {
+        use core::ops::Range;
+        use mem_fs::{
+            Allocator, BestFit, Buddy, FirstFit, FsErr, MemoryFs, NextFit, PageMap, bitmap_words,
+        };
+
+        type Fs<A> = MemoryFs<'static, 4096, 32, 32, 16, 4, A>;
+
+        // Returns the filesystem and the address of its storage.
+        fn new_fs<A: Allocator + Default>() -> (Fs<A>, usize) {
+            let storage = Box::leak(Box::new([0u8; 4096]));
+            let base = storage.as_ptr() as usize;
+            (Fs::<A>::from_backed(storage), base)
+        }
+
+        // Leaves free runs of 4 pages at page 0 and 2 pages at page 10, then 110 free pages.
+        fn make_holes<A: Allocator>(fs: &mut Fs<A>) {
+            fs.create("a", &[0u8; 4 * 32]).unwrap();
+            fs.create("b", &[0u8; 6 * 32]).unwrap();
+            fs.create("c", &[0u8; 2 * 32]).unwrap();
+            fs.create("d", &[0u8; 6 * 32]).unwrap();
+            fs.delete("a").unwrap();
+            fs.delete("c").unwrap();
+        }
+
+        fn start_page<A: Allocator>(fs: &Fs<A>, base: usize, name: &str) -> usize {
+            (fs.read(name).unwrap().as_ptr() as usize - base) / 32
+        }
+
+        #[test]
+        fn first_fit_takes_first_hole() {
+            let (mut fs, base) = new_fs::<FirstFit>();
+            make_holes(&mut fs);
+            fs.create("new", &[1u8; 2 * 32]).unwrap();
+            assert_eq!(start_page(&fs, base, "new"), 0);
+        }
+
+        #[test]
+        fn best_fit_takes_smallest_hole() {
+            let (mut fs, base) = new_fs::<BestFit>();
+            make_holes(&mut fs);
+            fs.create("new", &[1u8; 2 * 32]).unwrap();
+            assert_eq!(start_page(&fs, base, "new"), 10);
+            fs.create("new2", &[1u8; 3 * 32]).unwrap();
+            assert_eq!(start_page(&fs, base, "new2"), 0);
+        }
+
+        #[test]
+        fn next_fit_continues_after_last_allocation() {
+            let (mut fs, base) = new_fs::<NextFit>();
+            make_holes(&mut fs);
+            fs.create("new", &[1u8; 32]).unwrap();
+            assert_eq!(start_page(&fs, base, "new"), 18);
+            fs.create("new2", &[1u8; 32]).unwrap();
+            assert_eq!(start_page(&fs, base, "new2"), 19);
+        }
+
+        #[test]
+        fn buddy_rounds_to_aligned_blocks() {
+            let (mut fs, base) = new_fs::<Buddy>();
+            fs.create("a", &[0u8; 3 * 32]).unwrap();
+            assert_eq!(fs.capacity("a"), Some(4 * 32));
+
+            fs.create("small", &[1u8; 32]).unwrap();
+            assert_eq!(start_page(&fs, base, "small"), 4);
+            fs.create("pair", &[1u8; 2 * 32]).unwrap();
+            assert_eq!(start_page(&fs, base, "pair"), 6);
+            fs.create("big", &[1u8; 5 * 32]).unwrap();
+            assert_eq!(start_page(&fs, base, "big"), 8);
+            assert_eq!(fs.capacity("big"), Some(8 * 32));
+        }
+
+        #[test]
+        fn runs_cross_bitmap_words() {
+            // 70 pages: two full bitmap words and a partial one.
+            let storage = Box::leak(Box::new([0u8; 70 * 32]));
+            let base = storage.as_ptr() as usize;
+            let mut fs = MemoryFs::<'static, { 70 * 32 }, 32, 8, 16>::from_backed(storage);
+            fs.create("a", &[1u8; 30 * 32]).unwrap();
+            fs.create("b", &[2u8; 4 * 32]).unwrap();
+            fs.create("c", &[3u8; 36 * 32]).unwrap();
+            assert!(matches!(fs.create("d", &[4u8]), Err(FsErr::NoSpace)));
+
+            fs.delete("b").unwrap();
+            fs.create("d", &[4u8; 4 * 32]).unwrap();
+            assert_eq!((fs.read("d").unwrap().as_ptr() as usize - base) / 32, 30);
+
+            fs.delete("a").unwrap();
+            fs.delete("d").unwrap();
+            fs.create("e", &[5u8; 34 * 32]).unwrap();
+            assert_eq!(fs.read("e").unwrap(), &[5u8; 34 * 32]);
+            assert_eq!(fs.read("c").unwrap(), &[3u8; 36 * 32]);
+        }
+
+        #[test]
+        fn storage_beyond_default_bitmap() {
+            const SIZE: usize = 1 << 20;
+            let storage: Box<[u8; SIZE]> = vec![0u8; SIZE].into_boxed_slice().try_into().unwrap();
+            let mut fs =
+                MemoryFs::<'static, SIZE, 32, 8, 16, { bitmap_words(SIZE, 32) }>::from_backed(
+                    Box::leak(storage),
+                );
+            let data = vec![7u8; SIZE - 32];
+            fs.create("big", &data).unwrap();
+            fs.create("tail", &[1u8; 32]).unwrap();
+            assert_eq!(fs.read("big").unwrap(), &data[..]);
+            assert!(matches!(fs.create("more", &[1u8]), Err(FsErr::NoSpace)));
+        }
+
+        struct LastFit;
+
+        impl Allocator for LastFit {
+            fn find_run(&mut self, pages: &PageMap<'_>, need_pages: usize) -> Option<Range<usize>> {
+                let run = pages
+                    .free_runs(0)
+                    .filter(|run| run.len() >= need_pages)
+                    .last()?;
+                Some(run.end - need_pages..run.end)
+            }
+        }
+
+        #[test]
+        fn custom_allocator() {
+            let storage = Box::leak(Box::new([0u8; 4096]));
+            let base = storage.as_ptr() as usize;
+            let mut fs = Fs::with_allocator(storage, LastFit);
+            fs.create("a", &[1u8; 32]).unwrap();
+            fs.create("b", &[2u8; 64]).unwrap();
+            assert_eq!(start_page(&fs, base, "a"), 127);
+            assert_eq!(start_page(&fs, base, "b"), 125);
+            assert_eq!(fs.read("b").unwrap(), &[2u8; 64]);
+        }
+
+        fn churn<A: Allocator + Default>() {
+            let (mut fs, _) = new_fs::<A>();
+            let mut seed = 7u32;
+            for round in 0..400 {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                let name = format!("f{}", seed % 12);
+                let data = vec![round as u8; (seed as usize >> 8) % 300];
+                if fs.exists(&name) && seed.is_multiple_of(3) {
+                    fs.delete(&name).unwrap();
+                } else if fs.write(&name, &data).is_ok() {
+                    let mut buf = [0u8; 300];
+                    let len = fs.read_into(&name, &mut buf).unwrap();
+                    assert_eq!(&buf[..len], &data[..]);
+                }
+            }
+        }
+
+        #[test]
+        fn all_strategies_survive_churn() {
+            churn::<FirstFit>();
+            churn::<NextFit>();
+            churn::<BestFit>();
+            churn::<Buddy>();
+        }
+    }
+
+    mod runtime_storage {
+        use super::{dump_to_vec, reader};
+        use mem_fs::{DynMemoryFs, FsErr, MemFs};
+
+        #[test]
+        fn from_slice_uses_whole_pages() {
+            let mut storage = vec![0u8; 1000];
+            let mut fs: DynMemoryFs = DynMemoryFs::from_slice(&mut storage, 64).unwrap();
+
+            fs.create("a", &[1u8; 900]).unwrap();
+            fs.append("a", &[2u8; 60]).unwrap();
+            assert!(matches!(fs.append("a", &[3u8]), Err(FsErr::NoSpace)));
+            assert_eq!(fs.capacity("a"), Some(960));
+            assert_eq!(fs.read("a").unwrap()[..900], [1u8; 900]);
+            assert_eq!(fs.read("a").unwrap()[900..], [2u8; 60]);
+        }
+
+        #[test]
+        fn from_slice_rejects_bad_geometry() {
+            let mut storage = vec![0u8; 33 * 16];
+            let result: Result<DynMemoryFs, _> = DynMemoryFs::from_slice(&mut storage, 0);
+            assert!(matches!(result, Err(FsErr::InvalidOp)));
+            assert!(matches!(
+                DynMemoryFs::<'_, 8, 16, 1>::from_slice(&mut storage, 16),
+                Err(FsErr::InvalidOp)
+            ));
+            assert!(DynMemoryFs::<'_, 8, 16, 1>::from_slice(&mut storage[..32 * 16], 16).is_ok());
+        }
+
+        fn owned_fs(name: &str) -> DynMemoryFs<'static> {
+            let mut fs = DynMemoryFs::with_capacity(1024).unwrap();
+            fs.create(name, name.as_bytes()).unwrap();
+            fs
+        }
+
+        #[test]
+        fn with_capacity_owns_independent_storage() {
+            let filesystems: Vec<_> = (0..4).map(|i| owned_fs(&format!("file{i}"))).collect();
+            for (i, fs) in filesystems.iter().enumerate() {
+                let name = format!("file{i}");
+                assert_eq!(fs.read(&name).unwrap(), name.as_bytes());
+                assert_eq!(fs.entries().count(), 1);
+            }
+
+            let mut fs: DynMemoryFs = DynMemoryFs::with_capacity_and_page_size(1000, 64).unwrap();
+            assert!(fs.create("full", &[1u8; 960]).is_ok());
+            assert!(matches!(fs.append("full", &[1u8]), Err(FsErr::NoSpace)));
+
+            let too_big: Result<DynMemoryFs, _> = DynMemoryFs::with_capacity(1 << 20);
+            assert!(matches!(too_big, Err(FsErr::InvalidOp)));
+        }
+
+        #[test]
+        fn dump_restores_into_runtime_storage() {
+            let mut fs = mem_fs::memfs!();
+            fs.mkdir("logs").unwrap();
+            fs.create("logs/boot", b"booted").unwrap();
+
+            let dump = dump_to_vec(&fs);
+            assert!(dump.len() <= MemFs::serialized_max_size());
+
+            let mut storage = vec![0u8; mem_fs::DEFAULT_STORAGE_SIZE];
+            let mut restored: DynMemoryFs =
+                DynMemoryFs::from_slice(&mut storage, mem_fs::DEFAULT_PAGE_SIZE).unwrap();
+            assert_eq!(restored.dump_max_size(), MemFs::serialized_max_size());
+            restored.restore(reader(&dump)).unwrap();
+            assert_eq!(restored.read("logs/boot").unwrap(), b"booted");
+        }
+    }
+
+    mod images {
+        use mem_fs::{DynMemoryFs, FsErr, RestoreCheck};
+
+        type Fs<'a> = DynMemoryFs<'a, 8, 16>;
+
+        #[test]
+        fn mount_reads_files_in_place() {
+            let mut buffer = vec![0u8; Fs::superblock_size() + 2048];
+            {
+                let mut fs = Fs::format(&mut buffer, 64).unwrap();
+                fs.mkdir("cfg").unwrap();
+                fs.create("cfg/net", b"dhcp").unwrap();
+                fs.create("log", &[7u8; 100]).unwrap();
+                fs.sync().unwrap();
+            }
+
+            let range = buffer.as_ptr_range();
+            let mut fs = Fs::mount(&mut buffer).unwrap();
+            assert_eq!(fs.read("cfg/net").unwrap(), b"dhcp");
+            assert!(range.contains(&fs.read("log").unwrap().as_ptr()));
+            assert_eq!(fs.capacity("log"), Some(128));
+
+            // The remounted image keeps working, including new allocations.
+            fs.append("log", &[8u8; 100]).unwrap();
+            fs.create("new", b"x").unwrap();
+            fs.sync().unwrap();
+            let fs = Fs::mount(&mut buffer).unwrap();
+            assert_eq!(fs.read("log").unwrap().len(), 200);
+            assert_eq!(fs.read("new").unwrap(), b"x");
+        }
+
+        #[test]
+        fn mount_sees_last_sync() {
+            let mut buffer = vec![0u8; Fs::superblock_size() + 1024];
+            {
+                let mut fs = Fs::format(&mut buffer, 32).unwrap();
+                fs.create("a", b"a").unwrap();
+            }
+            let fs = Fs::mount(&mut buffer).unwrap();
+            assert!(!fs.exists("a"));
+        }
+
+        #[test]
+        fn reusing_synced_pages_invalidates_superblock() {
+            let mut buffer = vec![0u8; Fs::superblock_size() + 1024];
+            {
+                let mut fs = Fs::format(&mut buffer, 32).unwrap();
+                fs.create("a", b"old").unwrap();
+                fs.create("b", b"b").unwrap();
+                fs.sync().unwrap();
+                // Free pages can be used without losing the synced state.
+                fs.create("c", b"c").unwrap();
+            }
+            let mut fs = Fs::mount(&mut buffer).unwrap();
+            assert!(!fs.exists("c"));
+
+            // The pages of "a" now belong to "d".
+            fs.delete("a").unwrap();
+            fs.create("d", b"new").unwrap();
+            drop(fs);
+            assert!(matches!(
+                Fs::mount(&mut buffer),
+                Err(FsErr::Restore(err)) if err.check() == RestoreCheck::Magic
+            ));
+        }
+
+        #[test]
+        fn mount_rejects_damaged_superblock() {
+            let mut buffer = vec![0u8; Fs::superblock_size() + 1024];
+            assert!(matches!(
+                Fs::mount(&mut buffer),
+                Err(FsErr::Restore(err)) if err.check() == RestoreCheck::Magic && err.offset() == 0
+            ));
+
+            {
+                let mut fs = Fs::format(&mut buffer, 32).unwrap();
+                fs.create("a", b"a").unwrap();
+                fs.sync().unwrap();
+            }
+            buffer[30] ^= 1;
+            assert!(matches!(Fs::mount(&mut buffer), Err(FsErr::Restore(_))));
+        }
+
+        #[test]
+        fn format_and_sync_errors() {
+            let mut buffer = vec![0u8; Fs::superblock_size() - 1];
+            assert!(matches!(Fs::format(&mut buffer, 32), Err(FsErr::NoSpace)));
+
+            let mut fs = mem_fs::memfs!();
+            assert!(matches!(fs.sync(), Err(FsErr::InvalidOp)));
+        }
+    }
+
+    mod readonly {
+        use super::{FOOTER_SIZE, reseal};
+        use mem_fs::{DynMemoryFs, FileSystem, FsErr, MemoryFsRo, RestoreCheck};
+
+        fn sample_dump() -> Vec<u8> {
+            let mut fs = mem_fs::memfs!();
+            fs.mkdir("assets").unwrap();
+            fs.create("assets/logo", &[0xAB; 100]).unwrap();
+            fs.create("readme", b"read me").unwrap();
+
+            let mut dump = Vec::new();
+            fs.dump(|chunk| dump.extend_from_slice(chunk)).unwrap();
+            dump
+        }
+
+        #[test]
+        fn reads_dump_in_place() {
+            let dump = sample_dump();
+            let range = dump.as_ptr_range();
+            let fs: MemoryFsRo = MemoryFsRo::from_dump(&dump).unwrap();
+
+            let logo = fs.read("assets/logo").unwrap();
+            assert_eq!(logo, &[0xAB; 100]);
+            assert!(range.contains(&logo.as_ptr()));
+            assert_eq!(fs.read_at("readme", 5, 2).unwrap(), b"me");
+            assert!(fs.exists("assets"));
+            assert!(!fs.exists("missing"));
+            assert_eq!(fs.entries().count(), 3);
+            assert!(fs.metadata("assets").unwrap().is_dir());
+            assert_eq!(fs.read_dir("assets").unwrap().count(), 1);
+        }
+
+        #[test]
+        fn mutations_are_rejected() {
+            let dump = sample_dump();
+            let mut fs: MemoryFsRo = MemoryFsRo::from_dump(&dump).unwrap();
+
+            assert!(matches!(fs.create("new", b""), Err(FsErr::ReadOnly)));
+            assert!(matches!(
+                FileSystem::write(&mut fs, "readme", b"x"),
+                Err(FsErr::ReadOnly)
+            ));
+            assert!(matches!(fs.append("readme", b"x"), Err(FsErr::ReadOnly)));
+            assert!(matches!(fs.delete("readme"), Err(FsErr::ReadOnly)));
+            assert!(matches!(fs.rename("readme", "other"), Err(FsErr::ReadOnly)));
+            assert_eq!(fs.read("readme").unwrap(), b"read me");
+        }
+
+        #[test]
+        fn rejects_damaged_dump() {
+            let mut dump = sample_dump();
+            dump[40] ^= 1;
+            let result: Result<MemoryFsRo, _> = MemoryFsRo::from_dump(&dump);
+            assert!(matches!(
+                result,
+                Err(FsErr::Restore(err))
+                    if err.check() == RestoreCheck::Checksum && err.offset() == dump.len() - 4
+            ));
+
+            let result: Result<MemoryFsRo, _> = MemoryFsRo::from_dump(&dump[..10]);
+            assert!(matches!(
+                result,
+                Err(FsErr::Restore(err)) if err.check() == RestoreCheck::Truncated
+            ));
+        }
+
+        #[test]
+        fn rejects_payload_cut_inside_data_length() {
+            let mut fs = mem_fs::memfs!();
+            fs.mkdir("empty").unwrap();
+            let mut dump = Vec::new();
+            fs.dump(|chunk| dump.extend_from_slice(chunk)).unwrap();
+
+            // Drop half of the data length that ends the payload, and fix up the footer, so
+            // the footer would be read as the rest of the field.
+            let payload_len = dump.len() - FOOTER_SIZE - 2;
+            let mut cut = dump[..payload_len].to_vec();
+            cut.extend_from_slice(b"MEMFSEND");
+            cut.extend_from_slice(&(payload_len as u32).to_le_bytes());
+            cut.extend_from_slice(&[0; 4]);
+            reseal(&mut cut);
+
+            let result: Result<MemoryFsRo, _> = MemoryFsRo::from_dump(&cut);
+            assert!(matches!(
+                result,
+                Err(FsErr::Restore(err))
+                    if err.check() == RestoreCheck::Truncated && err.offset() == payload_len
+            ));
+        }
+
+        #[test]
+        fn rejects_compressed_dump() {
+            let mut fs = mem_fs::memfs!();
+            fs.create("readme", b"read me").unwrap();
+            let mut dump = Vec::new();
+            fs.dump_compressed(|chunk| dump.extend_from_slice(chunk))
+                .unwrap();
+
+            let result: Result<MemoryFsRo, _> = MemoryFsRo::from_dump(&dump);
+            assert!(matches!(result, Err(FsErr::InvalidOp)));
+        }
+
+        #[test]
+        fn reads_formatted_image() {
+            type Fs<'a> = DynMemoryFs<'a, 8, 16>;
+            let mut buffer = vec![0u8; Fs::superblock_size() + 1024];
+            {
+                let mut fs = Fs::format(&mut buffer, 32).unwrap();
+                fs.create("boot", b"kernel").unwrap();
+                fs.sync().unwrap();
+            }
+
+            let fs = MemoryFsRo::<8, 16>::from_image(&buffer).unwrap();
+            assert_eq!(fs.read("boot").unwrap(), b"kernel");
+        }
+    }
+
+    mod atomic {
+        use mem_fs::FileFlags;
+        use mem_fs::FsErr;
+
+        #[test]
+        fn atomic_write_uses_fresh_pages() {
+            let mut fs = mem_fs::memfs!();
+            fs.create("cfg", b"version=1").unwrap();
+            fs.create_with_flags("sum", b"abc", FileFlags::CHECKSUMMED)
+                .unwrap();
+
+            let old = fs.read("cfg").unwrap().as_ptr();
+            fs.atomic_write("cfg", b"version=2").unwrap();
+            assert_eq!(fs.read("cfg").unwrap(), b"version=2");
+            assert_ne!(fs.read("cfg").unwrap().as_ptr(), old);
+
+            assert_eq!(fs.capacity("cfg"), Some(32));
+
+            fs.atomic_write("sum", b"defg").unwrap();
+            assert_eq!(fs.read("sum").unwrap(), b"defg");
+
+            fs.atomic_write("new", b"created").unwrap();
+            assert_eq!(fs.read("new").unwrap(), b"created");
+            fs.atomic_write("new", b"").unwrap();
+            assert_eq!(fs.capacity("new"), Some(0));
+        }
+
+        #[test]
+        fn atomic_write_failure_keeps_old_contents() {
+            let mut fs = mem_fs::memfs!();
+            fs.create("big", &[1u8; 3000]).unwrap();
+            fs.create_with_flags("locked", b"data", FileFlags::IMMUTABLE)
+                .unwrap();
+
+            assert!(matches!(
+                fs.atomic_write("big", &[2u8; 3000]),
+                Err(FsErr::NoSpace)
+            ));
+            assert_eq!(fs.read("big").unwrap(), &[1u8; 3000]);
+            assert!(matches!(
+                fs.atomic_write("locked", b"x"),
+                Err(FsErr::ReadOnly)
+            ));
+        }
+
+        #[test]
+        fn rename_replace_overwrites_target() {
+            let mut fs = mem_fs::memfs!();
+            fs.mkdir("cfg").unwrap();
+            fs.create("cfg/current", &[1u8; 64]).unwrap();
+            fs.create("staging", b"new config").unwrap();
+
+            fs.rename_replace("staging", "cfg/current").unwrap();
+            assert!(!fs.exists("staging"));
+            assert_eq!(fs.read("cfg/current").unwrap(), b"new config");
+            assert_eq!(fs.entries().count(), 2);
+
+            // Without a target it is a plain rename.
+            fs.rename_replace("cfg/current", "cfg/backup").unwrap();
+            assert_eq!(fs.read("cfg/backup").unwrap(), b"new config");
+
+            // The two pages of the replaced file are free again.
+            fs.create("fill", &[0u8; 4096 - 32]).unwrap();
+        }
+
+        #[test]
+        fn rename_replace_honors_flags() {
+            let mut fs = mem_fs::memfs!();
+            fs.create_with_flags("sealed", b"s", FileFlags::SEALED_NAMES)
+                .unwrap();
+            fs.create_with_flags("locked", b"l", FileFlags::IMMUTABLE)
+                .unwrap();
+            fs.create("plain", b"p").unwrap();
+            fs.mkdir("dir").unwrap();
+
+            assert!(matches!(
+                fs.rename_replace("sealed", "plain"),
+                Err(FsErr::FileNameSealed)
+            ));
+            assert!(matches!(
+                fs.rename_replace("plain", "locked"),
+                Err(FsErr::ReadOnly)
+            ));
+            assert!(matches!(
+                fs.rename_replace("plain", "sealed"),
+                Err(FsErr::ReadOnly)
+            ));
+            assert!(matches!(
+                fs.rename_replace("plain", "dir"),
+                Err(FsErr::IsDirectory)
+            ));
+            assert!(matches!(
+                fs.rename_replace("dir", "plain"),
+                Err(FsErr::IsDirectory)
+            ));
+
+            assert_eq!(fs.read("sealed").unwrap(), b"s");
+            assert_eq!(fs.read("locked").unwrap(), b"l");
+            assert_eq!(fs.read("plain").unwrap(), b"p");
+        }
+    }
+
+    mod transactions {
+        use mem_fs::FsErr;
+
+        #[test]
+        fn transaction_commits_all_operations() {
+            let mut fs = mem_fs::memfs!();
+            fs.mkdir("config").unwrap();
+            fs.create("config/net", b"dhcp").unwrap();
+            fs.create("config/legacy", b"old").unwrap();
+
+            fs.transaction(|tx| {
+                tx.write("config/net", b"static 10.0.0.2")?;
+                tx.create("config/wifi", b"ssid=lab")?;
+                tx.delete("config/legacy")?;
+                assert!(tx.exists("config/wifi"));
+                Ok::<_, FsErr>(())
+            })
+            .unwrap();
+
+            assert_eq!(fs.read("config/net").unwrap(), b"static 10.0.0.2");
+            assert_eq!(fs.read("config/wifi").unwrap(), b"ssid=lab");
+            assert!(!fs.exists("config/legacy"));
+        }
+
+        #[test]
+        fn transaction_rolls_back_on_error() {
+            let mut fs = mem_fs::memfs!();
+            fs.create("a", b"alpha").unwrap();
+            fs.create("b", &[7u8; 70]).unwrap();
+            fs.create("c", b"charlie").unwrap();
+
+            let result = fs.transaction(|tx| {
+                tx.write_at("a", 0, b"ALPHA")?;
+                tx.write("b", b"short")?;
+                tx.truncate("c", 2)?;
+                tx.append("c", b"XXXXX")?;
+                tx.rename("a", "renamed")?;
+                tx.mkdir("dir")?;
+                tx.create("dir/new", &[1u8; 100])?;
+                tx.delete("missing")
+            });
+            assert!(matches!(result, Err(FsErr::NotFound)));
+
+            assert_eq!(fs.read("a").unwrap(), b"alpha");
+            assert_eq!(fs.read("b").unwrap(), &[7u8; 70]);
+            assert_eq!(fs.read("c").unwrap(), b"charlie");
+            assert!(!fs.exists("renamed"));
+            assert!(!fs.exists("dir"));
+
+            // Pages allocated during the transaction are free again.
+            fs.create("big", &[0u8; 4096 - 5 * 32]).unwrap();
+        }
+
+        #[test]
+        fn transaction_keeps_checksums_valid() {
+            use mem_fs::FileFlags;
+
+            let mut fs = mem_fs::memfs!();
+            fs.create_with_flags("sum", b"0123456789", FileFlags::CHECKSUMMED)
+                .unwrap();
+
+            let result: Result<(), _> = fs.transaction(|tx| {
+                tx.write_at("sum", 3, b"xyz")?;
+                assert_eq!(tx.read("sum")?, b"012xyz6789");
+                Err(FsErr::InvalidOp)
+            });
+            assert!(result.is_err());
+            assert_eq!(fs.read("sum").unwrap(), b"0123456789");
+        }
+
+        #[test]
+        fn freed_pages_are_reused_after_commit() {
+            let mut fs = mem_fs::memfs!();
+            fs.create("old", &[1u8; 2048]).unwrap();
+            fs.create("keep", &[2u8; 2048]).unwrap();
+
+            // The pages of "old" stay reserved until the transaction commits.
+            let result = fs.transaction(|tx| {
+                tx.delete("old")?;
+                tx.create("new", &[3u8; 2048])
+            });
+            assert!(matches!(result, Err(FsErr::NoSpace)));
+            assert_eq!(fs.read("old").unwrap(), &[1u8; 2048]);
+
+            fs.transaction(|tx| tx.delete("old")).unwrap();
+            fs.create("new", &[3u8; 2048]).unwrap();
+            assert_eq!(fs.read("keep").unwrap(), &[2u8; 2048]);
+        }
+
+        #[test]
+        fn failed_write_keeps_pages_protected() {
+            let mut fs = mem_fs::memfs!();
+            fs.create("a", &[1u8; 64]).unwrap();
+
+            let result = fs.transaction(|tx| {
+                // The failed reallocation hands the original pages back to the file.
+                assert!(matches!(tx.write("a", &[2u8; 4096]), Err(FsErr::NoSpace)));
+                tx.write_at("a", 0, &[3u8; 64])?;
+                tx.append("a", &[4u8; 32])?;
+                Err::<(), _>(FsErr::InvalidOp)
+            });
+            assert!(result.is_err());
+            assert_eq!(fs.read("a").unwrap(), &[1u8; 64]);
+        }
+    }
+
+    mod filesystem {
+        use mem_fs::{FileSystem, HostFs};
+
+        // Asset-loader style code that only knows about the trait.
+        fn exercise<F: FileSystem>(fs: &mut F) -> Result<(), F::Error> {
+            fs.create("config.txt", b"volume=3")?;
+            assert!(fs.create("config.txt", b"").is_err());
+
+            fs.write_at("config.txt", 7, b"7")?;
+            fs.append("config.txt", b"\nmuted=0")?;
+            fs.truncate("config.txt", 14)?;
+            assert!(fs.truncate("config.txt", 100).is_err());
+            assert!(fs.write_at("config.txt", 100, b"x").is_err());
+
+            let mut buf = [0u8; 32];
+            let len = fs.read_into("config.txt", &mut buf)?;
+            assert_eq!(&buf[..len], b"volume=7\nmuted");
+
+            let meta = fs.metadata("config.txt")?;
+            assert!(meta.is_file());
+            assert_eq!(meta.len(), 14);
+
+            fs.write("save.bin", &[1, 2, 3])?;
+            fs.rename("save.bin", "save.old")?;
+            assert!(!fs.exists("save.bin"));
+            assert!(fs.rename("save.old", "config.txt").is_err());
+
+            assert!(fs.exists("/"));
+            assert!(fs.metadata("/")?.is_dir());
+
+            let mut names: Vec<String> = Vec::new();
+            for entry in fs.read_dir("/")? {
+                names.push(entry?.name.as_str().into());
+            }
+            names.sort();
+            assert_eq!(names, ["config.txt", "save.old"]);
+
+            fs.delete("save.old")?;
+            assert!(!fs.exists("save.old"));
+            assert!(fs.metadata("save.old").is_err());
+            Ok(())
+        }
+
+        #[test]
+        fn memory_fs_implements_trait() {
+            let mut fs = mem_fs::memfs!();
+            exercise(&mut fs).unwrap();
+
+            fs.mkdir("assets").unwrap();
+            fs.create("assets/logo.bin", &[0u8; 40]).unwrap();
+            assert!(FileSystem::metadata(&fs, "assets").unwrap().is_dir());
+            assert!(FileSystem::metadata(&fs, "/").unwrap().is_dir());
+
+            let entries: Vec<_> = FileSystem::read_dir(&fs, "assets")
+                .unwrap()
+                .map(Result::unwrap)
+                .collect();
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].name, "logo.bin");
+            assert_eq!(entries[0].metadata.len(), 40);
+        }
+
+        #[test]
+        fn host_fs_implements_trait() {
+            let root = std::env::temp_dir().join(format!("mem-fs-host-{}", std::process::id()));
+            std::fs::create_dir_all(&root).unwrap();
+
+            let mut fs = HostFs::new(&root);
+            let result = exercise(&mut fs);
+            std::fs::remove_dir_all(&root).unwrap();
+            result.unwrap();
+        }
+    }
+
+    mod persistence {
+        use super::{FOOTER_SIZE, dump_to_vec, reader, reseal};
+        use mem_fs::DEFAULT_PAGE_SIZE;
+        use mem_fs::DEFAULT_STORAGE_SIZE;
+        use mem_fs::DynMemoryFs;
+        use mem_fs::FileFlags;
+        use mem_fs::FsErr;
+        use mem_fs::MemFs;
+        use mem_fs::RestoreCheck;
+
+        #[test]
+        fn dump_restore_roundtrip_basic() {
+            let mut fs = mem_fs::memfs!();
+            fs.create("foo", b"hello").unwrap();
+            fs.create("bar", b"world!!").unwrap();
+
+            let data = dump_to_vec(&fs);
+
+            let mut fs2 = mem_fs::memfs!();
+            fs2.restore(reader(&data)).unwrap();
+
+            assert_eq!(fs2.read("foo").unwrap(), b"hello");
+            assert_eq!(fs2.read("bar").unwrap(), b"world!!");
+        }
+
+        #[test]
+        fn dump_restore_empty_fs() {
+            let fs = mem_fs::memfs!();
+            let data = dump_to_vec(&fs);
+
+            let mut fs2 = mem_fs::memfs!();
+            fs2.restore(reader(&data)).unwrap();
+
+            assert_eq!(fs2.entries().count(), 0);
+        }
+
+        #[test]
+        fn dump_restore_keeps_checksums() {
+            let mut fs = mem_fs::memfs!();
+            fs.create_with_flags("cal", b"calibration", FileFlags::CHECKSUMMED)
+                .unwrap();
+
+            let data = dump_to_vec(&fs);
+
+            let mut fs2 = mem_fs::memfs!();
+            fs2.restore(reader(&data)).unwrap();
+
+            assert_eq!(fs2.read("cal").unwrap(), b"calibration");
+        }
+
+        #[test]
+        fn checksummed_bit_flip_is_detected() {
+            let mut fs = mem_fs::memfs!();
+            fs.create_with_flags("cal", b"calibration", FileFlags::CHECKSUMMED)
+                .unwrap();
+            fs.create("plain", b"plain").unwrap();
+
+            let mut data = dump_to_vec(&fs);
+
+            // "cal" occupies the first page of the data, followed by "plain".
+            let data_start = data.len() - FOOTER_SIZE - 2 * DEFAULT_PAGE_SIZE;
+            data[data_start + 3] ^= 0x04;
+            reseal(&mut data);
+
+            let mut fs2 = mem_fs::memfs!();
+            fs2.restore(reader(&data)).unwrap();
+
+            assert!(matches!(fs2.read("cal"), Err(FsErr::Corrupt)));
+            assert!(matches!(fs2.read_at("cal", 0, 1), Err(FsErr::Corrupt)));
+            assert_eq!(fs2.read("plain").unwrap(), b"plain");
+        }
+
+        #[test]
+        fn dump_restore_fragmented_file() {
+            let mut fs = mem_fs::memfs!();
+            fs.create("a", b"first").unwrap();
+            fs.create("b", b"blocker").unwrap();
+            fs.append("a", &[7u8; 64]).unwrap();
+
+            let data = dump_to_vec(&fs);
+
+            let mut fs2 = mem_fs::memfs!();
+            fs2.restore(reader(&data)).unwrap();
+
+            let mut buf = [0u8; 69];
+            assert_eq!(fs2.read_into("a", &mut buf).unwrap(), 69);
+            assert_eq!(&buf[..5], b"first");
+            assert_eq!(&buf[5..], &[7u8; 64]);
+            assert_eq!(fs2.read("b").unwrap(), b"blocker");
+        }
+
+        #[test]
+        fn dump_restore_keeps_directories() {
+            let mut fs = mem_fs::memfs!();
+            fs.mkdir("config").unwrap();
+            fs.mkdir("config/net").unwrap();
+            fs.create("config/net/wifi", b"ssid").unwrap();
+            fs.create("top", b"top").unwrap();
+
+            let data = dump_to_vec(&fs);
+
+            let mut fs2 = mem_fs::memfs!();
+            fs2.restore(reader(&data)).unwrap();
+
+            assert_eq!(fs2.read("config/net/wifi").unwrap(), b"ssid");
+            assert_eq!(fs2.read("top").unwrap(), b"top");
+            assert_eq!(fs2.read_dir("config").unwrap().count(), 1);
+
+            // New entries must not reuse restored ids.
+            fs2.mkdir("config/other").unwrap();
+            assert_eq!(fs2.read_dir("config/net").unwrap().count(), 1);
+            assert_eq!(fs2.read_dir("config/other").unwrap().count(), 0);
+        }
+
+        #[test]
+        fn restore_rejects_bad_magic() {
+            let fs = mem_fs::memfs!();
+            let mut data = dump_to_vec(&fs);
+
+            data[0] ^= 0xFF; // corrupt first magic byte
+
+            let mut fs2 = mem_fs::memfs!();
+            assert!(matches!(
+                fs2.restore(reader(&data)),
+                Err(FsErr::Restore(err)) if err.check() == RestoreCheck::Magic && err.offset() == 0
+            ));
+        }
+
+        #[test]
+        fn restore_rejects_truncated_dump() {
+            let mut fs = mem_fs::memfs!();
+            fs.create("foo", b"hello").unwrap();
+
+            let data = dump_to_vec(&fs);
+            let truncated = &data[..data.len() - 1];
+
+            let mut fs2 = mem_fs::memfs!();
+            assert!(fs2.restore(reader(truncated)).is_err());
+        }
+
+        #[test]
+        fn restore_reports_where_stream_ends() {
+            let mut fs = mem_fs::memfs!();
+            fs.create("foo", &[7u8; 100]).unwrap();
+            let data = dump_to_vec(&fs);
+
+            // Ends within the data section: the offset is where the failed read started.
+            let cut = data.len() - FOOTER_SIZE - 10;
+            let mut fs2 = mem_fs::memfs!();
+            let Err(FsErr::Restore(err)) = fs2.restore(reader(&data[..cut])) else {
+                panic!("truncated stream not reported");
+            };
+            assert_eq!(err.check(), RestoreCheck::Truncated);
+            assert!(err.offset() > 0 && err.offset() <= cut);
+
+            // Ends within the footer.
+            let footer_start = data.len() - FOOTER_SIZE;
+            let mut fs2 = mem_fs::memfs!();
+            assert!(matches!(
+                fs2.restore(reader(&data[..data.len() - 2])),
+                Err(FsErr::Restore(err))
+                    if err.check() == RestoreCheck::Truncated && err.offset() == footer_start + 12
+            ));
+        }
+
+        #[test]
+        fn restore_rejects_storage_len_mismatch() {
+            let fs = mem_fs::memfs!();
+            let mut data = dump_to_vec(&fs);
+
+            // Patch data_len, the last 4 bytes before the (empty) data section.
+            let off = data.len() - FOOTER_SIZE - 4;
+            let bad = (DEFAULT_PAGE_SIZE as u32).to_le_bytes();
+            data[off..off + 4].copy_from_slice(&bad);
+
+            let mut fs2 = mem_fs::memfs!();
+            assert!(matches!(
+                fs2.restore(reader(&data)),
+                Err(FsErr::Restore(err))
+                    if err.check() == RestoreCheck::DataLength && err.offset() == off + 4
+            ));
+        }
+
+        #[test]
+        fn dump_leaves_out_free_pages() {
+            let mut fs = mem_fs::memfs!();
+            fs.create("big", &[1u8; 2048]).unwrap();
+            fs.create("small", b"small").unwrap();
+            fs.delete("big").unwrap();
+
+            let data = dump_to_vec(&fs);
+            assert_eq!(data.len(), fs.dump_size());
+            assert!(data.len() < DEFAULT_PAGE_SIZE * 2 + 64);
+
+            // Stale bytes in the target are cleared, only restored files keep data.
+            let mut storage = [0xEEu8; DEFAULT_STORAGE_SIZE];
+            let mut fs2 = mem_fs::MemoryFs::<DEFAULT_STORAGE_SIZE, DEFAULT_PAGE_SIZE>::from_backed(
+                &mut storage,
+            );
+            fs2.restore(reader(&data)).unwrap();
+            assert_eq!(fs2.read("small").unwrap(), b"small");
+            drop(fs2);
+            assert!(storage.iter().all(|&b| b == 0 || b"small".contains(&b)));
+        }
+
+        /// A version 2 dump with the file `logs/today` on page 3: one extent per file, no
+        /// checksums, and the whole storage.
+        fn version_2_dump() -> Vec<u8> {
+            let mut data = Vec::new();
+            data.extend_from_slice(b"MEMFS\x02");
+            data.extend_from_slice(&(DEFAULT_PAGE_SIZE as u32).to_le_bytes());
+            data.extend_from_slice(
+                &((DEFAULT_STORAGE_SIZE / DEFAULT_PAGE_SIZE) as u32).to_le_bytes(),
+            );
+            data.extend_from_slice(&1u32.to_le_bytes());
+            data.extend_from_slice(&10u16.to_le_bytes());
+            data.extend_from_slice(b"logs/today");
+            data.extend_from_slice(&5u32.to_le_bytes()); // size
+            data.extend_from_slice(&0u32.to_le_bytes()); // flags
+            data.extend_from_slice(&3u32.to_le_bytes()); // start page
+            data.extend_from_slice(&1u32.to_le_bytes()); // pages
+            data.extend_from_slice(&(DEFAULT_STORAGE_SIZE as u32).to_le_bytes());
+            let mut storage = [0u8; DEFAULT_STORAGE_SIZE];
+            storage[3 * DEFAULT_PAGE_SIZE..][..5].copy_from_slice(b"entry");
+            data.extend_from_slice(&storage);
+            let body_len = data.len() as u32;
+            data.extend_from_slice(b"MEMFSEND");
+            data.extend_from_slice(&body_len.to_le_bytes());
+            data.extend_from_slice(&[0; 4]);
+            reseal(&mut data);
+            data
+        }
+
+        #[test]
+        fn restore_accepts_version_2_dump() {
+            let mut fs = mem_fs::memfs!();
+            fs.restore(reader(&version_2_dump())).unwrap();
+            assert_eq!(fs.read("logs/today").unwrap(), b"entry");
+            assert_eq!(fs.read_dir("logs").unwrap().count(), 1);
+        }
+
+        #[test]
+        fn restore_relayouts_into_other_page_size() {
+            let mut fs = mem_fs::memfs!();
+            fs.mkdir("cfg").unwrap();
+            fs.create_with_flags("cfg/cal", b"calibration", FileFlags::CHECKSUMMED)
+                .unwrap();
+            fs.create("a", b"first").unwrap();
+            fs.create("b", b"blocker").unwrap();
+            fs.create("filler", &[0u8; 123 * mem_fs::DEFAULT_PAGE_SIZE])
+                .unwrap();
+            fs.append("a", &[7u8; 64]).unwrap();
+            fs.delete("filler").unwrap();
+            fs.create("empty", b"").unwrap();
+            assert_eq!(
+                fs.entries().find(|f| f.name == "a").unwrap().extent_count(),
+                2
+            );
+            let data = dump_to_vec(&fs);
+
+            for page_size in [16, 128] {
+                let mut storage = [0xEEu8; 2048];
+                let mut fs2: DynMemoryFs =
+                    DynMemoryFs::from_slice(&mut storage, page_size).unwrap();
+                fs2.restore(reader(&data)).unwrap();
+
+                assert_eq!(fs2.read("cfg/cal").unwrap(), b"calibration");
+                let mut buf = [0u8; 69];
+                assert_eq!(fs2.read_into("a", &mut buf).unwrap(), 69);
+                assert_eq!(&buf[..5], b"first");
+                assert_eq!(&buf[5..], &[7u8; 64]);
+                assert_eq!(fs2.read("b").unwrap(), b"blocker");
+                assert_eq!(fs2.read("empty").unwrap(), b"");
+                fs2.create("cfg/new", &[1u8; 100]).unwrap();
+            }
+        }
+
+        #[test]
+        fn restore_relayouts_legacy_dump() {
+            let mut storage = [0u8; 1024];
+            let mut fs: DynMemoryFs = DynMemoryFs::from_slice(&mut storage, 64).unwrap();
+            fs.restore(reader(&version_2_dump())).unwrap();
+            assert_eq!(fs.read("logs/today").unwrap(), b"entry");
+        }
+
+        #[test]
+        fn restore_relayout_needs_room_for_files() {
+            let mut fs = mem_fs::memfs!();
+            fs.create("big", &[3u8; 1000]).unwrap();
+            let data = dump_to_vec(&fs);
+
+            let mut storage = [0u8; 512];
+            let mut fs2: DynMemoryFs = DynMemoryFs::from_slice(&mut storage, 32).unwrap();
+            assert!(matches!(fs2.restore(reader(&data)), Err(FsErr::NoSpace)));
+        }
+
+        #[test]
+        fn restore_skips_fields_of_newer_minor_version() {
+            let mut fs = mem_fs::memfs!();
+            fs.create("file", b"contents").unwrap();
+            let mut data = dump_to_vec(&fs);
+
+            // Minor version 1 with a 4 byte header field and a 2 byte entry field appended.
+            let header_end = 9 + 16;
+            data[6] = 1;
+            data[7..9].copy_from_slice(&20u16.to_le_bytes());
+            data.splice(header_end..header_end, [0xAA; 4]);
+            let entry_start = header_end + 4;
+            let entry_len = u16::from_le_bytes([data[entry_start], data[entry_start + 1]]);
+            data[entry_start..entry_start + 2].copy_from_slice(&(entry_len + 2).to_le_bytes());
+            let entry_end = entry_start + 2 + entry_len as usize;
+            data.splice(entry_end..entry_end, [0xBB; 2]);
+            let body_len = (data.len() - FOOTER_SIZE) as u32;
+            let len_off = data.len() - 8;
+            data[len_off..len_off + 4].copy_from_slice(&body_len.to_le_bytes());
+            reseal(&mut data);
+
+            let mut fs2 = mem_fs::memfs!();
+            fs2.restore(reader(&data)).unwrap();
+            assert_eq!(fs2.read("file").unwrap(), b"contents");
+        }
+
+        #[test]
+        fn restore_rejects_newer_major_version() {
+            let fs = mem_fs::memfs!();
+            let mut data = dump_to_vec(&fs);
+            data[5] += 1;
+            reseal(&mut data);
+
+            let mut fs2 = mem_fs::memfs!();
+            assert!(matches!(
+                fs2.restore(reader(&data)),
+                Err(FsErr::Restore(err)) if err.check() == RestoreCheck::Version && err.offset() == 5
+            ));
+        }
+
+        #[test]
+        fn restore_reports_failed_check_and_offset() {
+            let mut fs = mem_fs::memfs!();
+            fs.create("name", b"contents").unwrap();
+            let data = dump_to_vec(&fs);
+
+            let mut damaged = data.clone();
+            let crc_off = damaged.len() - 4;
+            damaged[crc_off] ^= 1;
+            let mut fs2 = mem_fs::memfs!();
+            let Err(FsErr::Restore(err)) = fs2.restore(reader(&damaged)) else {
+                panic!("checksum mismatch not reported");
+            };
+            assert_eq!(err.check(), RestoreCheck::Checksum);
+            assert_eq!(err.offset(), crc_off);
+
+            let mut damaged = data.clone();
+            let name_off = damaged.windows(4).position(|w| w == b"name").unwrap();
+            damaged[name_off] = 0xFF;
+            reseal(&mut damaged);
+            let mut fs2 = mem_fs::memfs!();
+            let Err(FsErr::Restore(err)) = fs2.restore(reader(&damaged)) else {
+                panic!("invalid name not reported");
+            };
+            assert_eq!(err.check(), RestoreCheck::Name);
+            assert_eq!(err.offset(), name_off + 4);
+            assert_eq!(
+                FsErr::Restore(err).to_string(),
+                format!("restore failed: invalid file name at byte {}", name_off + 4)
+            );
+        }
+
+        #[test]
+        fn restore_rejects_ids_out_of_order() {
+            let mut fs = mem_fs::memfs!();
+            fs.create("first", b"1").unwrap();
+            fs.create("second", b"2").unwrap();
+            let mut data = dump_to_vec(&fs);
+
+            // Swap the ids that follow each name.
+            let first = data.windows(5).position(|w| w == b"first").unwrap() + 5;
+            let second = data.windows(6).position(|w| w == b"second").unwrap() + 6;
+            for i in 0..4 {
+                data.swap(first + i, second + i);
+            }
+            reseal(&mut data);
+
+            let mut fs2 = mem_fs::memfs!();
+            let Err(FsErr::Restore(err)) = fs2.restore(reader(&data)) else {
+                panic!("unsorted ids not reported");
+            };
+            assert_eq!(err.check(), RestoreCheck::Tree);
+            assert_eq!(fs2.entries().count(), 0);
+        }
+
+        #[test]
+        fn failed_restore_can_be_retried() {
+            let mut fs = mem_fs::memfs!();
+            fs.mkdir("dir").unwrap();
+            fs.create("dir/file", b"contents").unwrap();
+            let data = dump_to_vec(&fs);
+
+            let mut damaged = data.clone();
+            let crc_off = damaged.len() - 4;
+            damaged[crc_off] ^= 1;
+            let mut fs2 = mem_fs::memfs!();
+            assert!(matches!(
+                fs2.restore(reader(&damaged)),
+                Err(FsErr::Restore(err)) if err.check() == RestoreCheck::Checksum
+            ));
+            assert_eq!(fs2.entries().count(), 0);
+
+            // A stream that ends inside the data section.
+            assert!(fs2.restore(reader(&data[..data.len() - 20])).is_err());
+            assert_eq!(fs2.entries().count(), 0);
+
+            fs2.restore(reader(&data)).unwrap();
+            assert_eq!(fs2.read("dir/file").unwrap(), b"contents");
+
+            // A filesystem that is not empty is left alone.
+            assert!(matches!(fs2.restore(reader(&data)), Err(FsErr::InvalidOp)));
+            assert_eq!(fs2.read("dir/file").unwrap(), b"contents");
+        }
+
+        fn dump_compressed_to_vec(fs: &MemFs) -> Vec<u8> {
+            let mut out = Vec::new();
+            fs.dump_compressed(|chunk| out.extend_from_slice(chunk))
+                .unwrap();
+            out
+        }
+
+        #[test]
+        fn compressed_dump_roundtrip() {
+            let mut fs = mem_fs::memfs!();
+            let config = b"mode=station\nretries=3\n".repeat(20);
+            fs.create("config", &config).unwrap();
+            // Incompressible data, longer than the compression window.
+            let mut state = 7u32;
+            let noise: Vec<u8> = (0..3000)
+                .map(|_| {
+                    state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                    (state >> 16) as u8
+                })
+                .collect();
+            fs.create("noise", &noise).unwrap();
+
+            let data = dump_compressed_to_vec(&fs);
+            let mut fs2 = mem_fs::memfs!();
+            fs2.restore(reader(&data)).unwrap();
+            assert_eq!(fs2.read("config").unwrap(), config.as_slice());
+            assert_eq!(fs2.read("noise").unwrap(), noise.as_slice());
+        }
+
+        #[test]
+        fn compressed_dump_is_smaller() {
+            let mut fs = mem_fs::memfs!();
+            fs.create("config", &b"log_level=debug\n".repeat(100))
+                .unwrap();
+            fs.create("empty_log", &[0u8; 1024]).unwrap();
+
+            let plain = dump_to_vec(&fs);
+            let compressed = dump_compressed_to_vec(&fs);
+            assert!(compressed.len() * 5 < plain.len());
+        }
+
+        #[test]
+        fn compressed_dump_checks_decompressed_payload() {
+            let mut fs = mem_fs::memfs!();
+            fs.create("config", &b"key=value\n".repeat(30)).unwrap();
+            let mut data = dump_compressed_to_vec(&fs);
+
+            // Reseal the damaged compressed bytes: the checksum covers the decompressed stream.
+            let off = data.len() - FOOTER_SIZE - 5;
+            data[off] ^= 0x10;
+            reseal(&mut data);
+
+            let mut fs2 = mem_fs::memfs!();
+            assert!(fs2.restore(reader(&data)).is_err());
+        }
+    }
+
+    mod snapshots {
+        use super::{dump_to_vec, reader, reseal};
+        use mem_fs::{DEFAULT_PAGE_SIZE, FsErr, RestoreCheck};
+
+        #[test]
+        fn deltas_apply_on_restored_base() {
+            let mut fs = mem_fs::memfs!();
+            fs.create("log", &[b'.'; 1000]).unwrap();
+            fs.create("config", b"v1").unwrap();
+            fs.create("old", b"old").unwrap();
+            let base = dump_to_vec(&fs);
+            let since = fs.checkpoint();
+
+            fs.write_at("log", 10, b"entry").unwrap();
+            fs.write("config", b"v2").unwrap();
+            fs.delete("old").unwrap();
+            fs.mkdir("new").unwrap();
+            let mut delta = Vec::new();
+            fs.dump_delta(since, |chunk| delta.extend_from_slice(chunk))
+                .unwrap();
+            assert!(delta.len() * 4 < base.len());
+            let since = fs.checkpoint();
+
+            fs.append("log", b"more").unwrap();
+            let mut delta2 = Vec::new();
+            fs.dump_delta(since, |chunk| delta2.extend_from_slice(chunk))
+                .unwrap();
+
+            let mut fs2 = mem_fs::memfs!();
+            fs2.restore(reader(&base)).unwrap();
+            fs2.restore_delta(reader(&delta)).unwrap();
+            fs2.restore_delta(reader(&delta2)).unwrap();
+
+            let mut log = [0u8; 1004];
+            assert_eq!(fs2.read_into("log", &mut log).unwrap(), 1004);
+            assert_eq!(&log[10..15], b"entry");
+            assert_eq!(&log[1000..], b"more");
+            assert_eq!(fs2.read("config").unwrap(), b"v2");
+            assert!(!fs2.exists("old"));
+            assert!(fs2.exists("new"));
+        }
+
+        #[test]
+        fn delta_leaves_out_unchanged_pages() {
+            let mut fs = mem_fs::memfs!();
+            fs.create("data", &[1u8; 2000]).unwrap();
+            let since = fs.checkpoint();
+            fs.rename("data", "renamed").unwrap();
+
+            let mut delta = Vec::new();
+            fs.dump_delta(since, |chunk| delta.extend_from_slice(chunk))
+                .unwrap();
+            assert!(delta.len() < 100);
+        }
+
+        #[test]
+        fn transaction_only_dirties_written_pages() {
+            let mut fs = mem_fs::memfs!();
+            fs.create("a", b"a").unwrap();
+            fs.create("b", &[2u8; 1000]).unwrap();
+            let base = dump_to_vec(&fs);
+            let since = fs.checkpoint();
+
+            fs.transaction(|tx| tx.create("c", b"c")).unwrap();
+            let mut delta = Vec::new();
+            fs.dump_delta(since, |chunk| delta.extend_from_slice(chunk))
+                .unwrap();
+
+            // The runs are the last thing before the footer; "c" went to the first free page.
+            let field =
+                |offset: usize| u32::from_le_bytes(delta[offset..offset + 4].try_into().unwrap());
+            let run = delta.len() - 16 - DEFAULT_PAGE_SIZE - 8;
+            assert_eq!(field(run - 4), 1);
+            assert_eq!(
+                field(run) as usize,
+                1 + 1000usize.div_ceil(DEFAULT_PAGE_SIZE)
+            );
+            assert_eq!(field(run + 4), 1);
+
+            let mut fs2 = mem_fs::memfs!();
+            fs2.restore(reader(&base)).unwrap();
+            fs2.restore_delta(reader(&delta)).unwrap();
+            assert_eq!(fs2.read("c").unwrap(), b"c");
+            assert_eq!(fs2.read("b").unwrap(), &[2u8; 1000]);
+        }
+
+        #[test]
+        fn truncated_delta_is_reported() {
+            let mut fs = mem_fs::memfs!();
+            let base = dump_to_vec(&fs);
+            let since = fs.checkpoint();
+            fs.create("a", &[1u8; 100]).unwrap();
+            let mut delta = Vec::new();
+            fs.dump_delta(since, |chunk| delta.extend_from_slice(chunk))
+                .unwrap();
+
+            let mut fs2 = mem_fs::memfs!();
+            fs2.restore(reader(&base)).unwrap();
+            let cut = delta.len() / 2;
+            assert!(matches!(
+                fs2.restore_delta(reader(&delta[..cut])),
+                Err(FsErr::Restore(err))
+                    if err.check() == RestoreCheck::Truncated && err.offset() <= cut
+            ));
+        }
+
+        #[test]
+        fn deltas_must_match_their_base() {
+            let mut fs = mem_fs::memfs!();
+            let base = dump_to_vec(&fs);
+            let first = fs.checkpoint();
+            fs.create("a", b"a").unwrap();
+            let mut delta = Vec::new();
+            fs.dump_delta(first, |chunk| delta.extend_from_slice(chunk))
+                .unwrap();
+            let second = fs.checkpoint();
+            fs.create("b", b"b").unwrap();
+            let mut delta2 = Vec::new();
+            fs.dump_delta(second, |chunk| delta2.extend_from_slice(chunk))
+                .unwrap();
+
+            // Changes are only tracked since the last checkpoint.
+            assert!(matches!(
+                fs.dump_delta(first, |_| {}),
+                Err(FsErr::InvalidOp)
+            ));
+
+            let mut fs2 = mem_fs::memfs!();
+            fs2.restore(reader(&base)).unwrap();
+            assert!(matches!(
+                fs2.restore_delta(reader(&delta2)),
+                Err(FsErr::InvalidOp)
+            ));
+            fs2.restore_delta(reader(&delta)).unwrap();
+            fs2.restore_delta(reader(&delta2)).unwrap();
+            assert_eq!(fs2.read("b").unwrap(), b"b");
+        }
+
+        #[test]
+        fn failed_delta_leaves_filesystem_empty() {
+            let mut fs = mem_fs::memfs!();
+            fs.create("a", b"a").unwrap();
+            let base = dump_to_vec(&fs);
+            let since = fs.checkpoint();
+            fs.create("b", &[2u8; 100]).unwrap();
+            let mut delta = Vec::new();
+            fs.dump_delta(since, |chunk| delta.extend_from_slice(chunk))
+                .unwrap();
+
+            let mut fs2 = mem_fs::memfs!();
+            fs2.restore(reader(&base)).unwrap();
+            let crc_off = delta.len() - 4;
+            delta[crc_off] ^= 1;
+            assert!(matches!(
+                fs2.restore_delta(reader(&delta)),
+                Err(FsErr::Restore(err)) if err.check() == RestoreCheck::Checksum
+            ));
+            assert_eq!(fs2.entries().count(), 0);
+
+            // Start over from the full dump.
+            delta[crc_off] ^= 1;
+            fs2.restore(reader(&base)).unwrap();
+            fs2.restore_delta(reader(&delta)).unwrap();
+            assert_eq!(fs2.read("b").unwrap(), &[2u8; 100]);
+        }
+
+        #[test]
+        fn delta_entries_carry_their_dump_version() {
+            let mut fs = mem_fs::memfs!();
+            let base = dump_to_vec(&fs);
+            let since = fs.checkpoint();
+            fs.create("a", b"a").unwrap();
+            let mut delta = Vec::new();
+            fs.dump_delta(since, |chunk| delta.extend_from_slice(chunk))
+                .unwrap();
+
+            // The dump version follows the magic and delta version.
+            let mut future = delta.clone();
+            future[9] += 1;
+            reseal(&mut future);
+
+            let mut fs2 = mem_fs::memfs!();
+            fs2.restore(reader(&base)).unwrap();
+            assert!(matches!(
+                fs2.restore_delta(reader(&future)),
+                Err(FsErr::Restore(err)) if err.check() == RestoreCheck::Version
+            ));
+            fs2.restore_delta(reader(&delta)).unwrap();
+            assert_eq!(fs2.read("a").unwrap(), b"a");
+        }
+    }
+
     #[cfg(not(feature = "std"))]
     #[test]
     fn no_std_builds() {
@@ -0,0 +1,221 @@
+use core::ops::Deref;
+use heapless::Vec;
+
+use crate::{
+    Allocator, DEFAULT_BITMAP_WORDS, DEFAULT_MAX_FILES, DEFAULT_MAX_NAME_LEN, FileEntry, FileFlags,
+    FirstFit, FsErr, MemoryFs,
+};
+
+/// A batch of operations that is applied all-or-nothing.
+///
+/// Created by `MemoryFs::transaction`. Read-only methods of `MemoryFs` are available through
+/// `Deref` and see the changes made so far.
+///
+/// While a transaction runs, pages that were in use when it started are neither reused nor
+/// overwritten: writes to such pages first copy the file to fresh pages. A transaction
+/// therefore needs free space for the data it changes, and freed pages only become available
+/// again once it commits.
+///
+/// If the transaction is dropped without committing (including on panic), every change is
+/// undone.
+pub struct Transaction<
+    'f,
+    'a,
+    const STORAGE_SIZE: usize,
+    const PAGE_SIZE: usize,
+    const MAX_FILES: usize = DEFAULT_MAX_FILES,
+    const MAX_NAME_LEN: usize = DEFAULT_MAX_NAME_LEN,
+    const BITMAP_WORDS: usize = DEFAULT_BITMAP_WORDS,
+    A: Allocator = FirstFit,
+> {
+    fs: &'f mut MemoryFs<'a, STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>,
+    entries: Vec<FileEntry<MAX_NAME_LEN>, MAX_FILES>, // Entry table when the transaction started.
+    name_index: Vec<usize, MAX_FILES>,
+    dirty_pages: Vec<u32, BITMAP_WORDS>, // Dirty pages when the transaction started.
+    next_id: u32,
+    committed: bool,
+}
+
+impl<
+    'a,
+    const STORAGE_SIZE: usize,
+    const PAGE_SIZE: usize,
+    const MAX_FILES: usize,
+    const MAX_NAME_LEN: usize,
+    const BITMAP_WORDS: usize,
+    A: Allocator,
+> MemoryFs<'a, STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>
+{
+    /// Run `f` as a transaction: either all of its operations are applied or none are.
+    ///
+    /// The transaction commits when `f` returns `Ok`. When `f` returns `Err` the filesystem is
+    /// rolled back to the state before the call, and the error is returned.
+    ///
+    /// The entry table and the dirty page bitmap are copied for the duration of the transaction.
+    pub fn transaction<T, E>(
+        &mut self,
+        f: impl FnOnce(
+            &mut Transaction<
+                '_,
+                'a,
+                STORAGE_SIZE,
+                PAGE_SIZE,
+                MAX_FILES,
+                MAX_NAME_LEN,
+                BITMAP_WORDS,
+                A,
+            >,
+        ) -> Result<T, E>,
+    ) -> Result<T, E> {
+        let mut tx = Transaction::begin(self);
+        let result = f(&mut tx);
+        if result.is_ok() {
+            tx.commit();
+        }
+        result
+    }
+}
+
+impl<
+    'f,
+    'a,
+    const STORAGE_SIZE: usize,
+    const PAGE_SIZE: usize,
+    const MAX_FILES: usize,
+    const MAX_NAME_LEN: usize,
+    const BITMAP_WORDS: usize,
+    A: Allocator,
+> Transaction<'f, 'a, STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>
+{
+    fn begin(
+        fs: &'f mut MemoryFs<'a, STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>,
+    ) -> Self {
+        // From here on the dirty pages are those allocated or written by the transaction.
+        let dirty_pages = fs.dirty_pages.clone();
+        fs.clear_dirty_pages();
+        fs.in_transaction = true;
+        Self {
+            entries: fs.entries.clone(),
+            name_index: fs.name_index.clone(),
+            dirty_pages,
+            next_id: fs.next_id,
+            fs,
+            committed: false,
+        }
+    }
+
+    /// Keep all changes and release the pages freed during the transaction.
+    fn commit(mut self) {
+        self.fs.in_transaction = false;
+        self.fs.rebuild_page_bitmap();
+        self.committed = true;
+    }
+
+    /// See `MemoryFs::create`.
+    pub fn create(&mut self, name: &str, data: &[u8]) -> Result<(), FsErr> {
+        self.fs.create(name, data)
+    }
+
+    /// See `MemoryFs::create_with_flags`.
+    pub fn create_with_flags(
+        &mut self,
+        name: &str,
+        data: &[u8],
+        flags: FileFlags,
+    ) -> Result<(), FsErr> {
+        self.fs.create_with_flags(name, data, flags)
+    }
+
+    /// See `MemoryFs::write`.
+    pub fn write(&mut self, name: &str, data: &[u8]) -> Result<(), FsErr> {
+        self.fs.write(name, data)
+    }
+
+    /// See `MemoryFs::atomic_write`.
+    pub fn atomic_write(&mut self, name: &str, data: &[u8]) -> Result<(), FsErr> {
+        self.fs.atomic_write(name, data)
+    }
+
+    /// See `MemoryFs::write_at`.
+    pub fn write_at(&mut self, name: &str, offset: usize, data: &[u8]) -> Result<(), FsErr> {
+        self.fs.write_at(name, offset, data)
+    }
+
+    /// See `MemoryFs::append`.
+    pub fn append(&mut self, name: &str, data: &[u8]) -> Result<(), FsErr> {
+        self.fs.append(name, data)
+    }
+
+    /// See `MemoryFs::truncate`.
+    pub fn truncate(&mut self, name: &str, new_size: usize) -> Result<(), FsErr> {
+        self.fs.truncate(name, new_size)
+    }
+
+    /// See `MemoryFs::rename`.
+    pub fn rename(&mut self, name: &str, new_name: &str) -> Result<(), FsErr> {
+        self.fs.rename(name, new_name)
+    }
+
+    /// See `MemoryFs::rename_replace`.
+    pub fn rename_replace(&mut self, name: &str, new_name: &str) -> Result<(), FsErr> {
+        self.fs.rename_replace(name, new_name)
+    }
+
+    /// See `MemoryFs::delete`.
+    pub fn delete(&mut self, name: &str) -> Result<(), FsErr> {
+        self.fs.delete(name)
+    }
+
+    /// See `MemoryFs::mkdir`.
+    pub fn mkdir(&mut self, name: &str) -> Result<(), FsErr> {
+        self.fs.mkdir(name)
+    }
+
+    /// See `MemoryFs::rmdir`.
+    pub fn rmdir(&mut self, name: &str) -> Result<(), FsErr> {
+        self.fs.rmdir(name)
+    }
+}
+
+impl<
+    'a,
+    const STORAGE_SIZE: usize,
+    const PAGE_SIZE: usize,
+    const MAX_FILES: usize,
+    const MAX_NAME_LEN: usize,
+    const BITMAP_WORDS: usize,
+    A: Allocator,
+> Deref for Transaction<'_, 'a, STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>
+{
+    type Target = MemoryFs<'a, STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>;
+
+    fn deref(&self) -> &Self::Target {
+        self.fs
+    }
+}
+
+impl<
+    const STORAGE_SIZE: usize,
+    const PAGE_SIZE: usize,
+    const MAX_FILES: usize,
+    const MAX_NAME_LEN: usize,
+    const BITMAP_WORDS: usize,
+    A: Allocator,
+> Drop for Transaction<'_, '_, STORAGE_SIZE, PAGE_SIZE, MAX_FILES, MAX_NAME_LEN, BITMAP_WORDS, A>
+{
+    fn drop(&mut self) {
+        for (word, before) in self.fs.dirty_pages.iter_mut().zip(&self.dirty_pages) {
+            *word |= before;
+        }
+        if self.committed {
+            return;
+        }
+
+        // Pages in use at the start were never overwritten, so restoring the metadata is enough.
+        self.fs.in_transaction = false;
+        self.fs.entries = core::mem::take(&mut self.entries);
+        self.fs.name_index = core::mem::take(&mut self.name_index);
+        self.fs.rebuild_page_bitmap();
+        self.fs.next_id = self.next_id;
+    }
+}